@@ -2,8 +2,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // local mode
     pajamax_build::compile_protos_in_local(&["proto/helloworld.proto"], &["."])?;
 
-    // dispatch mode
-    pajamax_build::compile_protos_in_dispatch(&["proto/dict_store.proto"], &["."])?;
+    // dispatch mode, and compress the bulk replies of `ListShard` by zstd
+    // if enabled
+    pajamax_build::Builder::new(pajamax_build::PajamaxGen::Dispatch)
+        .method_encoding("/dict_store.DictStore/ListShard", "zstd")
+        .compile_protos(&["proto/dict_store.proto"], &["."])?;
 
     Ok(())
 }
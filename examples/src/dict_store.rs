@@ -6,7 +6,6 @@
 // dispatched to the corresponding thread.
 
 use std::collections::HashMap;
-use std::sync::mpsc;
 
 use pajamax::status::{Code, Status};
 
@@ -21,30 +20,38 @@ mod dict_store {
 
 // This is the MyDictDispatch.
 //
-// It dispatches requests to backend shards by channels, so it
-// contains the channel-send-end list.
+// It dispatches requests to backend shards, which are run by the
+// built-in `pajamax::shard::ShardPool`.
 //
 // The instance of this struct is global. All connections share the
 // same instence. Pajamax will wrap an `Arc` on this. This is the
 // same with `tonic`.
 struct MyDictDispatch {
-    req_txs: Vec<DictStoreRequestTx>,
+    pool: pajamax::shard::ShardPool<DictStoreRequest>,
 }
 
 impl DictStoreDispatch for MyDictDispatch {
     // Return the channel send-end where the request will be dispatched to.
     fn dispatch_to(&self, req: &DictStoreRequest) -> &DictStoreRequestTx {
+        self.pool.pick(req)
+    }
+
+    // Broadcast the `List` request to all shards.
+    fn scatter_to(&self, req: &DictStoreRequest) -> Option<DictStoreScatter<'_>> {
         match req {
-            // hashed by req.key
-            DictStoreRequest::Get(req) => self.pick_req_tx(&req.key),
-            DictStoreRequest::Set(req) => self.pick_req_tx(&req.key),
-            DictStoreRequest::Delete(req) => self.pick_req_tx(&req.key),
-            // by req.shard
-            DictStoreRequest::ListShard(req) => {
-                let i = req.shard as usize % self.req_txs.len();
-                &self.req_txs[i]
-            }
+            DictStoreRequest::List(_) => Some(DictStoreScatter::All(self.pool.channels())),
+            _ => None,
+        }
+    }
+
+    // Merge the items of all shards.
+    fn reduce_list(replies: Vec<ListShardReply>) -> Result<ListShardReply, Status> {
+        let mut all = ListShardReply::default();
+        for reply in replies {
+            all.count += reply.count;
+            all.entries.extend(reply.entries);
         }
+        Ok(all)
     }
 }
 
@@ -89,7 +96,20 @@ impl DictStoreShard for MyDictShard {
 
     // list the items in the current shard only
     fn list_shard(&mut self, _req: ListShardRequest) -> Result<ListShardReply, Status> {
-        Ok(ListShardReply {
+        Ok(self.list_items())
+    }
+
+    // list the items in all shards, see MyDictDispatch::scatter_to()
+    fn list(&mut self, _req: ListRequest) -> Result<ListShardReply, Status> {
+        Ok(self.list_items())
+    }
+}
+
+// some business code
+
+impl MyDictShard {
+    fn list_items(&self) -> ListShardReply {
+        ListShardReply {
             count: self.dict.len() as u32,
             entries: self
                 .dict
@@ -99,55 +119,39 @@ impl DictStoreShard for MyDictShard {
                     value: *value,
                 })
                 .collect(),
-        })
-    }
-}
-
-// some business code
-
-// pick one channel by hashing the key
-impl MyDictDispatch {
-    fn pick_req_tx(&self, key: &str) -> &DictStoreRequestTx {
-        let hash = hash_key(key) as usize;
-        let i = hash % self.req_txs.len();
-        &self.req_txs[i]
+        }
     }
 }
 
-// a common hash util helper
-fn hash_key<K>(key: &K) -> u64
-where
-    K: std::hash::Hash + ?Sized,
-{
-    use std::hash::Hasher;
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    key.hash(&mut hasher);
-    hasher.finish()
-}
-
-// backend shard routine
-fn shard_routine(req_rx: DictStoreRequestRx) {
-    let shard = MyDictShard {
-        dict: HashMap::new(),
-    };
-    let mut shard = DictStoreShardServer::new(shard);
-
-    while let Ok(req) = req_rx.recv() {
-        shard.handle(req); // handle the request!
+// pick the key to dispatch the request by
+fn shard_key(req: &DictStoreRequest) -> u64 {
+    match req {
+        // hashed by req.key
+        DictStoreRequest::Get(req) => pajamax::shard::hash_key(&req.key),
+        DictStoreRequest::Set(req) => pajamax::shard::hash_key(&req.key),
+        DictStoreRequest::Delete(req) => pajamax::shard::hash_key(&req.key),
+        // by req.shard
+        DictStoreRequest::ListShard(req) => req.shard as u64,
+        // scattered to all shards
+        DictStoreRequest::List(_) => 0,
     }
 }
 
 fn main() {
-    // start 8 backend shard threads
-    let mut req_txs = Vec::new();
-    for _ in 0..8 {
-        let (req_tx, req_rx) = mpsc::sync_channel(1000);
-        std::thread::spawn(move || shard_routine(req_rx));
-        req_txs.push(req_tx);
-    }
+    // start 8 backend shard threads, each owns one MyDictShard
+    let pool = pajamax::shard::ShardPoolBuilder::new(8)
+        .start(
+            |_| {
+                DictStoreShardServer::new(MyDictShard {
+                    dict: HashMap::new(),
+                })
+            },
+            shard_key,
+        )
+        .unwrap();
 
     let addr = "127.0.0.1:50051";
-    let dict = MyDictDispatch { req_txs };
+    let dict = MyDictDispatch { pool };
 
     println!("DictStoreServer listening on {}", addr);
 
@@ -1,11 +1,14 @@
 use std::fmt::Write;
 
-pub fn generate(service: prost_build::Service, buf: &mut String) {
+use crate::Options;
+
+pub fn generate(service: prost_build::Service, options: &Options, buf: &mut String) {
     gen_trait_dispatch(&service, buf);
-    gen_trait_shard(&service, buf);
-    gen_request_type(&service, buf);
-    gen_server(&service, buf);
-    gen_shard_server(&service, buf);
+    gen_trait_shard(&service, options, buf);
+    gen_request_type(&service, options, buf);
+    gen_server(&service, options, buf);
+    gen_shard_server(&service, options, buf);
+    gen_shard_pool(&service, options, buf);
     gen_reply_structs(&service, buf);
 }
 
@@ -14,6 +17,10 @@ pub fn generate(service: prost_build::Service, buf: &mut String) {
 // The `dispatch_to()` returns a &{Service}RequestTx to
 // specify where to dispatch the requets.
 //
+// The `scatter_to()` returns the shards to broadcast the request to,
+// e.g. for global list or count. The partial replies are merged by
+// `reduce_{method}()`. By default, no request is scattered.
+//
 // Applications should implement this trait for a server context.
 // The server context is global, wrapped by `Arc` and shared by all
 // network threads.
@@ -22,10 +29,28 @@ fn gen_trait_dispatch(service: &prost_build::Service, buf: &mut String) {
         buf,
         "pub trait {}Dispatch {{
             fn dispatch_to(&self, req: &{}Request) -> &{}RequestTx;
-        }}",
-        service.name, service.name, service.name
+
+            fn scatter_to(&self, _req: &{}Request) -> Option<{}Scatter<'_>> {{
+                None
+            }}",
+        service.name, service.name, service.name, service.name, service.name
     )
     .unwrap();
+
+    for m in service.methods.iter() {
+        writeln!(
+            buf,
+            "fn reduce_{}(_replies: Vec<{}>) -> pajamax::Response<{}> where Self: Sized {{
+                Err(pajamax::status::Status {{
+                    code: pajamax::status::Code::Unimplemented,
+                    message: String::from(\"no reducer for {}\"),
+                }})
+            }}",
+            m.name, m.output_type, m.output_type, m.proto_name
+        )
+        .unwrap();
+    }
+    writeln!(buf, "}}").unwrap();
 }
 
 // trait {Service}Shard
@@ -33,31 +58,132 @@ fn gen_trait_dispatch(service: &prost_build::Service, buf: &mut String) {
 // Defines all gRPC methods to make replies.
 // These methods will be called in backend shard threads.
 //
+// The deferred methods take a `pajamax::shard::Responder` to answer
+// later, instead of returning the reply.
+//
+// The `handle_batch()` handles a batch of requests, one by one by the
+// methods by default. Applications can override it to amortize the
+// work of the batch.
+//
 // Applications should implement this trait for a backend shard
 // context struct. Each shard thread owns a context instence,
 // so these methods take mutable reference of `self`.
-fn gen_trait_shard(service: &prost_build::Service, buf: &mut String) {
+fn gen_trait_shard(service: &prost_build::Service, options: &Options, buf: &mut String) {
     writeln!(buf, "pub trait {}Shard {{", service.name).unwrap();
 
     for m in service.methods.iter() {
-        writeln!(
-            buf,
-            "fn {}(&mut self, request: {}) -> pajamax::Response<{}>;",
-            m.name, m.input_type, m.output_type
+        if options.is_deferred(service, m) {
+            writeln!(
+                buf,
+                "fn {}(&mut self, request: {}, responder: pajamax::shard::Responder<{}>);",
+                m.name, m.input_type, m.output_type
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                buf,
+                "fn {}(&mut self, request: {}) -> pajamax::Response<{}>;",
+                m.name, m.input_type, m.output_type
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(
+        buf,
+        "fn handle_batch(
+            &mut self,
+            batch: &mut [pajamax::shard::BatchItem<{}Request, {}Reply>],
+        ) where Self: Sized {{
+            for item in batch.iter_mut() {{",
+        service.name, service.name
+    )
+    .unwrap();
+
+    // the deferred requests are taken out of the batch
+    let has_deferred = service
+        .methods
+        .iter()
+        .any(|m| options.is_deferred(service, m));
+    if has_deferred {
+        writeln!(buf, "match item.request() {{").unwrap();
+        for m in service.methods.iter() {
+            if !options.is_deferred(service, m) {
+                continue;
+            }
+            let call = gen_deferred_call(&format!("self.{}", m.name), options);
+            writeln!(
+                buf,
+                "Some({}Request::{}(_)) => {{
+                    let Some({}Request::{}(request)) = item.take_request() else {{
+                        unreachable!()
+                    }};
+                    let responder = item.defer(|reply|
+                        Box::new({}{}Reply(reply)) as Box<dyn pajamax::ReplyEncode>);
+                    {}
+                }}",
+                service.name,
+                m.proto_name,
+                service.name,
+                m.proto_name,
+                service.name,
+                m.proto_name,
+                call
+            )
+            .unwrap();
+        }
+        writeln!(buf, "_ => ").unwrap();
+    }
+
+    writeln!(buf, "item.handle(|request| match request {{").unwrap();
+    for m in service.methods.iter() {
+        if options.is_deferred(service, m) {
+            writeln!(
+                buf,
+                "{}Request::{}(_) => unreachable!(),",
+                service.name, m.proto_name
+            )
+            .unwrap();
+        } else {
+            let call = crate::gen_handler_call(&format!("self.{}(request)", m.name), options);
+            writeln!(
+                buf,
+                "{}Request::{}(request) => {}Reply::{}({}),",
+                service.name, m.proto_name, service.name, m.proto_name, call
+            )
+            .unwrap();
+        }
+    }
+    if has_deferred {
+        writeln!(buf, "}}), }}").unwrap();
+    } else {
+        writeln!(buf, "}});").unwrap();
+    }
+    writeln!(buf, "}} }} }}").unwrap();
+}
+
+// Call the deferred method with `request` and `responder`. The
+// responder answers `INTERNAL` status on drop if the method panics.
+fn gen_deferred_call(method: &str, options: &Options) -> String {
+    if options.catch_panics {
+        format!(
+            "let _: pajamax::Response<()> =
+                pajamax::catch_panic(|| {{ {method}(request, responder); Ok(()) }});"
         )
-        .unwrap();
+    } else {
+        format!("{method}(request, responder);")
     }
-    writeln!(buf, "}}").unwrap();
 }
 
-// enum ${Service}Request
+// enum ${Service}Request, and enum ${Service}Reply
 //
-// Used to dispatch requests through channel.
+// Used to dispatch requests through channel, and to reply in
+// `{Service}Shard::handle_batch()`.
 //
-// Applications need not access this.
-fn gen_request_type(service: &prost_build::Service, buf: &mut String) {
+// Applications need not access these, unless handling in batch.
+fn gen_request_type(service: &prost_build::Service, options: &Options, buf: &mut String) {
     // enum
-    writeln!(buf, "#[derive(Debug, PartialEq)]").unwrap();
+    writeln!(buf, "#[derive(Debug, Clone, PartialEq)]").unwrap();
     writeln!(buf, "pub enum {}Request {{", service.name).unwrap();
 
     for m in service.methods.iter() {
@@ -65,12 +191,36 @@ fn gen_request_type(service: &prost_build::Service, buf: &mut String) {
     }
     writeln!(buf, "}}").unwrap();
 
+    // reply enum, for `{Service}Shard::handle_batch()`, where the
+    // deferred methods are answered by `Responder` by default
+    writeln!(buf, "#[allow(dead_code)]").unwrap();
+    writeln!(buf, "pub enum {}Reply {{", service.name).unwrap();
+    for m in service.methods.iter() {
+        writeln!(
+            buf,
+            "{}(pajamax::Response<{}>),",
+            m.proto_name, m.output_type
+        )
+        .unwrap();
+    }
+    writeln!(buf, "}}").unwrap();
+
     // channel types
     writeln!(
         buf,
-        "pub type {}RequestTx = pajamax::dispatch::RequestTx<{}Request>;
-         pub type {}RequestRx = pajamax::dispatch::RequestRx<{}Request>;",
-        service.name, service.name, service.name, service.name
+        "pub type {}RequestTx = pajamax::dispatch::RequestTx<{}Request, {}>;
+         #[allow(dead_code)]
+         pub type {}RequestRx = pajamax::dispatch::RequestRx<{}Request, {}>;
+         pub type {}Scatter<'a> = pajamax::dispatch::Scatter<'a, {}Request, {}>;",
+        service.name,
+        service.name,
+        options.dispatch_channel(),
+        service.name,
+        service.name,
+        options.dispatch_channel(),
+        service.name,
+        service.name,
+        options.dispatch_channel()
     )
     .unwrap();
 }
@@ -80,7 +230,7 @@ fn gen_request_type(service: &prost_build::Service, buf: &mut String) {
 // Intermediary between pajamax::PajamaxService and application's server.
 // Applications should call ${Service}Server::new(AppServer) to make a
 // Pajamax service.
-fn gen_server(service: &prost_build::Service, buf: &mut String) {
+fn gen_server(service: &prost_build::Service, options: &Options, buf: &mut String) {
     writeln!(
         buf,
         "pub struct {}Server<T: {}Dispatch>(T);
@@ -109,6 +259,7 @@ fn gen_server(service: &prost_build::Service, buf: &mut String) {
 
     gen_service_route(service, buf);
     gen_service_handle(service, buf);
+    crate::gen_service_method_encoding(service, options, buf);
 
     writeln!(buf, "}}").unwrap();
 }
@@ -154,10 +305,33 @@ fn gen_service_handle(service: &prost_build::Service, buf: &mut String) {
             buf,
             "{} => {{
                 let request = {}Request::{}({}::decode(req_buf)?);
+                if let Some(targets) = self.0.scatter_to(&request) {{
+                    let reducer = |replies: Vec<Box<dyn pajamax::ReplyEncode>>| {{
+                        let replies = replies
+                            .into_iter()
+                            .map(|r| r.into_any().downcast::<{}>().map(|r| *r))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(|_| pajamax::status::Status {{
+                                code: pajamax::status::Code::Internal,
+                                message: String::from(\"mismatched reply for {}\"),
+                            }})?;
+                        T::reduce_{}(replies).map(|reply|
+                            Box::new({}{}Reply(reply)) as Box<dyn pajamax::ReplyEncode>)
+                    }};
+                    return pajamax::dispatch::scatter(targets, request, stream_id, frame_len, reducer);
+                }}
                 let req_tx = self.0.dispatch_to(&request);
                 pajamax::dispatch::dispatch(req_tx, request, stream_id, frame_len)
             }}",
-            i, service.name, m.proto_name, m.input_type
+            i,
+            service.name,
+            m.proto_name,
+            m.input_type,
+            m.output_type,
+            m.proto_name,
+            m.name,
+            service.name,
+            m.proto_name
         )
         .unwrap();
     }
@@ -171,8 +345,11 @@ fn gen_service_handle(service: &prost_build::Service, buf: &mut String) {
 // 1. create some backend shard threads,
 // 2. call {Service}ShardServer::new(AppShardServer) to make a server,
 // 3. receive requests from channel,
-// 4. call {Service}ShardServer::handle(request) to handle them.
-fn gen_shard_server(service: &prost_build::Service, buf: &mut String) {
+// 4. call {Service}ShardServer::handle(request) to handle them,
+//    or handle_batch(requests) in batch.
+//
+// Or use the built-in `pajamax::shard::ShardPool` for these.
+fn gen_shard_server(service: &prost_build::Service, options: &Options, buf: &mut String) {
     writeln!(
         buf,
         "pub struct {}ShardServer<T: {}Shard>(T);
@@ -184,6 +361,7 @@ fn gen_shard_server(service: &prost_build::Service, buf: &mut String) {
             pub fn inner(&self) -> &T {{ &self.0 }}
 
             pub fn handle(&mut self, disp_req: pajamax::dispatch::DispatchRequest<{}Request>) {{
+                let ctx = pajamax::context::enter(disp_req.context);
                 let response = match disp_req.request {{",
         service.name, service.name, service.name, service.name, service.name
     )
@@ -191,13 +369,34 @@ fn gen_shard_server(service: &prost_build::Service, buf: &mut String) {
 
     // continue of `fn handle()`
     for m in service.methods.iter() {
+        if options.is_deferred(service, m) {
+            let call = gen_deferred_call(&format!("self.0.{}", m.name), options);
+            writeln!(
+                buf,
+                "{}Request::{}(request) => {{
+                    let responder = pajamax::shard::Responder::new(
+                        disp_req.stream_id,
+                        disp_req.req_data_len,
+                        disp_req.resp_tx,
+                        ctx.exit(),
+                        |reply| Box::new({}{}Reply(reply)) as Box<dyn pajamax::ReplyEncode>,
+                    );
+                    {}
+                    return;
+                }}",
+                service.name, m.proto_name, service.name, m.proto_name, call
+            )
+            .unwrap();
+            continue;
+        }
+        let call = crate::gen_handler_call(&format!("self.0.{}(request)", m.name), options);
         writeln!(
             buf,
             "{}Request::{}(request) => {{
-                self.0.{}(request).map(|reply|
+                {}.map(|reply|
                     Box::new({}{}Reply(reply)) as Box<dyn pajamax::ReplyEncode>)
             }}",
-            service.name, m.proto_name, m.name, service.name, m.proto_name
+            service.name, m.proto_name, call, service.name, m.proto_name
         )
         .unwrap();
     }
@@ -209,11 +408,85 @@ fn gen_shard_server(service: &prost_build::Service, buf: &mut String) {
              stream_id: disp_req.stream_id,
              req_data_len: disp_req.req_data_len,
              response,
+             context: ctx.exit(),
         }};
 
         let _ = disp_req.resp_tx.send(disp_resp);
 
-        }} }}"
+        }}"
+    )
+    .unwrap();
+
+    // fn handle_batch()
+    // The requests not replied for panic are answered with INTERNAL status.
+    let call = if options.catch_panics {
+        "let _: pajamax::Response<()> =
+            pajamax::catch_panic(|| { self.0.handle_batch(&mut batch); Ok(()) });"
+    } else {
+        "self.0.handle_batch(&mut batch);"
+    };
+    writeln!(
+        buf,
+        "pub fn handle_batch(
+            &mut self,
+            disp_reqs: impl Iterator<Item = pajamax::dispatch::DispatchRequest<{}Request>>,
+        ) {{
+            let mut batch: Vec<_> = disp_reqs.map(pajamax::shard::BatchItem::new).collect();
+            {}
+            pajamax::shard::send_batch(batch, |reply| match reply {{",
+        service.name, call
+    )
+    .unwrap();
+    for m in service.methods.iter() {
+        writeln!(
+            buf,
+            "{}Reply::{}(response) => response.map(|reply|
+                Box::new({}{}Reply(reply)) as Box<dyn pajamax::ReplyEncode>),",
+            service.name, m.proto_name, service.name, m.proto_name
+        )
+        .unwrap();
+    }
+    writeln!(buf, "}}); }} }}").unwrap();
+}
+
+// Implement `pajamax::shard::Shard` for {Service}ShardServer, and
+// {Service}Dispatch for `pajamax::shard::ShardPool`, so applications
+// can use the built-in shard runtime.
+fn gen_shard_pool(service: &prost_build::Service, options: &Options, buf: &mut String) {
+    writeln!(
+        buf,
+        "impl<T: {}Shard + Send + 'static> pajamax::shard::Shard for {}ShardServer<T> {{
+            type Request = {}Request;
+
+            fn handle(&mut self, disp_req: pajamax::dispatch::DispatchRequest<{}Request>) {{
+                {}ShardServer::handle(self, disp_req)
+            }}
+
+            fn handle_batch(
+                &mut self,
+                disp_reqs: impl Iterator<Item = pajamax::dispatch::DispatchRequest<{}Request>>,
+            ) {{
+                {}ShardServer::handle_batch(self, disp_reqs)
+            }}
+        }}
+
+        impl {}Dispatch for pajamax::shard::ShardPool<{}Request, {}> {{
+            fn dispatch_to(&self, req: &{}Request) -> &{}RequestTx {{
+                self.pick(req)
+            }}
+        }}",
+        service.name,
+        service.name,
+        service.name,
+        service.name,
+        service.name,
+        service.name,
+        service.name,
+        service.name,
+        service.name,
+        options.dispatch_channel(),
+        service.name,
+        service.name
     )
     .unwrap();
 }
@@ -223,7 +496,7 @@ fn gen_shard_server(service: &prost_build::Service, buf: &mut String) {
 // Since prost::Message is not object-safe, we need to define `trait ReplyEncode`
 // to work around this. Here we define may reply structs and implement
 // ReplyEncode for all of them.
-fn gen_reply_structs(service: &prost_build::Service, buf: &mut String) {
+pub fn gen_reply_structs(service: &prost_build::Service, buf: &mut String) {
     for m in service.methods.iter() {
         writeln!(
             buf,
@@ -233,6 +506,9 @@ fn gen_reply_structs(service: &prost_build::Service, buf: &mut String) {
                     use prost::Message;
                     self.0.encode(output)
                 }}
+                fn into_any(self: Box<Self>) -> Box<dyn std::any::Any> {{
+                    Box::new(self.0)
+                }}
             }}",
             service.name, m.proto_name, m.output_type, service.name, m.proto_name
         )
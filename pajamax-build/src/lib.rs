@@ -36,17 +36,23 @@
 //!    }
 //!    ```
 //!
+//!    Use [`Builder`] for per-method options, such as the response
+//!    compression.
+//!
 //! 3. Call `pajamax` in your source code. See the local-mode example
 //!    [`helloworld`](https://github.com/WuBingzheng/pajamax/tree/main/examples/src/helloworld.rs)
 //!    and dispatch-mode example [`dict-store`](https://github.com/WuBingzheng/pajamax/tree/main/examples/src/dict_store.rs)
 //!    for details.
 
+use std::fmt::Write;
 use std::path::Path;
 
 mod dispatch_mode;
 mod local_mode;
+mod pool_mode;
 
-/// Specify the services to be compiled in local-mode or dispatch-mode.
+/// Specify the services to be compiled in local-mode, dispatch-mode
+/// or pool-mode.
 ///
 /// Generally you can call the `compile_protos_*` APIs which swap this enum.
 /// You need to use this enum only if you need more `prost` options.
@@ -64,36 +70,222 @@ pub enum PajamaxGen {
         local_svcs: Vec<&'static str>,
         dispatch_svcs: Vec<&'static str>,
     },
+    /// All in pool-mode.
+    ///
+    /// The handlers are stateless as in local-mode, but called in the
+    /// shared thread pool of `pajamax` rather than the connection thread,
+    /// for CPU-heavy requests. See `pajamax::Config::pool_threads`.
+    Pool,
+    /// The listed in pool-mode while others in local-mode.
+    ListPool(Vec<&'static str>),
 }
 
-impl prost_build::ServiceGenerator for PajamaxGen {
-    fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
-        let name = &service.name.as_str();
-        let is_local_mode = match self {
-            PajamaxGen::Local => true,
-            PajamaxGen::Dispatch => false,
-            PajamaxGen::ListLocal(svcs) => svcs.contains(&name),
-            PajamaxGen::ListDispatch(svcs) => !svcs.contains(&name),
+enum Mode {
+    Local,
+    Dispatch,
+    Pool,
+}
+
+impl PajamaxGen {
+    fn generate_with(&self, service: prost_build::Service, options: &Options, buf: &mut String) {
+        let name = service.name.as_str();
+        let mode = match self {
+            PajamaxGen::Local => Mode::Local,
+            PajamaxGen::Dispatch => Mode::Dispatch,
+            PajamaxGen::ListLocal(svcs) if svcs.contains(&name) => Mode::Local,
+            PajamaxGen::ListLocal(_) => Mode::Dispatch,
+            PajamaxGen::ListDispatch(svcs) if svcs.contains(&name) => Mode::Dispatch,
+            PajamaxGen::ListDispatch(_) => Mode::Local,
             PajamaxGen::ListBoth {
                 local_svcs,
                 dispatch_svcs,
             } => {
-                if local_svcs.contains(&service.name.as_str()) {
-                    true
-                } else if dispatch_svcs.contains(&service.name.as_str()) {
-                    false
+                if local_svcs.contains(&name) {
+                    Mode::Local
+                } else if dispatch_svcs.contains(&name) {
+                    Mode::Dispatch
                 } else {
                     return;
                 }
             }
+            PajamaxGen::Pool => Mode::Pool,
+            PajamaxGen::ListPool(svcs) if svcs.contains(&name) => Mode::Pool,
+            PajamaxGen::ListPool(_) => Mode::Local,
         };
 
-        if is_local_mode {
-            local_mode::generate(service, buf);
-        } else {
-            dispatch_mode::generate(service, buf);
+        match mode {
+            Mode::Local => local_mode::generate(service, options, buf),
+            Mode::Dispatch => dispatch_mode::generate(service, options, buf),
+            Mode::Pool => pool_mode::generate(service, options, buf),
+        }
+    }
+}
+
+impl prost_build::ServiceGenerator for PajamaxGen {
+    fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
+        self.generate_with(service, &Options::default(), buf);
+    }
+}
+
+// Options besides the modes.
+#[derive(Default)]
+struct Options {
+    method_encodings: Vec<(String, String)>, // (path, encoding)
+    catch_panics: bool,
+    dispatch_channel: Option<String>,
+    deferred_methods: Vec<String>, // paths
+}
+
+impl Options {
+    fn is_deferred(&self, service: &prost_build::Service, m: &prost_build::Method) -> bool {
+        let path = format!("{}.{}/{}", service.package, service.name, m.proto_name);
+        self.deferred_methods.contains(&path)
+    }
+
+    fn dispatch_channel(&self) -> &str {
+        self.dispatch_channel
+            .as_deref()
+            .unwrap_or("pajamax::channel::StdChannel")
+    }
+}
+
+/// Generator with more options than [`PajamaxGen`].
+///
+/// # Examples:
+///
+/// ```rust,ignore
+/// pajamax_build::Builder::new(pajamax_build::PajamaxGen::Dispatch)
+///     .method_encoding("/dict_store.DictStore/ListShard", "zstd")
+///     .compile_protos(&["proto/dict_store.proto"], &["."])?;
+/// ```
+pub struct Builder {
+    gen: PajamaxGen,
+    options: Options,
+}
+
+impl Builder {
+    pub fn new(gen: PajamaxGen) -> Self {
+        Self {
+            gen,
+            options: Options::default(),
+        }
+    }
+
+    /// Set the response `grpc-encoding` of one method, e.g. `zstd` for
+    /// bulk replies, or `identity` to never compress hot small replies.
+    ///
+    /// The `path` is in format of `/{package}.{Service}/{Method}`.
+    /// See `pajamax::compression` for details.
+    pub fn method_encoding(mut self, path: &str, encoding: &str) -> Self {
+        self.options.method_encodings.push((
+            String::from(path.trim_start_matches('/')),
+            String::from(encoding),
+        ));
+        self
+    }
+
+    /// Catch panics in the handlers, in both local-mode and dispatch-mode,
+    /// and answer the requests with `INTERNAL` status.
+    ///
+    /// Without this, a panic in local-mode handler drops all in-flight
+    /// streams of the connection, and a panic in shard thread leaves the
+    /// requests unanswered.
+    ///
+    /// Note that the handler's state may be left inconsistent by the
+    /// panic. See also `pajamax::Config::max_connection_panics`.
+    ///
+    /// The panics in pool-mode handlers are always caught.
+    pub fn catch_panics(mut self) -> Self {
+        self.options.catch_panics = true;
+        self
+    }
+
+    /// Set the channel type of request channels of dispatch-mode services,
+    /// e.g. `pajamax::channel::RingChannel`, to tune the latency vs. CPU
+    /// tradeoff. Compile services with different `Builder`s to use
+    /// different channels.
+    ///
+    /// The `path` is the full path of a type implementing
+    /// `pajamax::channel::DispatchChannel`. See `pajamax::channel` for details.
+    ///
+    /// Default: `pajamax::channel::StdChannel`
+    pub fn dispatch_channel(mut self, path: &str) -> Self {
+        self.options.dispatch_channel = Some(String::from(path));
+        self
+    }
+
+    /// Answer the requests of one dispatch-mode method later, maybe from
+    /// other threads, e.g. after disk I/O or replication.
+    ///
+    /// The method of `{Service}Shard` takes a `pajamax::shard::Responder`
+    /// to answer by, instead of returning the reply. See `pajamax::shard`
+    /// for details.
+    ///
+    /// The `path` is in format of `/{package}.{Service}/{Method}`.
+    pub fn deferred_method(mut self, path: &str) -> Self {
+        self.options
+            .deferred_methods
+            .push(String::from(path.trim_start_matches('/')));
+        self
+    }
+
+    /// Complie protofile.
+    ///
+    /// If your want more options, call `prost_build` directly with this
+    /// `Builder` as the service generator.
+    pub fn compile_protos(
+        self,
+        protos: &[impl AsRef<Path>],
+        includes: &[impl AsRef<Path>],
+    ) -> std::io::Result<()> {
+        prost_build::Config::new()
+            .service_generator(Box::new(self))
+            .compile_protos(protos, includes)
+    }
+}
+
+impl prost_build::ServiceGenerator for Builder {
+    fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
+        self.gen.generate_with(service, &self.options, buf);
+    }
+}
+
+// Call the handler, in `pajamax::catch_panic()` if enabled.
+fn gen_handler_call(call: &str, options: &Options) -> String {
+    if options.catch_panics {
+        format!("pajamax::catch_panic(|| {call})")
+    } else {
+        String::from(call)
+    }
+}
+
+// impl PajamaxService::method_encoding(), if any method is set.
+fn gen_service_method_encoding(
+    service: &prost_build::Service,
+    options: &Options,
+    buf: &mut String,
+) {
+    let mut arms = String::new();
+    for (i, m) in service.methods.iter().enumerate() {
+        let path = format!("{}.{}/{}", service.package, service.name, m.proto_name);
+        if let Some((_, encoding)) = options.method_encodings.iter().find(|(p, _)| *p == path) {
+            writeln!(arms, "{i} => Some({encoding:?}),").unwrap();
         }
     }
+    if arms.is_empty() {
+        return;
+    }
+
+    writeln!(
+        buf,
+        "fn method_encoding(&self, req_disc: usize) -> Option<&'static str> {{
+            match req_disc {{
+                {arms}
+                _ => None,
+            }}
+        }}"
+    )
+    .unwrap();
 }
 
 /// Complie protofile. Build all services as local-mode.
@@ -116,11 +308,21 @@ pub fn compile_protos_in_dispatch(
         .compile_protos(protos, includes)
 }
 
+/// Complie protofile. Build all services as pool-mode.
+pub fn compile_protos_in_pool(
+    protos: &[impl AsRef<Path>],
+    includes: &[impl AsRef<Path>],
+) -> std::io::Result<()> {
+    prost_build::Config::new()
+        .service_generator(Box::new(PajamaxGen::Pool))
+        .compile_protos(protos, includes)
+}
+
 /// Complie protofile. Build some services as local-mode and others as dispatch-mode.
 ///
 /// # Examples:
 ///
-/// ```
+/// ```rust,ignore
 /// // Build "StatsService" and "AccountService" as local-mode and others as dispatch-mode.
 /// pajamax_build::compile_protos_list_local(
 ///     &["proto/helloworld.proto"],
@@ -141,9 +343,9 @@ pub fn compile_protos_list_local(
 ///
 /// # Examples:
 ///
-/// ```
+/// ```rust,ignore
 /// // Build "OrderService" as dispatch-mode and others as local-mode.
-/// pajamax_build::compile_protos_list_local(
+/// pajamax_build::compile_protos_list_dispatch(
 ///     &["proto/helloworld.proto"],
 ///     &["."],
 ///     ["OrderService"])?;
@@ -162,10 +364,10 @@ pub fn compile_protos_list_dispatch(
 ///
 /// # Examples:
 ///
-/// ```
+/// ```rust,ignore
 /// // Build "StatsService" and "AccountService" as local-mode,
 /// // while "OrderService" as dispatch-mode, and ignore others.
-/// pajamax_build::compile_protos_list_local(
+/// pajamax_build::compile_protos_list_both(
 ///     &["proto/helloworld.proto"],
 ///     &["."],
 ///     ["StatsService", "AccountService"],
@@ -184,3 +386,54 @@ pub fn compile_protos_list_both(
         }))
         .compile_protos(protos, includes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn method(name: &str) -> prost_build::Method {
+        prost_build::Method {
+            name: name.to_lowercase(),
+            proto_name: String::from(name),
+            comments: Default::default(),
+            input_type: String::from("Req"),
+            output_type: String::from("Reply"),
+            input_proto_type: String::from(".test.Req"),
+            output_proto_type: String::from(".test.Reply"),
+            options: Default::default(),
+            client_streaming: false,
+            server_streaming: false,
+        }
+    }
+
+    fn service() -> prost_build::Service {
+        prost_build::Service {
+            name: String::from("Svc"),
+            proto_name: String::from("Svc"),
+            package: String::from("test"),
+            comments: Default::default(),
+            methods: vec![method("Get"), method("List"), method("Set")],
+            options: Default::default(),
+        }
+    }
+
+    #[test]
+    fn method_encoding() {
+        let builder = Builder::new(PajamaxGen::Local)
+            .method_encoding("/test.Svc/Get", "identity")
+            .method_encoding("/test.Svc/List", "zstd")
+            .method_encoding("/test.Other/Set", "gzip");
+
+        let mut buf = String::new();
+        gen_service_method_encoding(&service(), &builder.options, &mut buf);
+        let arms: Vec<&str> = buf.lines().map(str::trim).collect();
+        assert!(arms.contains(&"0 => Some(\"identity\"),"));
+        assert!(arms.contains(&"1 => Some(\"zstd\"),"));
+        assert!(!buf.contains("gzip"));
+
+        // nothing generated if no method is set
+        let mut buf = String::new();
+        gen_service_method_encoding(&service(), &Options::default(), &mut buf);
+        assert!(buf.is_empty());
+    }
+}
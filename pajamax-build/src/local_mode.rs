@@ -1,14 +1,16 @@
 use std::fmt::Write;
 
-pub fn generate(service: prost_build::Service, buf: &mut String) {
+use crate::Options;
+
+pub fn generate(service: prost_build::Service, options: &Options, buf: &mut String) {
     gen_trait_service(&service, buf);
-    gen_server(&service, buf);
+    gen_server(&service, options, buf);
 }
 
 // trait ${Service}
 //
 // This defines all gRPC methods.
-fn gen_trait_service(service: &prost_build::Service, buf: &mut String) {
+pub fn gen_trait_service(service: &prost_build::Service, buf: &mut String) {
     writeln!(buf, "pub trait {} {{", service.name).unwrap();
 
     for m in service.methods.iter() {
@@ -25,7 +27,7 @@ fn gen_trait_service(service: &prost_build::Service, buf: &mut String) {
 // struct ${Service}Server
 //
 // Intermediary between pajamax::PajamaxService and application's server.
-fn gen_server(service: &prost_build::Service, buf: &mut String) {
+fn gen_server(service: &prost_build::Service, options: &Options, buf: &mut String) {
     writeln!(
         buf,
         "pub struct {}Server<T: {}>(T);
@@ -53,13 +55,14 @@ fn gen_server(service: &prost_build::Service, buf: &mut String) {
     .unwrap();
 
     gen_service_route(service, buf);
-    gen_service_handle(service, buf);
+    gen_service_handle(service, options, buf);
+    crate::gen_service_method_encoding(service, options, buf);
 
     writeln!(buf, "}}").unwrap();
 }
 
 // impl PajamaxService::route()
-fn gen_service_route(service: &prost_build::Service, buf: &mut String) {
+pub fn gen_service_route(service: &prost_build::Service, buf: &mut String) {
     writeln!(
         buf,
         "fn route(&self, path: &[u8]) -> Option<usize> {{
@@ -79,7 +82,7 @@ fn gen_service_route(service: &prost_build::Service, buf: &mut String) {
 }
 
 // impl PajamaxService::handle()
-fn gen_service_handle(service: &prost_build::Service, buf: &mut String) {
+fn gen_service_handle(service: &prost_build::Service, options: &Options, buf: &mut String) {
     writeln!(
         buf,
         "fn handle(
@@ -95,14 +98,15 @@ fn gen_service_handle(service: &prost_build::Service, buf: &mut String) {
     .unwrap();
 
     for (i, m) in service.methods.iter().enumerate() {
+        let call = crate::gen_handler_call(&format!("self.0.{}(request)", m.name), options);
         writeln!(
             buf,
             "{} => {{
                 let request = {}::decode(req_buf)?;
-                let response = self.0.{}(request);
+                let response = {};
                 pajamax::local_build_response(stream_id, response, frame_len)
             }}",
-            i, m.input_type, m.name
+            i, m.input_type, call
         )
         .unwrap();
     }
@@ -0,0 +1,88 @@
+use std::fmt::Write;
+
+use crate::Options;
+
+pub fn generate(service: prost_build::Service, options: &Options, buf: &mut String) {
+    // the same trait and route with local-mode
+    crate::local_mode::gen_trait_service(&service, buf);
+    gen_server(&service, options, buf);
+    crate::dispatch_mode::gen_reply_structs(&service, buf);
+}
+
+// struct ${Service}Server
+//
+// Intermediary between pajamax::PajamaxService and application's server,
+// which is shared by the threads of the pool.
+fn gen_server(service: &prost_build::Service, options: &Options, buf: &mut String) {
+    writeln!(
+        buf,
+        "pub struct {}Server<T: {}>(std::sync::Arc<T>);
+
+        #[allow(dead_code)]
+        impl<T: {}> {}Server<T> {{
+            pub fn new(inner: T) -> Self {{ Self(std::sync::Arc::new(inner)) }}
+
+            pub fn inner(&self) -> &T {{ &self.0 }}
+        }}",
+        service.name, service.name, service.name, service.name
+    )
+    .unwrap();
+
+    // impl pajamax::PajamaxService for ${Service}
+    //
+    // It's in dispatch-mode for the connection, since the responses
+    // are sent back by the pool threads.
+    writeln!(
+        buf,
+        "impl<T> pajamax::PajamaxService for {}Server<T>
+        where T: {} + Send + Sync + 'static
+        {{
+            fn is_dispatch_mode(&self) -> bool {{ true }}
+        ",
+        service.name, service.name
+    )
+    .unwrap();
+
+    crate::local_mode::gen_service_route(service, buf);
+    gen_service_handle(service, buf);
+    crate::gen_service_method_encoding(service, options, buf);
+
+    writeln!(buf, "}}").unwrap();
+}
+
+// impl PajamaxService::handle()
+//
+// Decode the request in the connection thread, and handle it in the
+// pool. The panics are always caught by `pajamax::dispatch::spawn()`.
+fn gen_service_handle(service: &prost_build::Service, buf: &mut String) {
+    writeln!(
+        buf,
+        "fn handle(
+            &self,
+            req_disc: usize,
+            req_buf: &[u8],
+            stream_id: u32,
+            frame_len: usize,
+        ) -> Result<(), pajamax::error::Error> {{
+            use prost::Message;
+            match req_disc {{"
+    )
+    .unwrap();
+
+    for (i, m) in service.methods.iter().enumerate() {
+        writeln!(
+            buf,
+            "{} => {{
+                let request = {}::decode(req_buf)?;
+                let inner = self.0.clone();
+                pajamax::dispatch::spawn(stream_id, frame_len, move || {{
+                    inner.{}(request).map(|reply|
+                        Box::new({}{}Reply(reply)) as Box<dyn pajamax::ReplyEncode>)
+                }})
+            }}",
+            i, m.input_type, m.name, service.name, m.proto_name
+        )
+        .unwrap();
+    }
+    writeln!(buf, "d => unreachable!(\"invalid req_disc: {{d}}\"), }} }}").unwrap();
+}
@@ -0,0 +1,108 @@
+//! Access log, one structured record for each completed request.
+//!
+//! Configure a sink by [`crate::Config::access_log`]. The sink is called
+//! on the thread that builds the response, which is the connection thread
+//! in local-mode and the response thread in dispatch-mode, so it should
+//! not block. [`FileSink`] is a built-in sink that writes records to a
+//! file on a background thread.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! pajamax::Config::new()
+//!     .access_log(FileSink::open("access.log")?)
+//!     .add_service(GreeterServer::new(greeter))
+//!     .serve(addr)
+//!     .unwrap();
+//! ```
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::log_writer::{write_json_str, LogWriter};
+use crate::status::Code;
+
+/// Record of one completed request.
+#[derive(Debug)]
+pub struct AccessRecord<'a> {
+    pub peer: SocketAddr,
+    /// Method path, e.g. `/helloworld.Greeter/SayHello`.
+    pub method: &'a str,
+    pub stream_id: u32,
+    pub code: Code,
+    /// Size of the request DATA frame.
+    pub req_size: usize,
+    /// Size of the response HTTP/2 frames.
+    pub resp_size: usize,
+    /// From receiving the request to calling the handler, including
+    /// the time waiting in dispatch channel in dispatch-mode.
+    pub queue_time: Duration,
+    /// From calling the handler to building the response.
+    pub handler_time: Duration,
+}
+
+impl fmt::Display for AccessRecord<'_> {
+    // One JSON object.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{{\"ts\":{}.{:06},\"peer\":\"{}\",\"method\":",
+            ts.as_secs(),
+            ts.subsec_micros(),
+            self.peer
+        )?;
+        write_json_str(f, self.method)?;
+        write!(
+            f,
+            ",\"stream_id\":{},\"code\":{},\"req_size\":{},\"resp_size\":{},\"queue_us\":{},\"handler_us\":{}}}",
+            self.stream_id,
+            self.code as u8,
+            self.req_size,
+            self.resp_size,
+            self.queue_time.as_micros(),
+            self.handler_time.as_micros(),
+        )
+    }
+}
+
+/// Where access records go.
+pub trait AccessLogSink: Send + Sync {
+    fn log(&self, record: &AccessRecord);
+}
+
+impl fmt::Debug for dyn AccessLogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccessLogSink")
+    }
+}
+
+/// Write records as JSON lines into a file, by a [`LogWriter`] with
+/// a queue of [`Self::QUEUE_SIZE`] records.
+pub struct FileSink(LogWriter);
+
+impl FileSink {
+    pub const QUEUE_SIZE: usize = 64 * 1024;
+
+    /// Open the file in append mode, and start the writer thread.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(LogWriter::new(file, Self::QUEUE_SIZE)?))
+    }
+
+    /// Number of records dropped because the queue is full.
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped()
+    }
+}
+
+impl AccessLogSink for FileSink {
+    fn log(&self, record: &AccessRecord) {
+        self.0.write_line(format!("{record}\n"));
+    }
+}
@@ -0,0 +1,275 @@
+//! Built-in admin HTTP/1.1 endpoint, served on a side port.
+//!
+//! It's a tiny synchronous server, handling one request per connection.
+//! Each connection is served in its own thread, so a slow client does not
+//! block the others, e.g. `/healthz` probes. At most
+//! `MAX_CONNECTIONS` connections are served at the same time, and more
+//! are closed at once. Paths:
+//!
+//! - `/metrics`: see `crate::metrics::render_prometheus()`;
+//! - `/healthz`: always `ok` while the server is running;
+//! - `/connections`: live connections, with the age of each connection.
+//!   It's also the age of the connection's `pajamax-w` thread in the
+//!   thread-per-connection model. In the worker-pool and reactor models,
+//!   connections share the threads, and thread ages are not reported;
+//! - `/config`: the effective `Config`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::macros::*;
+
+// Statistics of one connection.
+pub(crate) struct ConnStats {
+    pub(crate) peer: SocketAddr,
+    start: Instant,
+    pub(crate) requests: AtomicU64,
+    pub(crate) streams: AtomicUsize, // streams waiting for DATA frame
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) bytes_out: AtomicU64,
+    pub(crate) panics: AtomicU64,
+}
+
+// All live connections.
+#[derive(Default)]
+pub(crate) struct Registry {
+    conns: Mutex<Vec<Arc<ConnStats>>>,
+}
+
+impl Registry {
+    pub(crate) fn register(&self, peer: SocketAddr) -> Arc<ConnStats> {
+        let stats = Arc::new(ConnStats {
+            peer,
+            start: Instant::now(),
+            requests: AtomicU64::new(0),
+            streams: AtomicUsize::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+        });
+        self.conns.lock().unwrap().push(stats.clone());
+        stats
+    }
+
+    pub(crate) fn unregister(&self, stats: &Arc<ConnStats>) {
+        self.conns
+            .lock()
+            .unwrap()
+            .retain(|s| !Arc::ptr_eq(s, stats));
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for s in self.conns.lock().unwrap().iter() {
+            out.push_str(&format!(
+                "peer={} age={:.3}s requests={} streams={} bytes_in={} bytes_out={} panics={}\n",
+                s.peer,
+                s.start.elapsed().as_secs_f64(),
+                s.requests.load(Ordering::Relaxed),
+                s.streams.load(Ordering::Relaxed),
+                s.bytes_in.load(Ordering::Relaxed),
+                s.bytes_out.load(Ordering::Relaxed),
+                s.panics.load(Ordering::Relaxed),
+            ));
+        }
+        out
+    }
+}
+
+// Max admin connections served at the same time.
+const MAX_CONNECTIONS: usize = 4;
+
+// Bind the admin address and start the admin accept thread.
+pub(crate) fn start(
+    addr: SocketAddr,
+    config: &Config,
+    registry: Arc<Registry>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("admin endpoint listening on {addr}");
+
+    let config: Arc<str> = Arc::from(format!("{config:#?}\n"));
+    std::thread::Builder::new()
+        .name(String::from("pajamax-admin"))
+        .spawn(move || accept_routine(listener, config, registry))?;
+    Ok(())
+}
+
+fn accept_routine(listener: TcpListener, config: Arc<str>, registry: Arc<Registry>) {
+    let active = Arc::new(AtomicUsize::new(0));
+    for c in listener.incoming() {
+        let Ok(c) = c else {
+            continue;
+        };
+
+        // Only this thread adds, so the limit is never exceeded.
+        if active.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+            error!("admin connection closed for limit");
+            continue;
+        }
+        active.fetch_add(1, Ordering::Relaxed);
+
+        let config = config.clone();
+        let registry = registry.clone();
+        let active2 = active.clone();
+        let spawned = std::thread::Builder::new()
+            .name(String::from("pajamax-admin-c"))
+            .spawn(move || {
+                if let Err(err) = serve(c, &config, &registry) {
+                    error!("admin request fail: {:?}", err);
+                }
+                active2.fetch_sub(1, Ordering::Relaxed);
+            });
+        if let Err(err) = spawned {
+            error!("admin thread spawn fail: {:?}", err);
+            active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn serve(mut c: TcpStream, config: &str, registry: &Registry) -> std::io::Result<()> {
+    c.set_read_timeout(Some(Duration::from_secs(5)))?;
+    c.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    // request line, e.g. "GET /metrics HTTP/1.1"
+    let mut reader = BufReader::new(&mut c);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_ascii_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    // skip the request headers
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = if method != "GET" {
+        (
+            "405 Method Not Allowed",
+            String::from("method not allowed\n"),
+        )
+    } else {
+        match path.split('?').next().unwrap() {
+            "/metrics" => metrics(),
+            "/healthz" => ("200 OK", String::from("ok\n")),
+            "/connections" => ("200 OK", registry.render()),
+            "/config" => ("200 OK", String::from(config)),
+            _ => ("404 Not Found", String::from("not found\n")),
+        }
+    };
+
+    write!(
+        c,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(feature = "metrics")]
+fn metrics() -> (&'static str, String) {
+    ("200 OK", crate::metrics::render_prometheus())
+}
+
+#[cfg(not(feature = "metrics"))]
+fn metrics() -> (&'static str, String) {
+    (
+        "404 Not Found",
+        String::from("the `metrics` feature is not enabled\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Send the request to `serve()`, and return the response.
+    fn request(req: &str, registry: &Registry) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (c, _) = listener.accept().unwrap();
+
+        client.write_all(req.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        serve(c, "config\n", registry).unwrap();
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[test]
+    fn routes() {
+        let registry = Registry::default();
+        let stats = registry.register("127.0.0.1:1234".parse().unwrap());
+        stats.requests.fetch_add(3, Ordering::Relaxed);
+
+        let resp = request("GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n", &registry);
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.contains("\r\nContent-Length: 3\r\n"));
+        assert!(resp.ends_with("\r\n\r\nok\n"));
+
+        let resp = request("GET /config?x=1 HTTP/1.1\r\n\r\n", &registry);
+        assert!(resp.ends_with("\r\n\r\nconfig\n"));
+
+        let resp = request("GET /connections HTTP/1.1\r\n\r\n", &registry);
+        assert!(resp.contains("peer=127.0.0.1:1234 age="));
+        assert!(resp.contains(" requests=3 "));
+
+        registry.unregister(&stats);
+        let resp = request("GET /connections HTTP/1.1\r\n\r\n", &registry);
+        assert!(resp.ends_with("\r\n\r\n"));
+
+        let resp = request("GET /nothing HTTP/1.1\r\n\r\n", &registry);
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let resp = request("POST /healthz HTTP/1.1\r\n\r\n", &registry);
+        assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // closed without any request
+        let resp = request("", &registry);
+        assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[test]
+    fn connection_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || accept_routine(listener, Arc::from("config\n"), Arc::default()));
+
+        // idle connections hold all the slots
+        let idle: Vec<TcpStream> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+
+        // accepted after the idle ones, and closed without response
+        let mut c = TcpStream::connect(addr).unwrap();
+        c.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        let _ = c.read_to_string(&mut resp);
+        assert_eq!(resp, "");
+
+        // a slot is freed
+        drop(idle);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut c = TcpStream::connect(addr).unwrap();
+            c.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+            let mut resp = String::new();
+            let _ = c.read_to_string(&mut resp);
+            if resp.starts_with("HTTP/1.1 200 OK") {
+                break;
+            }
+            assert!(Instant::now() < deadline, "no slot is freed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
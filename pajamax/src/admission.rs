@@ -0,0 +1,371 @@
+//! Admission control: rate limiting and load shedding.
+//!
+//! Requests are checked on receiving the HEADERS frame, before the
+//! protobuf decoding. Rejected requests are answered with
+//! `ResourceExhausted` status, and the handlers are not called.
+//!
+//! There are 2 mechanisms:
+//!
+//! - Rate limiting by token-buckets, per method, per connection, and
+//!   globally. See [`crate::Config::method_rate_limit`],
+//!   [`crate::Config::connection_rate_limit`] and
+//!   [`crate::Config::global_rate_limit`].
+//!
+//! - Adaptive load shedding, which watches the depth of dispatch channels
+//!   and the latency of local-mode handlers. See
+//!   [`crate::Config::load_shedding`].
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::status::{Code, Status};
+use crate::PajamaxService;
+
+/// Token-bucket rate limit.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Tokens filled per second.
+    pub rate: f64,
+    /// Capacity of the bucket, which is the max burst requests.
+    pub burst: f64,
+}
+
+impl RateLimit {
+    /// Allow `rate` requests per second, with bursts of up to `burst` requests.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+        }
+    }
+}
+
+/// Adaptive load shedding thresholds.
+///
+/// Requests are rejected early while any threshold is exceeded.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadShedding {
+    /// Max number of requests dispatched but not responded yet, in all
+    /// dispatch-mode services. This is the total depth of all dispatch
+    /// channels, plus the requests being handled in shard threads.
+    pub max_dispatch_depth: Option<usize>,
+
+    /// Max average latency of local-mode handlers in each connection.
+    ///
+    /// While shedding, 1 in every [`Self::PROBE_INTERVAL`] requests is
+    /// still handled as a probe, to find out when the handlers recover.
+    pub max_local_latency: Option<Duration>,
+}
+
+impl LoadShedding {
+    pub const PROBE_INTERVAL: usize = 10;
+}
+
+// Requests dispatched but not responded yet, in all connections.
+pub(crate) static DISPATCH_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+// One request counted in `DISPATCH_DEPTH`, until dropped.
+//
+// Owned by the request's response end, so the request is uncounted
+// however it ends: replied, failed to send, or dropped by the shard.
+pub(crate) struct DepthGuard(());
+
+impl DepthGuard {
+    pub(crate) fn new() -> Self {
+        DISPATCH_DEPTH.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DISPATCH_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last: Instant::now(),
+        }
+    }
+
+    // Fill the tokens since last time, and return if there is one.
+    fn refill(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last = now;
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+// Shared by all connections.
+pub(crate) struct Admission {
+    global: Option<Mutex<TokenBucket>>,
+    methods: HashMap<(usize, usize), Mutex<TokenBucket>>, // by (isvc, req_disc)
+    connection: Option<RateLimit>,
+    shedding: Option<LoadShedding>,
+}
+
+impl Admission {
+    pub(crate) fn new(
+        config: &Config,
+        services: &[Arc<dyn PajamaxService + Send + Sync + 'static>],
+    ) -> std::io::Result<Self> {
+        let mut methods = HashMap::new();
+        for (path, limit) in config.method_rate_limits.iter() {
+            let route = services
+                .iter()
+                .enumerate()
+                .find_map(|(i, svc)| svc.route(path.as_bytes()).map(|d| (i, d)));
+            let Some(route) = route else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown method in rate limit: {path}"),
+                ));
+            };
+            methods.insert(route, Mutex::new(TokenBucket::new(*limit)));
+        }
+
+        Ok(Self {
+            global: config
+                .global_rate_limit
+                .map(|l| Mutex::new(TokenBucket::new(l))),
+            methods,
+            connection: config.connection_rate_limit,
+            shedding: config.load_shedding,
+        })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.global.is_some()
+            || !self.methods.is_empty()
+            || self.connection.is_some()
+            || self.shedding.is_some()
+    }
+}
+
+// Per connection.
+pub(crate) struct ConnAdmission {
+    shared: Arc<Admission>,
+    connection: Option<TokenBucket>,
+
+    // for load shedding
+    local_latency: Duration, // moving average
+    shed_count: usize,
+}
+
+impl ConnAdmission {
+    pub(crate) fn new(shared: Arc<Admission>) -> Self {
+        Self {
+            connection: shared.connection.map(TokenBucket::new),
+            shared,
+            local_latency: Duration::ZERO,
+            shed_count: 0,
+        }
+    }
+
+    // Check a new request. Return Err if rejected.
+    pub(crate) fn admit(
+        &mut self,
+        isvc: usize,
+        req_disc: usize,
+        is_dispatch_mode: bool,
+    ) -> Result<(), Status> {
+        if let Some(shedding) = &self.shared.shedding {
+            if is_dispatch_mode {
+                if let Some(max) = shedding.max_dispatch_depth {
+                    if DISPATCH_DEPTH.load(Ordering::Relaxed) >= max {
+                        return Err(exhausted("load shedding: dispatch queue is too deep"));
+                    }
+                }
+            } else if let Some(max) = shedding.max_local_latency {
+                if self.local_latency > max {
+                    self.shed_count += 1;
+                    if !self.shed_count.is_multiple_of(LoadShedding::PROBE_INTERVAL) {
+                        return Err(exhausted("load shedding: handlers are too slow"));
+                    }
+                }
+            }
+        }
+
+        // Check all buckets first, and take the tokens only if all of
+        // them admit, so a rejected request does not use up the others.
+        // The shared buckets are always locked in this order.
+        let mut method = self
+            .shared
+            .methods
+            .get(&(isvc, req_disc))
+            .map(|bucket| bucket.lock().unwrap());
+        let mut global = self.shared.global.as_ref().map(|b| b.lock().unwrap());
+
+        if let Some(bucket) = &mut self.connection {
+            if !bucket.refill() {
+                return Err(exhausted("connection rate limit exceeded"));
+            }
+        }
+        if let Some(bucket) = &mut method {
+            if !bucket.refill() {
+                return Err(exhausted("method rate limit exceeded"));
+            }
+        }
+        if let Some(bucket) = &mut global {
+            if !bucket.refill() {
+                return Err(exhausted("global rate limit exceeded"));
+            }
+        }
+
+        self.connection.iter_mut().for_each(|bucket| bucket.take());
+        method.iter_mut().for_each(|bucket| bucket.take());
+        global.iter_mut().for_each(|bucket| bucket.take());
+        Ok(())
+    }
+
+    pub(crate) fn need_local_latency(&self) -> bool {
+        matches!(
+            self.shared.shedding,
+            Some(LoadShedding {
+                max_local_latency: Some(_),
+                ..
+            })
+        )
+    }
+
+    // Update the moving average of local handlers' latency.
+    pub(crate) fn record_local_latency(&mut self, latency: Duration) {
+        self.local_latency = (self.local_latency * 7 + latency) / 8;
+        if self.local_latency <= self.max_local_latency() {
+            self.shed_count = 0;
+        }
+    }
+
+    fn max_local_latency(&self) -> Duration {
+        self.shared
+            .shedding
+            .and_then(|s| s.max_local_latency)
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+fn exhausted(message: &str) -> Status {
+    Status {
+        code: Code::ResourceExhausted,
+        message: String::from(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn_admission(shedding: LoadShedding) -> ConnAdmission {
+        ConnAdmission::new(Arc::new(Admission {
+            global: None,
+            methods: HashMap::new(),
+            connection: None,
+            shedding: Some(shedding),
+        }))
+    }
+
+    #[test]
+    fn rate_limit_rejected_takes_no_token() {
+        let mut methods = HashMap::new();
+        methods.insert((0, 0), Mutex::new(TokenBucket::new(RateLimit::new(0, 1))));
+        let mut admission = ConnAdmission::new(Arc::new(Admission {
+            global: Some(Mutex::new(TokenBucket::new(RateLimit::new(0, 3)))),
+            methods,
+            connection: Some(RateLimit::new(0, 5)),
+            shedding: None,
+        }));
+        let conn_tokens = |a: &ConnAdmission| a.connection.as_ref().unwrap().tokens;
+        let global_tokens =
+            |a: &ConnAdmission| a.shared.global.as_ref().unwrap().lock().unwrap().tokens;
+
+        assert!(admission.admit(0, 0, false).is_ok());
+        assert_eq!(conn_tokens(&admission), 4.0);
+        assert_eq!(global_tokens(&admission), 2.0);
+
+        // rejected by the method limit
+        let status = admission.admit(0, 0, false).unwrap_err();
+        assert_eq!(status.code, Code::ResourceExhausted);
+        assert_eq!(status.message, "method rate limit exceeded");
+        assert_eq!(conn_tokens(&admission), 4.0);
+        assert_eq!(global_tokens(&admission), 2.0);
+
+        // other methods are limited by the connection and global only
+        assert!(admission.admit(0, 1, false).is_ok());
+        assert!(admission.admit(0, 1, false).is_ok());
+        let status = admission.admit(0, 1, false).unwrap_err();
+        assert_eq!(status.message, "global rate limit exceeded");
+        assert_eq!(conn_tokens(&admission), 2.0);
+    }
+
+    #[test]
+    fn shed_by_dispatch_depth() {
+        let base = DISPATCH_DEPTH.load(Ordering::Relaxed);
+        let mut admission = conn_admission(LoadShedding {
+            max_dispatch_depth: Some(base + 2),
+            max_local_latency: None,
+        });
+
+        let g1 = DepthGuard::new();
+        assert!(admission.admit(0, 0, true).is_ok());
+
+        let g2 = DepthGuard::new();
+        let status = admission.admit(0, 0, true).unwrap_err();
+        assert_eq!(status.code, Code::ResourceExhausted);
+
+        // local-mode requests are not affected
+        assert!(admission.admit(0, 0, false).is_ok());
+
+        drop(g1);
+        assert!(admission.admit(0, 0, true).is_ok());
+
+        drop(g2);
+        assert_eq!(DISPATCH_DEPTH.load(Ordering::Relaxed), base);
+    }
+
+    #[test]
+    fn shed_by_local_latency() {
+        let mut admission = conn_admission(LoadShedding {
+            max_dispatch_depth: None,
+            max_local_latency: Some(Duration::from_millis(10)),
+        });
+        assert!(admission.admit(0, 0, false).is_ok());
+
+        for _ in 0..20 {
+            admission.record_local_latency(Duration::from_millis(100));
+        }
+
+        // 1 probe in every PROBE_INTERVAL
+        let admitted = (0..LoadShedding::PROBE_INTERVAL * 3)
+            .filter(|_| admission.admit(0, 0, false).is_ok())
+            .count();
+        assert_eq!(admitted, 3);
+
+        // dispatch-mode requests are not affected
+        assert!(admission.admit(0, 0, true).is_ok());
+
+        // recover
+        for _ in 0..40 {
+            admission.record_local_latency(Duration::ZERO);
+        }
+        assert!(admission.admit(0, 0, false).is_ok());
+    }
+}
@@ -0,0 +1,260 @@
+//! Authentication by the `authorization` header.
+//!
+//! Configure a validator by [`crate::Config::authenticator`]. Then for
+//! each request, pajamax parses the `authorization` header into
+//! [`Credential`] and calls the validator, before calling the handler.
+//! If the validation fails, the request is rejected with the returned
+//! status, typically `Unauthenticated`, and the handler is not called.
+//!
+//! One gateway connection carries many requests with the same credential,
+//! so successful validations are cached per connection, for at most
+//! [`crate::Config::auth_cache_ttl`], after which the credential is
+//! validated again, in case it's revoked or expired. Failures are not
+//! cached.
+//!
+//! The claims returned by the validator are available to handlers, in
+//! both local-mode and dispatch-mode, by [`claims`].
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! pajamax::Config::new()
+//!     .authenticator(|cred: &Credential| match cred {
+//!         Credential::Bearer(token) => lookup_user(token).ok_or(Status {
+//!             code: Code::Unauthenticated,
+//!             message: String::from("invalid token"),
+//!         }),
+//!         Credential::ApiKey(_) => Err(Status {
+//!             code: Code::Unauthenticated,
+//!             message: String::from("API key is not accepted"),
+//!         }),
+//!     })
+//!     .add_service(GreeterServer::new(greeter))
+//!     .serve(addr)
+//!     .unwrap();
+//!
+//! // in handler
+//! fn say_hello(&self, req: HelloRequest) -> Result<HelloReply, Status> {
+//!     let user = pajamax::auth::claims::<User>().unwrap();
+//!     ...
+//! }
+//! ```
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::context;
+use crate::metadata::Metadata;
+use crate::status::{Code, Status};
+
+/// Credential parsed from the `authorization` header.
+#[derive(Debug)]
+pub enum Credential<'a> {
+    /// `authorization: Bearer <token>`. The scheme is case-insensitive.
+    Bearer(&'a str),
+    /// Any other `authorization` value is taken as an API key as a whole.
+    ApiKey(&'a str),
+}
+
+impl<'a> Credential<'a> {
+    fn parse(value: &'a str) -> Self {
+        match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                Credential::Bearer(token.trim_start())
+            }
+            _ => Credential::ApiKey(value),
+        }
+    }
+}
+
+pub(crate) type Claims = Arc<dyn Any + Send + Sync>;
+
+type ValidateFn = dyn Fn(&Credential) -> Result<Claims, Status> + Send + Sync;
+
+/// The validator wrapper, with type of claims erased.
+#[derive(Clone)]
+pub(crate) struct Authenticator(Arc<ValidateFn>);
+
+impl Authenticator {
+    pub(crate) fn new<F, C>(f: F) -> Self
+    where
+        F: Fn(&Credential) -> Result<C, Status> + Send + Sync + 'static,
+        C: Send + Sync + 'static,
+    {
+        Self(Arc::new(move |cred| f(cred).map(|c| Arc::new(c) as Claims)))
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+// Cache of successful validations in one connection, expired after
+// `ttl` to check revoked or expired tokens again.
+pub(crate) struct AuthCache {
+    authenticator: Authenticator,
+    ttl: Duration,
+    cache: HashMap<Arc<[u8]>, (Claims, Instant)>,
+}
+
+impl AuthCache {
+    // Clear the cache if too many, in case of clients who use
+    // a new token for each request.
+    const MAX_CACHED: usize = 64;
+
+    pub(crate) fn new(authenticator: Authenticator, ttl: Duration) -> Self {
+        Self {
+            authenticator,
+            ttl,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn authenticate(&mut self, metadata: &Metadata) -> Result<Claims, Status> {
+        self.authenticate_at(metadata, Instant::now())
+    }
+
+    // The clock is passed in for tests.
+    fn authenticate_at(&mut self, metadata: &Metadata, now: Instant) -> Result<Claims, Status> {
+        let Some(value) = metadata.get("authorization") else {
+            return Err(unauthenticated("missing authorization header"));
+        };
+
+        if let Some((claims, validated)) = self.cache.get(value) {
+            if now.duration_since(*validated) < self.ttl {
+                return Ok(claims.clone());
+            }
+        }
+
+        let Ok(value_str) = std::str::from_utf8(value) else {
+            return Err(unauthenticated("invalid authorization header"));
+        };
+
+        let claims = (self.authenticator.0)(&Credential::parse(value_str))?;
+
+        if self.cache.len() >= Self::MAX_CACHED {
+            self.cache.clear();
+        }
+        self.cache.insert(value.into(), (claims.clone(), now));
+        Ok(claims)
+    }
+}
+
+fn unauthenticated(message: &str) -> Status {
+    Status {
+        code: Code::Unauthenticated,
+        message: String::from(message),
+    }
+}
+
+/// Return the claims of the request being handled.
+///
+/// Return `None` if not called in a handler, or no authenticator is
+/// configured, or the type does not match.
+pub fn claims<T>() -> Option<Arc<T>>
+where
+    T: Any + Send + Sync,
+{
+    context::with(|ctx| ctx?.claims())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_cache(ttl: Duration) -> (AuthCache, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let authenticator = Authenticator::new(move |cred: &Credential| {
+            calls2.fetch_add(1, Ordering::Relaxed);
+            match cred {
+                Credential::Bearer("good") => Ok(()),
+                _ => Err(unauthenticated("bad token")),
+            }
+        });
+        (AuthCache::new(authenticator, ttl), calls)
+    }
+
+    fn metadata(auth: &str) -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.push("authorization".into(), auth.as_bytes().into());
+        metadata
+    }
+
+    #[test]
+    fn credential_parse() {
+        assert!(matches!(
+            Credential::parse("Bearer abc"),
+            Credential::Bearer("abc")
+        ));
+        assert!(matches!(
+            Credential::parse("bearer  abc"),
+            Credential::Bearer("abc")
+        ));
+        assert!(matches!(
+            Credential::parse("abc"),
+            Credential::ApiKey("abc")
+        ));
+        assert!(matches!(
+            Credential::parse("Basic abc"),
+            Credential::ApiKey("Basic abc")
+        ));
+    }
+
+    #[test]
+    fn cache_hit() {
+        let (mut cache, calls) = counting_cache(Duration::from_secs(60));
+        let good = metadata("Bearer good");
+
+        assert!(cache.authenticate(&good).is_ok());
+        assert!(cache.authenticate(&good).is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // failures are not cached
+        let bad = metadata("Bearer bad");
+        assert!(cache.authenticate(&bad).is_err());
+        assert!(cache.authenticate(&bad).is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        let status = cache.authenticate(&Metadata::new()).unwrap_err();
+        assert_eq!(status.code, Code::Unauthenticated);
+    }
+
+    #[test]
+    fn cache_expire() {
+        let ttl = Duration::from_secs(60);
+        let (mut cache, calls) = counting_cache(ttl);
+        let good = metadata("Bearer good");
+        let start = Instant::now();
+
+        assert!(cache.authenticate_at(&good, start).is_ok());
+        assert!(cache.authenticate_at(&good, start + ttl / 2).is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // validated again after the ttl, and cached from then on
+        assert!(cache.authenticate_at(&good, start + ttl).is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(cache.authenticate_at(&good, start + ttl + ttl / 2).is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn cache_disabled() {
+        let (mut cache, calls) = counting_cache(Duration::ZERO);
+        let good = metadata("Bearer good");
+
+        assert!(cache.authenticate(&good).is_ok());
+        assert!(cache.authenticate(&good).is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
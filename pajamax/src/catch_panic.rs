@@ -0,0 +1,50 @@
+// Catch panics in handlers, enabled by `catch_panics` in `pajamax-build`.
+//
+// A panic in a local-mode handler would unwind the connection thread
+// and drop all in-flight streams of the connection, while a panic in a
+// shard thread would leave its requests unanswered. So the generated
+// code calls handlers in `catch_panic()`, which answers the request with
+// `INTERNAL` status instead.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::context;
+use crate::macros::*;
+use crate::metrics;
+use crate::status::{Code, Status};
+use crate::Response;
+
+/// Call the handler, and convert its panic into `INTERNAL` status.
+///
+/// Used by pajamax-build crate.
+pub fn catch_panic<Reply, F>(f: F) -> Response<Reply>
+where
+    F: FnOnce() -> Response<Reply>,
+{
+    // The handler's state may be broken by the panic, while it's up to
+    // the application to tolerate that or not.
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(response) => response,
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown");
+
+            context::with(|ctx| match ctx {
+                Some(ctx) => {
+                    error!("handler panics on {}: {msg}", ctx.method());
+                    metrics::handler_panicked(ctx);
+                }
+                None => error!("handler panics: {msg}"), // e.g. in batch
+            });
+            context::set_panicked();
+
+            Err(Status {
+                code: Code::Internal,
+                message: String::from("handler panicked"),
+            })
+        }
+    }
+}
@@ -0,0 +1,556 @@
+//! Channel backends for dispatch mode.
+//!
+//! Requests are dispatched to the shard threads through channels, and
+//! responses are sent back through channels too. The channel type is
+//! pluggable by [`DispatchChannel`], to tune the latency vs. CPU
+//! tradeoff:
+//!
+//! - [`StdChannel`], `std::sync::mpsc::sync_channel`, the default. Its
+//!   blocking receiving wakes up slowly, which is why the response thread
+//!   polls the channel by `Config::dispatch_poll_interval` by default;
+//! - [`RingChannel`], a built-in lock-free ring buffer. Its receiving
+//!   never sleeps in kernel, but spins and yields, so it's of the lowest
+//!   latency, but burns CPU while waiting;
+//! - `CrossbeamChannel`, by `crossbeam-channel` crate, with `crossbeam`
+//!   feature;
+//! - `FlumeChannel`, by `flume` crate, with `flume` feature.
+//!
+//! The request channel is set for each dispatch-mode service by
+//! `pajamax_build::Builder::dispatch_channel`, and the response channel
+//! is set by `Config::dispatch_response_channel`.
+//!
+//! If a request channel is full, the request is rejected with `UNAVAILABLE`
+//! status by default. See [`Backpressure`] for other policies, and
+//! [`DispatchEvent`] to watch the full and closed channels.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+pub use std::sync::mpsc::{RecvError, SendError, TryRecvError, TrySendError};
+
+use crate::context::RequestContext;
+
+/// Channel backend for dispatch mode.
+pub trait DispatchChannel: Send + Sync + 'static {
+    type Sender<T: Send>: Sender<T>;
+    type Receiver<T: Send>: Receiver<T>;
+
+    /// Create a bounded channel.
+    fn bounded<T: Send>(cap: usize) -> (Self::Sender<T>, Self::Receiver<T>);
+}
+
+/// Send end of [`DispatchChannel`].
+pub trait Sender<T>: Clone + Send + Sync {
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>>;
+
+    /// Send and wait if the channel is full.
+    fn send(&self, t: T) -> Result<(), SendError<T>>;
+}
+
+/// Receive end of [`DispatchChannel`].
+pub trait Receiver<T>: Send {
+    fn try_recv(&self) -> Result<T, TryRecvError>;
+
+    /// Receive and wait if the channel is empty.
+    fn recv(&self) -> Result<T, RecvError>;
+}
+
+/// Channel by `std::sync::mpsc::sync_channel`.
+pub struct StdChannel;
+
+impl DispatchChannel for StdChannel {
+    type Sender<T: Send> = mpsc::SyncSender<T>;
+    type Receiver<T: Send> = mpsc::Receiver<T>;
+
+    fn bounded<T: Send>(cap: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        mpsc::sync_channel(cap)
+    }
+}
+
+impl<T: Send> Sender<T> for mpsc::SyncSender<T> {
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        mpsc::SyncSender::try_send(self, t)
+    }
+    fn send(&self, t: T) -> Result<(), SendError<T>> {
+        mpsc::SyncSender::send(self, t)
+    }
+}
+
+impl<T: Send> Receiver<T> for mpsc::Receiver<T> {
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        mpsc::Receiver::try_recv(self)
+    }
+    fn recv(&self) -> Result<T, RecvError> {
+        mpsc::Receiver::recv(self)
+    }
+}
+
+/// What to do if the request channel is full, set by
+/// `Config::dispatch_backpressure`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Reject the request with `UNAVAILABLE` status at once.
+    FailFast,
+
+    /// Wait for the shard to drain for at most the timeout, and then
+    /// reject the request with `UNAVAILABLE` status.
+    ///
+    /// A scattered request is sent to all the shards that are not full
+    /// first, and then waits for the full ones, within one timeout in
+    /// total.
+    Block(Duration),
+
+    /// Wait for the shard to drain without limit. The connection stops
+    /// reading from the socket meanwhile, so the client is pushed back
+    /// by TCP and HTTP/2 flow control.
+    ///
+    /// A scattered request is sent to all the shards that are not full
+    /// first, and then waits for the full ones.
+    ///
+    /// Not supported in the worker-pool model, where the other
+    /// connections of the same worker would be blocked too.
+    PauseReading,
+}
+
+/// Events of request channels, reported to `Config::dispatch_hook`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DispatchEvent {
+    /// The channel is full, and the connection waits for it by
+    /// [`Backpressure::Block`] or [`Backpressure::PauseReading`].
+    Waiting,
+
+    /// The channel is full, and the request is rejected.
+    Full,
+
+    /// The channel is closed, e.g. the shard thread is gone, and the
+    /// request is rejected.
+    Closed,
+}
+
+type HookFn = dyn Fn(DispatchEvent, &RequestContext) + Send + Sync;
+
+/// The hook wrapper.
+#[derive(Clone)]
+pub(crate) struct DispatchHook(Arc<HookFn>);
+
+impl DispatchHook {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(DispatchEvent, &RequestContext) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, event: DispatchEvent, ctx: &RequestContext) {
+        (self.0)(event, ctx)
+    }
+}
+
+impl fmt::Debug for DispatchHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DispatchHook")
+    }
+}
+
+/// Built-in lock-free ring buffer channel.
+///
+/// It's a bounded queue of multi-producer, for SPSC and MPSC both. The
+/// capacity is rounded up to power of 2.
+///
+/// Waiting, in `recv()` if empty or in `send()` if full, spins and yields
+/// the CPU, instead of sleeping in kernel.
+pub struct RingChannel;
+
+impl DispatchChannel for RingChannel {
+    type Sender<T: Send> = RingSender<T>;
+    type Receiver<T: Send> = RingReceiver<T>;
+
+    fn bounded<T: Send>(cap: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        let ring = Arc::new(Ring::new(cap));
+        (RingSender(ring.clone()), RingReceiver(ring))
+    }
+}
+
+// Dmitry Vyukov's bounded MPMC queue. Each slot has a sequence number,
+// which tells whether it's ready for the producer or the consumer at
+// the position.
+struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    head: AtomicUsize, // next position to push
+    tail: AtomicUsize, // next position to pop
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+struct Slot<T> {
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn new(cap: usize) -> Self {
+        let cap = cap.max(2).next_power_of_two();
+        let slots = (0..cap)
+            .map(|i| Slot {
+                seq: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Self {
+            slots,
+            mask: cap - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            senders: AtomicUsize::new(1),
+            receiver_alive: AtomicBool::new(true),
+        }
+    }
+
+    // Return the value back if full.
+    fn push(&self, t: T) -> Result<(), T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as isize - pos as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(t) };
+                        slot.seq.store(pos + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(cur) => pos = cur,
+                }
+            } else if diff < 0 {
+                return Err(t);
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as isize - (pos + 1) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let t = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.seq.store(pos + self.mask + 1, Ordering::Release);
+                        return Some(t);
+                    }
+                    Err(cur) => pos = cur,
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+// Spin first, and then yield the CPU.
+struct Backoff(u32);
+
+impl Backoff {
+    fn snooze(&mut self) {
+        if self.0 < 6 {
+            for _ in 0..(1 << self.0) {
+                std::hint::spin_loop();
+            }
+            self.0 += 1;
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Send end of [`RingChannel`].
+pub struct RingSender<T>(Arc<Ring<T>>);
+
+impl<T> Clone for RingSender<T> {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::Relaxed);
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for RingSender<T> {
+    fn drop(&mut self) {
+        self.0.senders.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T: Send> Sender<T> for RingSender<T> {
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        if !self.0.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(t));
+        }
+        self.0.push(t).map_err(TrySendError::Full)
+    }
+
+    fn send(&self, mut t: T) -> Result<(), SendError<T>> {
+        let mut backoff = Backoff(0);
+        loop {
+            match self.try_send(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(t2)) => t = t2,
+                Err(TrySendError::Disconnected(t)) => return Err(SendError(t)),
+            }
+            backoff.snooze();
+        }
+    }
+}
+
+/// Receive end of [`RingChannel`].
+pub struct RingReceiver<T>(Arc<Ring<T>>);
+
+impl<T> Drop for RingReceiver<T> {
+    fn drop(&mut self) {
+        self.0.receiver_alive.store(false, Ordering::Release);
+    }
+}
+
+impl<T: Send> Receiver<T> for RingReceiver<T> {
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(t) = self.0.pop() {
+            return Ok(t);
+        }
+        if self.0.senders.load(Ordering::Acquire) == 0 {
+            // pushed before the last sender dropped
+            return self.0.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    fn recv(&self) -> Result<T, RecvError> {
+        let mut backoff = Backoff(0);
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Empty) => backoff.snooze(),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+            }
+        }
+    }
+}
+
+/// Channel by `crossbeam-channel` crate.
+#[cfg(feature = "crossbeam")]
+pub struct CrossbeamChannel;
+
+#[cfg(feature = "crossbeam")]
+impl DispatchChannel for CrossbeamChannel {
+    type Sender<T: Send> = crossbeam_channel::Sender<T>;
+    type Receiver<T: Send> = crossbeam_channel::Receiver<T>;
+
+    fn bounded<T: Send>(cap: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        crossbeam_channel::bounded(cap)
+    }
+}
+
+#[cfg(feature = "crossbeam")]
+impl<T: Send> Sender<T> for crossbeam_channel::Sender<T> {
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        crossbeam_channel::Sender::try_send(self, t).map_err(|err| match err {
+            crossbeam_channel::TrySendError::Full(t) => TrySendError::Full(t),
+            crossbeam_channel::TrySendError::Disconnected(t) => TrySendError::Disconnected(t),
+        })
+    }
+    fn send(&self, t: T) -> Result<(), SendError<T>> {
+        crossbeam_channel::Sender::send(self, t).map_err(|err| SendError(err.0))
+    }
+}
+
+#[cfg(feature = "crossbeam")]
+impl<T: Send> Receiver<T> for crossbeam_channel::Receiver<T> {
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        crossbeam_channel::Receiver::try_recv(self).map_err(|err| match err {
+            crossbeam_channel::TryRecvError::Empty => TryRecvError::Empty,
+            crossbeam_channel::TryRecvError::Disconnected => TryRecvError::Disconnected,
+        })
+    }
+    fn recv(&self) -> Result<T, RecvError> {
+        crossbeam_channel::Receiver::recv(self).map_err(|_| RecvError)
+    }
+}
+
+/// Channel by `flume` crate.
+#[cfg(feature = "flume")]
+pub struct FlumeChannel;
+
+#[cfg(feature = "flume")]
+impl DispatchChannel for FlumeChannel {
+    type Sender<T: Send> = flume::Sender<T>;
+    type Receiver<T: Send> = flume::Receiver<T>;
+
+    fn bounded<T: Send>(cap: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        flume::bounded(cap)
+    }
+}
+
+#[cfg(feature = "flume")]
+impl<T: Send> Sender<T> for flume::Sender<T> {
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        flume::Sender::try_send(self, t).map_err(|err| match err {
+            flume::TrySendError::Full(t) => TrySendError::Full(t),
+            flume::TrySendError::Disconnected(t) => TrySendError::Disconnected(t),
+        })
+    }
+    fn send(&self, t: T) -> Result<(), SendError<T>> {
+        flume::Sender::send(self, t).map_err(|err| SendError(err.0))
+    }
+}
+
+#[cfg(feature = "flume")]
+impl<T: Send> Receiver<T> for flume::Receiver<T> {
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        flume::Receiver::try_recv(self).map_err(|err| match err {
+            flume::TryRecvError::Empty => TryRecvError::Empty,
+            flume::TryRecvError::Disconnected => TryRecvError::Disconnected,
+        })
+    }
+    fn recv(&self) -> Result<T, RecvError> {
+        flume::Receiver::recv(self).map_err(|_| RecvError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Count the dropped values.
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn ring_full_and_empty() {
+        let (tx, rx) = RingChannel::bounded(3); // rounded up to 4
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        for i in 0..4 {
+            assert_eq!(tx.try_send(i), Ok(()));
+        }
+        assert_eq!(tx.try_send(4), Err(TrySendError::Full(4)));
+
+        assert_eq!(rx.try_recv(), Ok(0));
+        assert_eq!(tx.try_send(4), Ok(()));
+        for i in 1..5 {
+            assert_eq!(rx.try_recv(), Ok(i));
+        }
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn ring_wraparound() {
+        let (tx, rx) = RingChannel::bounded(4);
+        for i in 0..100 {
+            assert_eq!(tx.try_send(i), Ok(()));
+            assert_eq!(tx.try_send(i + 1000), Ok(()));
+            assert_eq!(rx.try_recv(), Ok(i));
+            assert_eq!(rx.try_recv(), Ok(i + 1000));
+        }
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn ring_last_sender_dropped() {
+        let (tx, rx) = RingChannel::bounded(4);
+        let tx2 = tx.clone();
+        tx.try_send(1).unwrap();
+        drop(tx);
+        tx2.try_send(2).unwrap();
+        drop(tx2);
+
+        // queued values are still received
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn ring_receiver_dropped() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = RingChannel::bounded(4);
+        tx.try_send(Counted(dropped.clone())).ok().unwrap();
+        tx.try_send(Counted(dropped.clone())).ok().unwrap();
+        drop(rx);
+
+        match tx.try_send(Counted(dropped.clone())) {
+            Err(TrySendError::Disconnected(_)) => (),
+            _ => panic!("expect disconnected"),
+        }
+        assert!(tx.send(Counted(dropped.clone())).is_err());
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        // the queued values are dropped with the channel
+        drop(tx);
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn ring_multi_producers() {
+        const PRODUCERS: usize = 4;
+        const COUNT: usize = 100_000;
+
+        let (tx, rx) = RingChannel::bounded(16);
+        let handles: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    for i in 0..COUNT {
+                        tx.send((p, i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        // each producer's values are received exactly once, and in order
+        let mut next = [0; PRODUCERS];
+        while let Ok((p, i)) = rx.recv() {
+            assert_eq!(i, next[p]);
+            next[p] += 1;
+        }
+        assert_eq!(next, [COUNT; PRODUCERS]);
+
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+}
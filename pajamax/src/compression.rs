@@ -0,0 +1,525 @@
+//! gRPC message compression.
+//!
+//! Encodings are implemented by [`Codec`]. Built-in codecs are enabled
+//! by cargo features:
+//!
+//! - `gzip`: [`Gzip`];
+//! - `deflate`: [`Deflate`];
+//! - `zstd`: [`Zstd`];
+//! - `lz4`: [`Lz4`].
+//!
+//! Add more by [`crate::Config::codec`]. Then:
+//!
+//! - requests with `grpc-encoding` of supported encodings are decompressed
+//!   before decoding, while requests with unsupported encodings are
+//!   rejected with `Unimplemented`;
+//! - responses are compressed if the client accepts some supported
+//!   encoding by `grpc-accept-encoding`, and if the message is not smaller
+//!   than [`crate::Config::compression_threshold`].
+//!
+//! The supported encodings are listed in the `grpc-accept-encoding`
+//! header of responses.
+//!
+//! Without any codec, compressed requests are rejected with
+//! `Unimplemented`.
+//!
+//! # Per-method encoding
+//!
+//! The response encoding can be set for each method in `pajamax-build`,
+//! for example, to skip compression for hot small RPCs and to use zstd
+//! for bulk RPCs:
+//!
+//! ```rust,ignore
+//! pajamax_build::Builder::new(pajamax_build::PajamaxGen::Dispatch)
+//!     .method_encoding("/dict_store.DictStore/ListShard", "zstd")
+//!     .method_encoding("/dict_store.DictStore/Get", "identity")
+//!     .compile_protos(&["proto/dict_store.proto"], &["."])?;
+//! ```
+//!
+//! For methods with an encoding set, responses are compressed by it if the
+//! client accepts it, and if the message is not smaller than
+//! [`crate::Config::compression_threshold`] if set. Otherwise, it falls
+//! back to the default negotiation. The `identity` means never compress.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::metadata::Metadata;
+use crate::status::{Code, Status};
+
+/// Max size of decompressed request message, same with gRPC's default
+/// max receive message size.
+pub const MAX_DECOMPRESSED_SIZE: usize = 4 * 1024 * 1024;
+
+/// Compression algorithm of a `grpc-encoding`.
+pub trait Codec: Send + Sync {
+    /// Name in `grpc-encoding` header, e.g. `gzip`.
+    fn name(&self) -> &'static str;
+
+    /// Append the compressed `input` to `output`.
+    fn compress(&self, input: &[u8], output: &mut Vec<u8>);
+
+    /// Append the decompressed `input` to `output`.
+    ///
+    /// Implementations should fail if the output exceeds
+    /// [`MAX_DECOMPRESSED_SIZE`]. See [`read_limited`].
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()>;
+}
+
+/// `grpc-encoding: gzip`, by the `gzip` feature.
+#[cfg(feature = "gzip")]
+pub struct Gzip;
+
+#[cfg(feature = "gzip")]
+impl Codec for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+    fn compress(&self, input: &[u8], output: &mut Vec<u8>) {
+        use std::io::Write;
+        let mut e = flate2::write::GzEncoder::new(output, flate2::Compression::fast());
+        e.write_all(input).unwrap(); // writing into Vec never fails
+        e.finish().unwrap();
+    }
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+        read_limited(flate2::read::GzDecoder::new(input), output)
+    }
+}
+
+/// `grpc-encoding: deflate`, by the `deflate` feature.
+///
+/// This is the zlib format, the same with other gRPC implementations.
+#[cfg(feature = "deflate")]
+pub struct Deflate;
+
+#[cfg(feature = "deflate")]
+impl Codec for Deflate {
+    fn name(&self) -> &'static str {
+        "deflate"
+    }
+    fn compress(&self, input: &[u8], output: &mut Vec<u8>) {
+        use std::io::Write;
+        let mut e = flate2::write::ZlibEncoder::new(output, flate2::Compression::fast());
+        e.write_all(input).unwrap();
+        e.finish().unwrap();
+    }
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+        read_limited(flate2::read::ZlibDecoder::new(input), output)
+    }
+}
+
+/// `grpc-encoding: zstd`, by the `zstd` feature.
+#[cfg(feature = "zstd")]
+pub struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+    fn compress(&self, input: &[u8], output: &mut Vec<u8>) {
+        zstd::stream::copy_encode(input, output, 1).unwrap();
+    }
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+        read_limited(zstd::stream::read::Decoder::new(input)?, output)
+    }
+}
+
+/// `grpc-encoding: lz4`, in LZ4 frame format, by the `lz4` feature.
+///
+/// This is not a standard gRPC encoding, so clients need to support it
+/// by themselves.
+#[cfg(feature = "lz4")]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+    fn compress(&self, input: &[u8], output: &mut Vec<u8>) {
+        use std::io::Write;
+        let mut e = lz4_flex::frame::FrameEncoder::new(output);
+        e.write_all(input).unwrap();
+        e.finish().unwrap();
+    }
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+        read_limited(lz4_flex::frame::FrameDecoder::new(input), output)
+    }
+}
+
+/// Helper for [`Codec::decompress`], which reads from the decoder
+/// with limit of [`MAX_DECOMPRESSED_SIZE`].
+pub fn read_limited(r: impl std::io::Read, output: &mut Vec<u8>) -> std::io::Result<()> {
+    use std::io::Read;
+    let limit = MAX_DECOMPRESSED_SIZE as u64 + 1;
+    if r.take(limit).read_to_end(output)? > MAX_DECOMPRESSED_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            TooLarge,
+        ));
+    }
+    Ok(())
+}
+
+// Error of `read_limited()`, answered with `ResourceExhausted` status.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("message too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+// All supported codecs of the server.
+#[derive(Clone)]
+pub(crate) struct Codecs {
+    list: Vec<Arc<dyn Codec>>,
+    accept_encoding: String, // value of `grpc-accept-encoding` header
+}
+
+impl fmt::Debug for Codecs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.accept_encoding)
+    }
+}
+
+impl Codecs {
+    // With the built-in codecs enabled by features, in preference order.
+    pub(crate) fn new() -> Self {
+        let builtin: Vec<Arc<dyn Codec>> = vec![
+            #[cfg(feature = "zstd")]
+            Arc::new(Zstd),
+            #[cfg(feature = "lz4")]
+            Arc::new(Lz4),
+            #[cfg(feature = "gzip")]
+            Arc::new(Gzip),
+            #[cfg(feature = "deflate")]
+            Arc::new(Deflate),
+        ];
+        let mut codecs = Self {
+            list: Vec::new(),
+            accept_encoding: String::from("identity"),
+        };
+        for codec in builtin {
+            codecs.add(codec);
+        }
+        codecs
+    }
+
+    // Add, or replace the codec with the same name.
+    pub(crate) fn add(&mut self, codec: Arc<dyn Codec>) {
+        self.list.retain(|c| c.name() != codec.name());
+        self.list.push(codec);
+
+        self.accept_encoding = String::from("identity");
+        for c in self.list.iter() {
+            self.accept_encoding.push(',');
+            self.accept_encoding.push_str(c.name());
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // e.g. `identity,gzip,deflate`, or None if no codec is supported
+    pub(crate) fn accept_encoding(&self) -> Option<&str> {
+        (!self.list.is_empty()).then_some(self.accept_encoding.as_str())
+    }
+
+    fn find(&self, name: &str) -> Option<&Arc<dyn Codec>> {
+        self.list
+            .iter()
+            .find(|c| c.name().eq_ignore_ascii_case(name))
+    }
+
+    // Parse `grpc-encoding` and `grpc-accept-encoding` headers.
+    // Return Err if `grpc-encoding` is not supported.
+    //
+    // The `method_encoding` is set in pajamax-build for this method.
+    pub(crate) fn negotiate(
+        &self,
+        metadata: &Metadata,
+        method_encoding: Option<&str>,
+        threshold: Option<usize>,
+    ) -> Result<Negotiated, Status> {
+        let request = match metadata.get_str("grpc-encoding").map(str::trim) {
+            None | Some("identity") => None,
+            Some(name) => match self.find(name) {
+                Some(codec) => Some(codec.clone()),
+                None => {
+                    return Err(
+                        self.unimplemented(format!("grpc-encoding is not supported: {name}"))
+                    )
+                }
+            },
+        };
+
+        let accepted = || {
+            metadata
+                .get_str("grpc-accept-encoding")
+                .unwrap_or("")
+                .split(',')
+                .filter_map(|name| self.find(name.trim()))
+        };
+
+        let response = match method_encoding {
+            Some("identity") => None,
+            Some(name) if accepted().any(|c| c.name() == name) => {
+                let codec = self.find(name).unwrap().clone();
+                Some((codec, threshold.unwrap_or(0)))
+            }
+            // Prefer the request's encoding, and then the client's order.
+            _ => threshold.and_then(|threshold| {
+                let codec = match &request {
+                    Some(req) if accepted().any(|c| Arc::ptr_eq(c, req)) => req.clone(),
+                    _ => accepted().next()?.clone(),
+                };
+                Some((codec, threshold))
+            }),
+        };
+
+        Ok(Negotiated { request, response })
+    }
+
+    fn unimplemented(&self, message: String) -> Status {
+        Status {
+            code: Code::Unimplemented,
+            message: format!("{message}, accepted: {}", self.accept_encoding),
+        }
+    }
+}
+
+// Negotiated by request headers.
+#[derive(Clone, Default)]
+pub(crate) struct Negotiated {
+    request: Option<Arc<dyn Codec>>,
+    pub(crate) response: Option<(Arc<dyn Codec>, usize)>, // with threshold
+}
+
+impl Negotiated {
+    // Decompress the request message if the compressed-flag is set.
+    pub(crate) fn decompress<'a>(
+        &self,
+        compressed: bool,
+        msg: &'a [u8],
+        buf: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], Status> {
+        if !compressed {
+            return Ok(msg);
+        }
+        let Some(codec) = &self.request else {
+            return Err(Status {
+                code: Code::Unimplemented,
+                message: String::from("compressed message without supported grpc-encoding"),
+            });
+        };
+        buf.clear();
+        match codec.decompress(msg, buf) {
+            Ok(_) => Ok(buf),
+            Err(err) => {
+                let too_large = err.get_ref().is_some_and(|e| e.is::<TooLarge>());
+                Err(Status {
+                    code: if too_large {
+                        Code::ResourceExhausted
+                    } else {
+                        Code::Internal
+                    },
+                    message: format!("fail to decompress {}: {err}", codec.name()),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decompress to endless zeros, or fail.
+    struct Bomb;
+
+    impl Codec for Bomb {
+        fn name(&self) -> &'static str {
+            "bomb"
+        }
+        fn compress(&self, _input: &[u8], _output: &mut Vec<u8>) {}
+        fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+            if input.is_empty() {
+                return Err(std::io::Error::other("corrupt input"));
+            }
+            read_limited(std::io::repeat(0), output)
+        }
+    }
+
+    // Codec of the name, which does nothing.
+    struct Named(&'static str);
+
+    impl Codec for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+        fn compress(&self, _input: &[u8], _output: &mut Vec<u8>) {}
+        fn decompress(&self, _input: &[u8], _output: &mut Vec<u8>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn codecs(names: &[&'static str]) -> Codecs {
+        let mut codecs = Codecs {
+            list: Vec::new(),
+            accept_encoding: String::from("identity"),
+        };
+        for name in names {
+            codecs.add(Arc::new(Named(name)));
+        }
+        codecs
+    }
+
+    fn metadata(headers: &[(&str, &str)]) -> Metadata {
+        let mut metadata = Metadata::new();
+        for (name, value) in headers {
+            metadata.push((*name).into(), value.as_bytes().into());
+        }
+        metadata
+    }
+
+    // Names of the request and response codecs, and the threshold.
+    fn names(negotiated: &Negotiated) -> (Option<&str>, Option<(&str, usize)>) {
+        (
+            negotiated.request.as_ref().map(|c| c.name()),
+            negotiated.response.as_ref().map(|(c, t)| (c.name(), *t)),
+        )
+    }
+
+    #[test]
+    fn negotiate_request_encoding() {
+        let codecs = codecs(&["gzip", "deflate"]);
+
+        let status = codecs
+            .negotiate(&metadata(&[("grpc-encoding", "snappy")]), None, None)
+            .err()
+            .unwrap();
+        assert_eq!(status.code, Code::Unimplemented);
+        assert_eq!(
+            status.message,
+            "grpc-encoding is not supported: snappy, accepted: identity,gzip,deflate"
+        );
+
+        let negotiated = codecs
+            .negotiate(&metadata(&[("grpc-encoding", " GZIP ")]), None, None)
+            .ok()
+            .unwrap();
+        assert_eq!(names(&negotiated), (Some("gzip"), None));
+
+        // not compressed
+        for md in [metadata(&[("grpc-encoding", "identity")]), metadata(&[])] {
+            let negotiated = codecs.negotiate(&md, None, None).ok().unwrap();
+            assert_eq!(names(&negotiated), (None, None));
+        }
+    }
+
+    #[test]
+    fn negotiate_response_encoding() {
+        let codecs = codecs(&["gzip", "deflate"]);
+        let accept = |value| metadata(&[("grpc-accept-encoding", value)]);
+
+        // no threshold, no response compression
+        let negotiated = codecs.negotiate(&accept("gzip"), None, None).ok().unwrap();
+        assert_eq!(names(&negotiated), (None, None));
+
+        // the client's order, skipping unsupported ones
+        let negotiated = codecs
+            .negotiate(&accept("br, deflate,gzip"), None, Some(100))
+            .ok()
+            .unwrap();
+        assert_eq!(names(&negotiated), (None, Some(("deflate", 100))));
+
+        // prefer the request's encoding
+        let md = metadata(&[
+            ("grpc-encoding", "gzip"),
+            ("grpc-accept-encoding", "deflate,gzip"),
+        ]);
+        let negotiated = codecs.negotiate(&md, None, Some(0)).ok().unwrap();
+        assert_eq!(names(&negotiated), (Some("gzip"), Some(("gzip", 0))));
+
+        // nothing accepted
+        for md in [accept("identity"), accept("br"), metadata(&[])] {
+            let negotiated = codecs.negotiate(&md, None, Some(0)).ok().unwrap();
+            assert_eq!(names(&negotiated), (None, None));
+        }
+    }
+
+    #[test]
+    fn negotiate_method_encoding() {
+        let codecs = codecs(&["gzip", "zstd"]);
+        let accept = |value| metadata(&[("grpc-accept-encoding", value)]);
+
+        // never compressed
+        for threshold in [None, Some(0)] {
+            let negotiated = codecs
+                .negotiate(&accept("zstd,gzip"), Some("identity"), threshold)
+                .ok()
+                .unwrap();
+            assert_eq!(names(&negotiated), (None, None));
+        }
+
+        // compressed by zstd if accepted, even without threshold
+        let negotiated = codecs
+            .negotiate(&accept("gzip,zstd"), Some("zstd"), None)
+            .ok()
+            .unwrap();
+        assert_eq!(names(&negotiated), (None, Some(("zstd", 0))));
+        let negotiated = codecs
+            .negotiate(&accept("gzip,zstd"), Some("zstd"), Some(100))
+            .ok()
+            .unwrap();
+        assert_eq!(names(&negotiated), (None, Some(("zstd", 100))));
+
+        // or fall back to the default negotiation
+        let negotiated = codecs
+            .negotiate(&accept("gzip"), Some("zstd"), None)
+            .ok()
+            .unwrap();
+        assert_eq!(names(&negotiated), (None, None));
+        let negotiated = codecs
+            .negotiate(&accept("gzip"), Some("zstd"), Some(100))
+            .ok()
+            .unwrap();
+        assert_eq!(names(&negotiated), (None, Some(("gzip", 100))));
+    }
+
+    #[test]
+    fn decompress_failure() {
+        let negotiated = Negotiated {
+            request: Some(Arc::new(Bomb)),
+            response: None,
+        };
+        let mut buf = Vec::new();
+
+        let status = negotiated.decompress(true, b"x", &mut buf).unwrap_err();
+        assert_eq!(status.code, Code::ResourceExhausted);
+
+        let status = negotiated.decompress(true, b"", &mut buf).unwrap_err();
+        assert_eq!(status.code, Code::Internal);
+
+        let msg = negotiated.decompress(false, b"raw", &mut buf).ok();
+        assert_eq!(msg, Some(&b"raw"[..]));
+    }
+
+    #[test]
+    fn accept_encoding() {
+        let mut codecs = Codecs {
+            list: Vec::new(),
+            accept_encoding: String::from("identity"),
+        };
+        assert_eq!(codecs.accept_encoding(), None);
+
+        codecs.add(Arc::new(Bomb));
+        assert_eq!(codecs.accept_encoding(), Some("identity,bomb"));
+    }
+}
@@ -1,7 +1,21 @@
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::access_log::AccessLogSink;
+use crate::admission::{LoadShedding, RateLimit};
+use crate::auth::{Authenticator, Credential};
+use crate::channel::{Backpressure, DispatchChannel, DispatchEvent, DispatchHook, StdChannel};
+use crate::compression::{Codec, Codecs};
+use crate::context::RequestContext;
+use crate::dispatch::{self, NewResponseChannel};
+use crate::hpack_decoder::Capture;
+#[cfg(unix)]
+use crate::listener::FdListener;
+use crate::listener::Listener;
+use crate::status::Status;
+use crate::tls::TlsConfig;
+use crate::tracing::SpanExporter;
 use crate::PajamaxService;
 
 /// Configured server. Used to start the server.
@@ -10,7 +24,7 @@ use crate::PajamaxService;
 ///
 /// # Examples
 ///
-/// ```
+/// ```rust,ignore
 /// pajamax::Config::new()
 ///     .max_concurrent_connections(2000)
 ///     .add_service(GreeterServer::new(greeter))   // return ConfigedServer
@@ -38,10 +52,91 @@ impl ConfigedServer {
     where
         A: ToSocketAddrs,
     {
-        crate::connection::serve_with_config(self.services, self.config, addr)
+        #[cfg(unix)]
+        if self.config.reuse_port {
+            let n = self.config.acceptor_threads;
+            let listeners = crate::listener::bind_reuse_port(addr, n)?;
+            return crate::connection::serve_with_config(self.services, self.config, listeners);
+        }
+
+        let listener = std::net::TcpListener::bind(addr)?;
+        crate::connection::serve_with_config(self.services, self.config, vec![listener])
+    }
+
+    /// Start the server on a Unix domain socket!
+    ///
+    /// A stale socket file left by a dead server is removed. The file
+    /// permissions are set by [`Config::unix_socket_mode`].
+    #[cfg(unix)]
+    pub fn serve_unix<P>(self, path: P) -> std::io::Result<()>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let listener = crate::listener::bind_unix(path.as_ref(), self.config.unix_socket_mode)?;
+        crate::connection::serve_with_config(self.services, self.config, vec![listener])
+    }
+
+    /// Start the server on a listener created by you, e.g. a
+    /// `TcpListener` with socket options set.
+    ///
+    /// See [`crate::listener`] for details.
+    pub fn serve_listener<L>(self, listener: L) -> std::io::Result<()>
+    where
+        L: Listener,
+    {
+        crate::connection::serve_with_config(self.services, self.config, vec![listener])
+    }
+
+    /// Start the server on an inherited listening socket, TCP or Unix,
+    /// e.g. from [`crate::listener::listen_fds`].
+    #[cfg(unix)]
+    pub fn serve_from_fd(self, fd: std::os::fd::OwnedFd) -> std::io::Result<()> {
+        match FdListener::from(fd) {
+            FdListener::Tcp(listener) => self.serve_listener(listener),
+            FdListener::Unix(listener) => self.serve_listener(listener),
+        }
     }
 }
 
+/// How connections are driven by threads.
+///
+/// This is independent of the Local and Dispatch modes of services, which
+/// decide where the handlers run.
+#[derive(Clone, Copy, Debug)]
+pub enum ExecutionModel {
+    /// One thread for each connection, blocking on reading it.
+    ///
+    /// This is the most efficient for a few busy connections, e.g. from
+    /// gateways, which is the main scenario of pajamax.
+    ThreadPerConnection,
+
+    /// A fixed pool of worker threads, each of which multiplexes many
+    /// connections by epoll. Local-mode handlers run on the worker
+    /// threads, and responses are still flushed in batch.
+    ///
+    /// This is for many mostly idle connections. Note that slow clients
+    /// may block the worker by writing responses, until
+    /// [`Config::write_timeout`]. Dispatch-mode services still have one
+    /// response thread for each connection.
+    ///
+    /// Linux only. TLS and [`Backpressure::PauseReading`] are not
+    /// supported.
+    WorkerPool { threads: usize },
+
+    /// One thread drives all connections by epoll, and handlers are
+    /// called inline. It's pinned to the CPU if `cpu` is set.
+    ///
+    /// Responses are written without blocking: if the client is slow,
+    /// the rest is buffered and written later, and the connection is
+    /// not read until the buffer drains. So a slow client never stalls
+    /// others, while a slow handler stalls all.
+    ///
+    /// This is for extreme latency work with a few connections and fast
+    /// handlers. Linux only. Dispatch-mode services and TLS are not
+    /// supported.
+    Reactor { cpu: Option<usize> },
+}
+
 /// Configure the server.
 ///
 /// Generally you should:
@@ -52,7 +147,7 @@ impl ConfigedServer {
 ///
 /// # Examples
 ///
-/// ```
+/// ```rust,ignore
 /// pajamax::Config::new()
 ///     .max_concurrent_connections(2000) // config some option
 ///     .add_service(GreeterServer::new(greeter))  // return ConfigedServer
@@ -60,16 +155,48 @@ impl ConfigedServer {
 ///     .serve(addr)
 ///     .unwrap();
 /// ```
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) max_concurrent_connections: usize,
+    pub(crate) connection_queue_timeout: Option<Duration>,
     pub(crate) max_concurrent_streams: usize,
     pub(crate) max_frame_size: usize,
     pub(crate) max_flush_requests: usize,
     pub(crate) max_flush_size: usize,
     pub(crate) idle_timeout: Duration,
     pub(crate) write_timeout: Duration,
+    pub(crate) max_connection_panics: Option<usize>,
     pub(crate) dispatch_poll_interval: Option<Duration>,
+    pub(crate) dispatch_notify: bool,
+    pub(crate) dispatch_response_channel: NewResponseChannel,
+    pub(crate) dispatch_backpressure: Backpressure,
+    pub(crate) dispatch_hook: Option<DispatchHook>,
+    pub(crate) pool_threads: usize,
+    pub(crate) pool_queue_size: usize,
+    pub(crate) capture_metadata: bool,
+    pub(crate) authenticator: Option<Authenticator>,
+    pub(crate) auth_cache_ttl: Duration,
+    pub(crate) global_rate_limit: Option<RateLimit>,
+    pub(crate) connection_rate_limit: Option<RateLimit>,
+    pub(crate) method_rate_limits: Vec<(String, RateLimit)>,
+    pub(crate) load_shedding: Option<LoadShedding>,
+    pub(crate) admin_addr: Option<SocketAddr>,
+    pub(crate) access_log: Option<Arc<dyn AccessLogSink>>,
+    pub(crate) tracing: Option<Arc<dyn SpanExporter>>,
+    pub(crate) trace_sample_ratio: f64,
+    pub(crate) compression_threshold: Option<usize>,
+    pub(crate) codecs: Codecs,
+    pub(crate) tls: Option<TlsConfig>,
+    pub(crate) unix_socket_mode: Option<u32>,
+    pub(crate) acceptor_threads: usize,
+    pub(crate) reuse_port: bool,
+    pub(crate) execution_model: ExecutionModel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
@@ -77,13 +204,39 @@ impl Config {
     pub fn new() -> Self {
         Self {
             max_concurrent_connections: 100,
+            connection_queue_timeout: None,
             max_concurrent_streams: 1000,
             max_frame_size: 16 * 1024,
             max_flush_requests: 50,
             max_flush_size: 15000,
             idle_timeout: Duration::from_secs(60),
             write_timeout: Duration::from_secs(10),
+            max_connection_panics: None,
             dispatch_poll_interval: Some(Duration::from_millis(1)),
+            dispatch_notify: false,
+            dispatch_response_channel: dispatch::new_response_channel::<StdChannel>,
+            dispatch_backpressure: Backpressure::FailFast,
+            dispatch_hook: None,
+            pool_threads: crate::thread_pool::default_threads(),
+            pool_queue_size: 1000,
+            capture_metadata: false,
+            authenticator: None,
+            auth_cache_ttl: Duration::from_secs(60),
+            global_rate_limit: None,
+            connection_rate_limit: None,
+            method_rate_limits: Vec::new(),
+            load_shedding: None,
+            admin_addr: None,
+            access_log: None,
+            tracing: None,
+            trace_sample_ratio: 1.0,
+            compression_threshold: None,
+            codecs: Codecs::new(),
+            tls: None,
+            unix_socket_mode: None,
+            acceptor_threads: 1,
+            reuse_port: false,
+            execution_model: ExecutionModel::ThreadPerConnection,
         }
     }
 
@@ -94,6 +247,11 @@ impl Config {
     /// connection, the more efficient batch processing. So you'd better
     /// keep the concurrent connections as low as possible.
     ///
+    /// Excess connections are refused by HTTP/2 GOAWAY frame with
+    /// ENHANCE_YOUR_CALM error code, so that clients and load balancers
+    /// know to back off, rather than seeing an unexplained reset.
+    /// See also [`Self::connection_queue_timeout`].
+    ///
     /// Default: 100
     pub fn max_concurrent_connections(self, n: usize) -> Self {
         Self {
@@ -102,6 +260,21 @@ impl Config {
         }
     }
 
+    /// Keep excess new connections pending for at most this time, waiting
+    /// for live connections to close, before refusing them.
+    ///
+    /// The pending connections are queued, and started in order when
+    /// slots are freed, while accepting goes on. At most 1000 are queued,
+    /// and more are refused at once.
+    ///
+    /// Default: None, refuse at once
+    pub fn connection_queue_timeout(self, d: Option<Duration>) -> Self {
+        Self {
+            connection_queue_timeout: d,
+            ..self
+        }
+    }
+
     /// Limit for each connection.
     ///
     /// We just send this HTTP2 setting to clients and hope them respect it.
@@ -165,6 +338,20 @@ impl Config {
         }
     }
 
+    /// Close the connection after this many handler panics in it, since
+    /// a client triggering panics repeatedly is probably broken.
+    ///
+    /// Panics are caught only if enabled by `catch_panics` in
+    /// `pajamax-build`, and answered with `INTERNAL` status.
+    ///
+    /// Default: None, never close
+    pub fn max_connection_panics(self, n: Option<usize>) -> Self {
+        Self {
+            max_connection_panics: n,
+            ..self
+        }
+    }
+
     /// Set the poll-interval of response channel at the backend thread
     /// in dispatch-mode.
     ///
@@ -179,6 +366,8 @@ impl Config {
     /// Although the poll-mode has a minor impact on the performance of
     /// business threads, it brings some more latency.
     ///
+    /// See [`Self::dispatch_notify`] for a way between them.
+    ///
     /// Default: Some(1 milliseconds)
     pub fn dispatch_poll_interval(self, d: Option<Duration>) -> Self {
         Self {
@@ -187,6 +376,372 @@ impl Config {
         }
     }
 
+    /// Wake up the backend thread of response channel by notification
+    /// in dispatch-mode, instead of [`Self::dispatch_poll_interval`].
+    ///
+    /// The backend thread spins a while if the channel is empty, and
+    /// then sleeps on an eventfd (a condvar on non-Linux). The spinning
+    /// time adapts to how busy the channel is. Only the first response
+    /// after it sleeps wakes it up by syscall, while the others in the
+    /// same batch do not. So the application business threads pay for
+    /// at most one wakeup per batch of responses, and there is no
+    /// latency of polling.
+    ///
+    /// Default: false
+    pub fn dispatch_notify(self, enable: bool) -> Self {
+        Self {
+            dispatch_notify: enable,
+            ..self
+        }
+    }
+
+    /// Set the channel type of response channel in dispatch-mode.
+    ///
+    /// For example, with [`crate::channel::RingChannel`], set
+    /// [`Self::dispatch_poll_interval`] to `None` for the lowest latency,
+    /// since its blocking-mode `recv()` spins rather than sleeps.
+    ///
+    /// See [`crate::channel`] for details.
+    ///
+    /// Default: [`StdChannel`]
+    pub fn dispatch_response_channel<C: DispatchChannel>(self) -> Self {
+        Self {
+            dispatch_response_channel: dispatch::new_response_channel::<C>,
+            ..self
+        }
+    }
+
+    /// Set what to do if the request channel of a dispatch-mode service
+    /// is full: reject the request at once, wait for a while, or wait
+    /// and stop reading from the connection.
+    ///
+    /// See [`Backpressure`] for details.
+    ///
+    /// Default: [`Backpressure::FailFast`]
+    pub fn dispatch_backpressure(self, policy: Backpressure) -> Self {
+        Self {
+            dispatch_backpressure: policy,
+            ..self
+        }
+    }
+
+    /// Call the hook if a request channel is full or closed, besides the
+    /// `pajamax_dispatch_*` metrics. It's called on the connection thread
+    /// with the context of the request, so keep it fast.
+    ///
+    /// See [`DispatchEvent`] for the events.
+    ///
+    /// Default: None
+    pub fn dispatch_hook<F>(self, f: F) -> Self
+    where
+        F: Fn(DispatchEvent, &RequestContext) + Send + Sync + 'static,
+    {
+        Self {
+            dispatch_hook: Some(DispatchHook::new(f)),
+            ..self
+        }
+    }
+
+    /// Set the number of threads of the shared thread pool, which runs
+    /// the handlers of pool-mode services.
+    ///
+    /// The pool is shared by all servers in the process, and configured
+    /// by the first one serving, with this and [`Self::pool_queue_size`].
+    /// The settings of later servers are ignored, with an error logged
+    /// if they differ.
+    ///
+    /// Default: the number of CPUs
+    pub fn pool_threads(self, n: usize) -> Self {
+        Self {
+            pool_threads: n.max(1),
+            ..self
+        }
+    }
+
+    /// Set the maximum number of requests queued in the shared thread
+    /// pool of pool-mode services. More requests are answered with
+    /// `UNAVAILABLE` status.
+    ///
+    /// Only the first server's setting works, the same as
+    /// [`Self::pool_threads`].
+    ///
+    /// Default: 1000
+    pub fn pool_queue_size(self, size: usize) -> Self {
+        Self {
+            pool_queue_size: size,
+            ..self
+        }
+    }
+
+    /// Capture request headers as metadata, which handlers can access
+    /// by [`crate::context::with`].
+    ///
+    /// Pajamax parses only the `:path` header by default for performance.
+    /// This is turned on automatically by options that need metadata,
+    /// such as [`Self::authenticator`] and [`Self::tracing`]. With codecs
+    /// only, just the `grpc-encoding` and `grpc-accept-encoding` headers
+    /// are parsed.
+    ///
+    /// Default: false
+    pub fn capture_metadata(self, b: bool) -> Self {
+        Self {
+            capture_metadata: b,
+            ..self
+        }
+    }
+
+    /// Authenticate each request by the `authorization` header.
+    ///
+    /// The validator is called synchronously on the connection thread,
+    /// and successful validations are cached per connection, see
+    /// [`Self::auth_cache_ttl`].
+    /// Requests failing in validation are rejected with the returned status.
+    /// The returned claims are available to handlers by
+    /// [`crate::auth::claims`].
+    ///
+    /// See [`crate::auth`] for details.
+    ///
+    /// Default: None
+    pub fn authenticator<F, C>(self, f: F) -> Self
+    where
+        F: Fn(&Credential) -> Result<C, Status> + Send + Sync + 'static,
+        C: Send + Sync + 'static,
+    {
+        Self {
+            authenticator: Some(Authenticator::new(f)),
+            ..self
+        }
+    }
+
+    /// Set how long a successful validation is cached in the connection.
+    /// The credential is validated again after that, so a revoked or
+    /// expired token is rejected in time. Set zero to disable the cache.
+    ///
+    /// Default: 60 seconds
+    pub fn auth_cache_ttl(self, ttl: Duration) -> Self {
+        Self {
+            auth_cache_ttl: ttl,
+            ..self
+        }
+    }
+
+    /// Limit the requests rate of all connections.
+    ///
+    /// Requests over the limit are rejected with `ResourceExhausted`.
+    /// See [`crate::admission`] for details.
+    ///
+    /// Default: None
+    pub fn global_rate_limit(self, limit: RateLimit) -> Self {
+        Self {
+            global_rate_limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Limit the requests rate of each connection.
+    ///
+    /// Default: None
+    pub fn connection_rate_limit(self, limit: RateLimit) -> Self {
+        Self {
+            connection_rate_limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Limit the requests rate of one method, in all connections.
+    ///
+    /// The `path` is in format of `/{package}.{Service}/{Method}`, e.g.
+    /// `/helloworld.Greeter/SayHello`. Starting the server fails if the
+    /// method is not found in the services.
+    ///
+    /// Call this multiple times for multiple methods.
+    pub fn method_rate_limit(mut self, path: &str, limit: RateLimit) -> Self {
+        self.method_rate_limits.push((String::from(path), limit));
+        self
+    }
+
+    /// Reject requests early while the server is overloaded.
+    ///
+    /// Default: None
+    pub fn load_shedding(self, shedding: LoadShedding) -> Self {
+        Self {
+            load_shedding: Some(shedding),
+            ..self
+        }
+    }
+
+    /// Start a tiny HTTP/1.1 admin endpoint on this address, on
+    /// separate threads. It serves:
+    ///
+    /// - `/metrics`: metrics in Prometheus format, if the `metrics`
+    ///   feature is enabled. See [`crate::metrics`];
+    /// - `/healthz`: `ok`;
+    /// - `/connections`: peer address, age, handled requests, pending
+    ///   streams and bytes of each live connection;
+    /// - `/config`: the effective configuration.
+    ///
+    /// Do not expose this to the public.
+    ///
+    /// Default: None
+    pub fn admin_addr(self, addr: SocketAddr) -> Self {
+        Self {
+            admin_addr: Some(addr),
+            ..self
+        }
+    }
+
+    /// Write one record for each completed request into the sink.
+    ///
+    /// See [`crate::access_log`] for details.
+    ///
+    /// Default: None
+    pub fn access_log<S>(self, sink: S) -> Self
+    where
+        S: AccessLogSink + 'static,
+    {
+        Self {
+            access_log: Some(Arc::new(sink)),
+            ..self
+        }
+    }
+
+    /// Propagate W3C Trace Context, and export a server span for
+    /// each request into the exporter.
+    ///
+    /// See [`crate::tracing`] for details.
+    ///
+    /// Default: None
+    pub fn tracing<E>(self, exporter: E) -> Self
+    where
+        E: SpanExporter + 'static,
+    {
+        Self {
+            tracing: Some(Arc::new(exporter)),
+            ..self
+        }
+    }
+
+    /// Ratio of new traces to sample, in `[0.0, 1.0]`, if tracing is
+    /// enabled by [`Self::tracing`].
+    ///
+    /// This applies only to requests without a valid `traceparent`,
+    /// which start new traces. The decision is made by the trace ID, so
+    /// it's consistent for all servers using the same ratio. Requests
+    /// with `traceparent` follow its sampled flag.
+    ///
+    /// Default: 1.0, sample all
+    pub fn trace_sample_ratio(self, ratio: f64) -> Self {
+        Self {
+            trace_sample_ratio: ratio.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Compress response messages not smaller than this size, if the
+    /// client accepts some supported encoding.
+    ///
+    /// This works only if some codec is supported, by cargo features or
+    /// [`Self::codec`]. Compressed requests are accepted whether this is
+    /// set or not. See [`crate::compression`] for details.
+    ///
+    /// Default: None, which means never compress responses
+    pub fn compression_threshold(self, n: usize) -> Self {
+        Self {
+            compression_threshold: Some(n),
+            ..self
+        }
+    }
+
+    /// Support one more `grpc-encoding`, besides the built-in codecs
+    /// enabled by cargo features. A built-in codec with the same name
+    /// is replaced.
+    ///
+    /// Call this multiple times for multiple codecs.
+    pub fn codec<C>(mut self, codec: C) -> Self
+    where
+        C: Codec + 'static,
+    {
+        self.codecs.add(Arc::new(codec));
+        self
+    }
+
+    /// Serve over TLS, with ALPN `h2`. This needs the `tls` feature,
+    /// otherwise serving fails.
+    ///
+    /// See [`crate::tls`] for details.
+    ///
+    /// Default: None
+    pub fn tls(self, tls: TlsConfig) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// Permissions of the socket file in [`ConfigedServer::serve_unix`],
+    /// e.g. `0o660` to allow the group only.
+    ///
+    /// Default: None, which means decided by umask
+    pub fn unix_socket_mode(self, mode: u32) -> Self {
+        Self {
+            unix_socket_mode: Some(mode),
+            ..self
+        }
+    }
+
+    /// Number of threads accepting new connections.
+    ///
+    /// More threads help bursts of new connections, e.g. clients
+    /// reconnecting after a deploy. The threads share one listener,
+    /// unless [`Self::reuse_port`] is set. The
+    /// [`Self::max_concurrent_connections`] limit is shared by all threads.
+    ///
+    /// Default: 1
+    pub fn acceptor_threads(self, n: usize) -> Self {
+        assert!(n > 0, "acceptor_threads must be positive");
+        Self {
+            acceptor_threads: n,
+            ..self
+        }
+    }
+
+    /// Each acceptor thread binds its own socket with `SO_REUSEPORT`, so
+    /// the kernel balances new connections among them. This works in
+    /// [`ConfigedServer::serve`] on Unix only.
+    ///
+    /// Default: false
+    pub fn reuse_port(self, b: bool) -> Self {
+        Self {
+            reuse_port: b,
+            ..self
+        }
+    }
+
+    /// How connections are driven by threads. See [`ExecutionModel`].
+    ///
+    /// Default: [`ExecutionModel::ThreadPerConnection`]
+    pub fn execution_model(self, model: ExecutionModel) -> Self {
+        if let ExecutionModel::WorkerPool { threads } = model {
+            assert!(threads > 0, "worker threads must be positive");
+        }
+        Self {
+            execution_model: model,
+            ..self
+        }
+    }
+
+    // Which request headers to parse into metadata.
+    pub(crate) fn metadata_capture(&self) -> Capture {
+        if self.capture_metadata || self.authenticator.is_some() || self.tracing.is_some() {
+            Capture::All
+        } else if !self.codecs.is_empty() {
+            // only for the compression negotiation
+            Capture::Only(&["grpc-encoding", "grpc-accept-encoding"])
+        } else {
+            Capture::None
+        }
+    }
+
     /// Add the first service, and return a ConfigedServer.
     pub fn add_service<S>(self, svc: S) -> ConfigedServer
     where
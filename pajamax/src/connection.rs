@@ -1,71 +1,609 @@
 use std::cell::RefCell;
 use std::collections::VecDeque;
-use std::io::Read;
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use crate::dispatch;
+use crate::admin::{self, ConnStats, Registry};
+use crate::admission::{Admission, ConnAdmission};
+use crate::auth::AuthCache;
+#[cfg(target_os = "linux")]
+use crate::channel::Backpressure;
+use crate::compression::Negotiated;
+use crate::config::{Config, ExecutionModel};
+use crate::context::{self, Method, RequestContext};
+use crate::dispatch::{self, ConnOutput, ConnResponseTx, LocalReply};
 use crate::error::Error;
 use crate::hpack_decoder::{Decoder, PathKind};
 use crate::http2::*;
+use crate::listener::{Connection, Listener};
 use crate::macros::*;
-use crate::response_end::ResponseEnd;
-use crate::{PajamaxService, Response};
+use crate::metadata::Metadata;
+use crate::metrics;
+#[cfg(target_os = "linux")]
+use crate::reactor::Reactor;
+use crate::response_end::{Output, ResponseEnd};
+use crate::status::Status;
+use crate::thread_pool;
+use crate::tls::{PeerIdentity, TlsConfig};
+use crate::tracing::SpanContext;
+#[cfg(target_os = "linux")]
+use crate::worker_pool::WorkerPool;
+use crate::{PajamaxService, ReplyEncode, Response};
 
-pub fn serve_with_config<A>(
+// Shared by all acceptor threads.
+struct Server {
     services: Vec<Arc<dyn PajamaxService + Send + Sync + 'static>>,
     config: Config,
-    addr: A,
+    concurrent: Arc<AtomicUsize>, // also read by metrics
+    queue: Mutex<VecDeque<Queued>>,
+    slot_freed: Condvar, // or queue changed
+    refuser: mpsc::SyncSender<Refusal>,
+    lingerer: mpsc::Sender<Lingering>,
+    admission: Arc<Admission>,
+    tls: Option<Arc<TlsAcceptor>>,
+    registry: Arc<Registry>,
+    #[cfg(target_os = "linux")]
+    pool: Option<WorkerPool>,
+    #[cfg(target_os = "linux")]
+    reactor: Option<Reactor>,
+}
+
+impl Server {
+    fn close(&self, result: Result<(), Error>, stats: &Arc<ConnStats>) {
+        match result {
+            Ok(_) => info!("connection closed"),
+            Err(err) => error!("connection fail: {:?}", err),
+        }
+        self.registry.unregister(stats);
+        self.concurrent.fetch_sub(1, Ordering::Relaxed);
+
+        // wake up the queue thread for the freed slot
+        if self.config.connection_queue_timeout.is_some() {
+            let _queue = self.queue.lock().unwrap();
+            self.slot_freed.notify_one();
+        }
+    }
+
+    // Take a slot of the concurrent limit, shared by all acceptors.
+    fn try_acquire_slot(&self) -> bool {
+        let max = self.config.max_concurrent_connections;
+        self.concurrent
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+}
+
+// An excess connection waiting for a slot, by
+// `Config::connection_queue_timeout`.
+struct Queued {
+    deadline: Instant,
+    handle: QueuedHandle,
+}
+
+// Start the queued connection if it gets a slot (true), or refuse it.
+type QueuedHandle = Box<dyn FnOnce(&Arc<Server>, bool) + Send>;
+
+// Max excess connections waiting for slots. More are refused at once.
+const MAX_QUEUED_CONNECTIONS: usize = 1000;
+
+// Start the queued connections when slots are freed, in order, and
+// refuse those waiting for longer than the timeout.
+fn queue_routine(server: Arc<Server>) {
+    let mut queue = server.queue.lock().unwrap();
+    loop {
+        let mut ready = Vec::new();
+        while !queue.is_empty() && server.try_acquire_slot() {
+            ready.push((queue.pop_front().unwrap(), true));
+        }
+        let now = Instant::now();
+        while queue.front().is_some_and(|q| q.deadline <= now) {
+            ready.push((queue.pop_front().unwrap(), false));
+        }
+
+        if !ready.is_empty() {
+            drop(queue);
+            for (q, admitted) in ready {
+                (q.handle)(&server, admitted);
+            }
+            queue = server.queue.lock().unwrap();
+            continue;
+        }
+
+        queue = match queue.front() {
+            Some(q) => {
+                let timeout = q.deadline - now;
+                server.slot_freed.wait_timeout(queue, timeout).unwrap().0
+            }
+            None => server.slot_freed.wait(queue).unwrap(),
+        };
+    }
+}
+
+// Refusing of an excess TLS connection, run on the refuser threads.
+type Refusal = Box<dyn FnOnce() + Send>;
+
+// Refuser threads, for the TLS handshake of excess connections, so a
+// slow client does not hold up the acceptor or the others.
+const REFUSER_THREADS: usize = 4;
+
+// Refusals pending on the refuser threads. More are dropped.
+const MAX_PENDING_REFUSALS: usize = 100;
+
+// Time limit of TLS handshake and writing the GOAWAY.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn refuser_routine(rx: &Mutex<mpsc::Receiver<Refusal>>) {
+    loop {
+        let Ok(refusal) = rx.lock().unwrap().recv() else {
+            return;
+        };
+        refusal();
+    }
+}
+
+// A refused connection, kept open for a while after the GOAWAY, so the
+// client's writes in flight do not reset the connection before it reads
+// the GOAWAY.
+struct Lingering {
+    deadline: Instant,
+    _c: Box<dyn Send>,
+}
+
+// Time to keep the refused connections open.
+const REFUSE_LINGER: Duration = Duration::from_secs(1);
+
+// Max lingering connections. The oldest ones are closed early if more.
+const MAX_LINGERING: usize = 1000;
+
+// Close the lingering connections at their deadlines, without reading.
+fn linger_routine(rx: mpsc::Receiver<Lingering>) {
+    let mut queue: VecDeque<Lingering> = VecDeque::new();
+    loop {
+        let received = match queue.front() {
+            Some(l) => rx.recv_timeout(l.deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(l) => {
+                if queue.len() == MAX_LINGERING {
+                    queue.pop_front();
+                }
+                queue.push_back(l);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        while queue.front().is_some_and(|l| l.deadline <= now) {
+            queue.pop_front();
+        }
+    }
+}
+
+// Send SETTINGS and GOAWAY without waiting for the client preface, shut
+// down the writing, and hand the connection over to the linger thread.
+// This does not block except in TLS handshake.
+fn refuse<S: Connection>(server: &Server, c: S, tls: Option<&TlsAcceptor>) -> Result<(), Error> {
+    c.set_read_timeout(Some(REFUSE_TIMEOUT))?;
+    c.set_write_timeout(Some(REFUSE_TIMEOUT))?;
+    #[cfg(unix)]
+    let fd = c.as_raw_fd();
+
+    let (input, mut output, _) = split(c, tls)?;
+
+    let mut frames = Vec::new();
+    build_server_settings(&server.config, &mut frames);
+    build_goaway(0, ENHANCE_YOUR_CALM, &mut frames);
+    output.write_all(&frames)?;
+    output.flush()?;
+
+    #[cfg(unix)]
+    unsafe {
+        libc::shutdown(fd, libc::SHUT_WR);
+    }
+
+    let lingering = Lingering {
+        deadline: Instant::now() + REFUSE_LINGER,
+        _c: Box::new((input, output)),
+    };
+    let _ = server.lingerer.send(lingering);
+    Ok(())
+}
+
+// Run `config.acceptor_threads` acceptors on the listeners in turn,
+// one of which on the current thread.
+pub fn serve_with_config<L>(
+    services: Vec<Arc<dyn PajamaxService + Send + Sync + 'static>>,
+    config: Config,
+    listeners: Vec<L>,
 ) -> std::io::Result<()>
 where
-    A: ToSocketAddrs,
+    L: Listener,
 {
-    let concurrent = Arc::new(AtomicUsize::new(0));
+    let admission = Arc::new(Admission::new(&config, &services)?);
 
-    let listener = TcpListener::bind(addr)?;
-    for c in listener.incoming() {
-        // concurrent limit
-        if concurrent.load(Ordering::Relaxed) >= config.max_concurrent_connections {
-            error!("drop new connection for limit");
-            continue;
+    thread_pool::configure(config.pool_threads, config.pool_queue_size);
+
+    let tls = match &config.tls {
+        Some(tls_config) => Some(Arc::new(new_tls_acceptor(tls_config)?)),
+        None => None,
+    };
+
+    let registry = Arc::new(Registry::default());
+    if let Some(admin_addr) = config.admin_addr {
+        admin::start(admin_addr, &config, registry.clone())?;
+    }
+
+    #[cfg(target_os = "linux")]
+    let (pool, reactor) = match config.execution_model {
+        ExecutionModel::ThreadPerConnection => (None, None),
+        ExecutionModel::WorkerPool { threads } => {
+            if tls.is_some() {
+                return Err(unsupported("TLS in worker-pool model"));
+            }
+            if config.dispatch_backpressure == Backpressure::PauseReading {
+                return Err(unsupported(
+                    "PauseReading backpressure in worker-pool model",
+                ));
+            }
+            let pool = WorkerPool::new(threads, config.idle_timeout)?;
+            (Some(pool), None)
+        }
+        ExecutionModel::Reactor { cpu } => {
+            if tls.is_some() {
+                return Err(unsupported("TLS in reactor model"));
+            }
+            if services.iter().any(|svc| svc.is_dispatch_mode()) {
+                return Err(unsupported("dispatch-mode service in reactor model"));
+            }
+            let reactor = Reactor::new(cpu, config.idle_timeout, config.write_timeout)?;
+            (None, Some(reactor))
         }
-        concurrent.fetch_add(1, Ordering::Relaxed);
+    };
+    #[cfg(not(target_os = "linux"))]
+    if !matches!(config.execution_model, ExecutionModel::ThreadPerConnection) {
+        return Err(unsupported(
+            "execution models other than thread-per-connection",
+        ));
+    }
+
+    let (refuser, rx) = mpsc::sync_channel(MAX_PENDING_REFUSALS);
+    let rx = Arc::new(Mutex::new(rx));
+    let refuser_threads = if tls.is_some() { REFUSER_THREADS } else { 0 };
+    for _ in 0..refuser_threads {
+        let rx = rx.clone();
+        thread::Builder::new()
+            .name(String::from("pajamax-refuse"))
+            .spawn(move || refuser_routine(&rx))?;
+    }
+
+    let (lingerer, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name(String::from("pajamax-linger"))
+        .spawn(move || linger_routine(rx))?;
 
-        let c = c?;
-        info!("new connection from {}", c.local_addr().unwrap().ip());
+    let concurrent = Arc::new(AtomicUsize::new(0));
+    metrics::register_connections(&concurrent);
 
-        // configure
-        c.set_read_timeout(Some(config.idle_timeout))?;
-        c.set_write_timeout(Some(config.write_timeout))?;
+    let acceptor_threads = config.acceptor_threads;
+    let server = Arc::new(Server {
+        services,
+        config,
+        concurrent,
+        queue: Mutex::new(VecDeque::new()),
+        slot_freed: Condvar::new(),
+        refuser,
+        lingerer,
+        admission,
+        tls,
+        registry,
+        #[cfg(target_os = "linux")]
+        pool,
+        #[cfg(target_os = "linux")]
+        reactor,
+    });
 
-        // new thread for each connection
-        let concurrent = concurrent.clone();
-        let services = services.clone();
+    if server.config.connection_queue_timeout.is_some() {
+        let server = server.clone();
         thread::Builder::new()
-            .name(String::from("pajamax-w"))
+            .name(String::from("pajamax-q"))
+            .spawn(move || queue_routine(server))?;
+    }
+
+    let listeners: Vec<Arc<L>> = listeners.into_iter().map(Arc::new).collect();
+    for i in 1..acceptor_threads {
+        let server = server.clone();
+        let listener = listeners[i % listeners.len()].clone();
+        thread::Builder::new()
+            .name(String::from("pajamax-a"))
             .spawn(move || {
-                match handle(services, c, config) {
-                    Ok(_) => info!("connection closed"),
-                    Err(err) => error!("connection fail: {:?}", err),
+                if let Err(err) = accept_routine(&server, &*listener) {
+                    error!("acceptor fail: {:?}", err);
                 }
-                concurrent.fetch_sub(1, Ordering::Relaxed);
-            })
-            .unwrap();
+            })?;
+    }
+    accept_routine(&server, &*listeners[0])
+}
+
+fn accept_routine<L: Listener>(server: &Arc<Server>, listener: &L) -> std::io::Result<()> {
+    loop {
+        let (c, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // e.g. ECONNABORTED, or EMFILE which may last for a while
+                error!("accept fail: {:?}", err);
+                thread::sleep(ACCEPT_ERROR_DELAY);
+                continue;
+            }
+        };
+
+        // concurrent limit
+        let Some(timeout) = server.config.connection_queue_timeout else {
+            if server.try_acquire_slot() {
+                open_connection(server, c, peer);
+            } else {
+                refuse_connection(server, c, peer);
+            }
+            continue;
+        };
+
+        // Queue it if no slot, or if others are queued already, so
+        // they are started in order.
+        let mut queue = server.queue.lock().unwrap();
+        if queue.is_empty() && server.try_acquire_slot() {
+            drop(queue);
+            open_connection(server, c, peer);
+        } else if queue.len() < MAX_QUEUED_CONNECTIONS {
+            trace!("queue new connection from {}", peer);
+            queue.push_back(Queued {
+                deadline: Instant::now() + timeout,
+                handle: Box::new(move |server, admitted| {
+                    if admitted {
+                        open_connection(server, c, peer);
+                    } else {
+                        refuse_connection(server, c, peer);
+                    }
+                }),
+            });
+            server.slot_freed.notify_one();
+        } else {
+            drop(queue);
+            refuse_connection(server, c, peer);
+        }
+    }
+}
+
+// Start the connection which has got a slot.
+fn open_connection<S: Connection>(server: &Arc<Server>, c: S, peer: SocketAddr) {
+    info!("new connection from {}", peer);
+
+    // Failure of one connection should not stop accepting.
+    let stats = server.registry.register(peer);
+    if let Err(err) = start_connection(server, c, stats.clone()) {
+        server.close(Err(err.into()), &stats);
+    }
+}
+
+// Refuse the excess connection, at once if plain, or on the refuser
+// threads if TLS.
+fn refuse_connection<S: Connection>(server: &Arc<Server>, c: S, peer: SocketAddr) {
+    error!("refuse new connection from {} for limit", peer);
+    metrics::connection_refused();
+
+    let Some(tls) = &server.tls else {
+        if let Err(err) = refuse(server, c, None) {
+            trace!("refuse connection fail: {:?}", err);
+        }
+        return;
+    };
+
+    let tls = tls.clone();
+    let server2 = server.clone();
+    let refusal = Box::new(move || {
+        if let Err(err) = refuse(&server2, c, Some(&tls)) {
+            trace!("refuse connection fail: {:?}", err);
+        }
+    });
+    if server.refuser.try_send(refusal).is_err() {
+        error!("drop new connection for too many refusals");
+    }
+}
+
+// Configure the accepted connection, and hand it over to the worker
+// pool, the reactor, or a new thread. `server.close()` is called when
+// it's closed, but not if this fails.
+fn start_connection<S: Connection>(
+    server: &Arc<Server>,
+    c: S,
+    stats: Arc<ConnStats>,
+) -> std::io::Result<()> {
+    let config = &server.config;
+    c.set_read_timeout(Some(config.idle_timeout))?;
+    c.set_write_timeout(Some(config.write_timeout))?;
+
+    // hand over to the worker pool
+    #[cfg(target_os = "linux")]
+    if let Some(pool) = &server.pool {
+        let output: Output = Box::new(c.try_clone()?);
+        let state = ConnState::new(
+            server.services.clone(),
+            output,
+            None,
+            server.config.clone(),
+            server.admission.clone(),
+            stats.clone(),
+        );
+        let fd = c.as_raw_fd();
+        let server = server.clone();
+        let on_close = Box::new(move |result| server.close(result, &stats));
+        return pool.add(c, fd, state, on_close);
+    }
+
+    // hand over to the reactor
+    #[cfg(target_os = "linux")]
+    if let Some(reactor) = &server.reactor {
+        let (output, buffer) = Reactor::new_output();
+        let state = ConnState::new(
+            server.services.clone(),
+            output,
+            None,
+            server.config.clone(),
+            server.admission.clone(),
+            stats.clone(),
+        );
+        let fd = c.as_raw_fd();
+        let server = server.clone();
+        let on_close = Box::new(move |result| server.close(result, &stats));
+        return reactor.add(c, fd, state, buffer, on_close);
+    }
+
+    // new thread for each connection
+    let server = server.clone();
+    thread::Builder::new()
+        .name(String::from("pajamax-w"))
+        .spawn(move || {
+            let result = split(c, server.tls.as_deref()).and_then(|(input, output, identity)| {
+                handle(
+                    server.services.clone(),
+                    input,
+                    output,
+                    identity,
+                    server.config.clone(),
+                    server.admission.clone(),
+                    stats.clone(),
+                )
+            });
+            server.close(result, &stats);
+        })?;
+    Ok(())
+}
+
+// Sleep after accept error, to avoid busy loop.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(10);
+
+fn unsupported(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{what} is not supported"),
+    )
+}
+
+#[cfg(feature = "tls")]
+type TlsAcceptor = crate::tls::TlsAcceptor;
+#[cfg(not(feature = "tls"))]
+type TlsAcceptor = ();
+
+fn new_tls_acceptor(tls_config: &TlsConfig) -> std::io::Result<TlsAcceptor> {
+    #[cfg(feature = "tls")]
+    return TlsAcceptor::new(tls_config);
+
+    #[cfg(not(feature = "tls"))]
+    {
+        let _ = tls_config;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "TLS is configured but the `tls` feature is not enabled",
+        ))
+    }
+}
+
+// Split the connection into input and output ends, after TLS
+// handshake if enabled.
+#[allow(clippy::type_complexity)]
+fn split<S: Connection>(
+    c: S,
+    tls: Option<&TlsAcceptor>,
+) -> Result<(Box<dyn Read + Send>, Output, Option<Arc<PeerIdentity>>), Error> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        let (input, output, identity) = tls.accept(c)?;
+        return Ok((Box::new(input), Box::new(output), identity));
+    }
+
+    #[cfg(not(feature = "tls"))]
+    let _ = tls;
+
+    let c2 = c.try_clone()?;
+    Ok((Box::new(c), Box::new(c2), None))
+}
+
+// Output end of the connection thread.
+enum ConnEnd {
+    // written by itself, if no dispatch-mode service
+    Direct(Box<ResponseEnd>),
+
+    // forwarded to the backend response thread, which owns the output
+    // end, so the frames are written by one thread in order
+    Forward(Arc<dyn ConnResponseTx>),
+}
+
+impl ConnEnd {
+    fn build<Reply>(
+        &mut self,
+        stream_id: u32,
+        response: Response<Reply>,
+        req_data_len: usize,
+    ) -> Result<(), Error>
+    where
+        Reply: prost::Message + 'static,
+    {
+        match self {
+            ConnEnd::Direct(resp_end) => {
+                context::with(|ctx| Ok(resp_end.build(stream_id, response, req_data_len, ctx)?))
+            }
+            ConnEnd::Forward(resp_tx) => resp_tx.send(ConnOutput::Local {
+                stream_id,
+                req_data_len,
+                response: response.map(|reply| Box::new(LocalReply(reply)) as Box<dyn ReplyEncode>),
+                context: context::try_take(),
+            }),
+        }
+    }
+
+    fn build_frames(&mut self, frames: Vec<u8>) -> Result<(), Error> {
+        match self {
+            ConnEnd::Direct(resp_end) => {
+                resp_end.build_frames(&frames);
+                Ok(())
+            }
+            ConnEnd::Forward(resp_tx) => resp_tx.send(ConnOutput::Frames(frames)),
+        }
+    }
+
+    // The response thread flushes by itself in the forward case.
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            ConnEnd::Direct(resp_end) => Ok(resp_end.flush()?),
+            ConnEnd::Forward(_) => Ok(()),
+        }
     }
-    unreachable!();
 }
 
 thread_local! {
-    static RESPONSE_END: RefCell<ResponseEnd> = panic!();
+    // Set during `ConnState::process()`.
+    static RESPONSE_END: RefCell<Option<ConnEnd>> = const { RefCell::new(None) };
+}
+
+fn with_conn_end<R>(f: impl FnOnce(&mut ConnEnd) -> R) -> R {
+    RESPONSE_END.with_borrow_mut(|conn_end| f(conn_end.as_mut().expect("no response end")))
 }
 
 struct Stream {
     id: u32,
     isvc: usize, // index of services
     req_disc: usize,
+
+    context: RequestContext,
+
+    // rejected before handling, e.g. authentication fails
+    reject: Option<Status>,
 }
 
 // response in local thread
@@ -75,59 +613,184 @@ pub fn local_build_response<Reply>(
     req_data_len: usize,
 ) -> Result<(), Error>
 where
-    Reply: prost::Message,
+    Reply: prost::Message + 'static,
 {
-    RESPONSE_END
-        .with_borrow_mut(|resp_end| Ok(resp_end.build(stream_id, response, req_data_len)?))
+    with_conn_end(|conn_end| conn_end.build(stream_id, response, req_data_len))
 }
 
 // handle each connection on a new thread
 pub fn handle(
     services: Vec<Arc<dyn PajamaxService + Send + Sync + 'static>>,
-    mut c: TcpStream,
+    mut input_end: Box<dyn Read + Send>,
+    output_end: Output,
+    peer_identity: Option<Arc<PeerIdentity>>,
     config: Config,
+    admission: Arc<Admission>,
+    stats: Arc<ConnStats>,
 ) -> Result<(), Error> {
-    handshake(&mut c, &config)?;
-    trace!("handshake done");
+    let mut conn = ConnState::new(
+        services,
+        output_end,
+        peer_identity,
+        config,
+        admission,
+        stats,
+    );
+
+    // read and parse input data
+    while let Ok(len) = input_end.read(conn.input_buf()) {
+        trace!("receive data {len}");
+        if len == 0 {
+            // connection closed
+            return Ok(());
+        }
+        conn.process(len)?;
+    }
+    Ok(())
+}
+
+// State of a connection. Read input data into `input_buf()`, and
+// then call `process()` to handle the requests.
+//
+// This is driven by the connection thread in thread-per-connection
+// model, or by the event loop in other models.
+pub(crate) struct ConnState {
+    services: Vec<Arc<dyn PajamaxService + Send + Sync + 'static>>,
+    config: Config,
+    stats: Arc<ConnStats>,
+    peer_identity: Option<Arc<PeerIdentity>>,
 
-    // prepare some contexts
+    handshaken: bool,
 
     // network input buffer
-    let mut input = Vec::new();
-    input.resize(config.max_frame_size, 0);
+    input: Vec<u8>,
+    last_end: usize,
 
     // stream info in HEADER frame
-    let mut streams = VecDeque::new();
+    streams: VecDeque<Stream>,
 
-    let mut hpack_decoder: Decoder = Decoder::new();
+    hpack_decoder: Decoder,
 
-    let mut route_cache = Vec::new();
+    route_cache: Vec<(usize, usize, Arc<Method>)>,
 
-    // split into 2 ends.
-    // Read requests from `c` and write response into `c2`.
-    // Wrap `Arc` for backend-response thread in dispatch-mode.
-    let c2 = Arc::new(Mutex::new(c.try_clone()?));
+    auth_cache: Option<AuthCache>,
 
-    // create backend response thread if any dispatch-mode service
-    if services.iter().any(|svc| svc.is_dispatch_mode()) {
-        dispatch::new_response_routine(c2.clone(), &config);
+    // buffer for decompressed request
+    decompressed: Vec<u8>,
+
+    admission: Option<ConnAdmission>,
+
+    // writes all output if no dispatch-mode service, or forwards it to
+    // the backend response thread otherwise.
+    // Moved into `RESPONSE_END` during `process()`.
+    conn_end: Option<ConnEnd>,
+
+    // to the backend response thread, if any dispatch-mode service
+    resp_tx: Option<Arc<dyn ConnResponseTx>>,
+}
+
+impl ConnState {
+    pub(crate) fn new(
+        services: Vec<Arc<dyn PajamaxService + Send + Sync + 'static>>,
+        output_end: Output,
+        peer_identity: Option<Arc<PeerIdentity>>,
+        config: Config,
+        admission: Arc<Admission>,
+        stats: Arc<ConnStats>,
+    ) -> Self {
+        let admission = admission
+            .is_enabled()
+            .then(|| ConnAdmission::new(admission));
+
+        // create backend response thread if any dispatch-mode service,
+        // which owns the output end then
+        let (conn_end, resp_tx) = if services.iter().any(|svc| svc.is_dispatch_mode()) {
+            let resp_tx = dispatch::new_response_routine(output_end, &config, stats.clone());
+            (ConnEnd::Forward(resp_tx.clone()), Some(resp_tx))
+        } else {
+            let resp_end = ResponseEnd::new(output_end, &config, stats.clone());
+            (ConnEnd::Direct(Box::new(resp_end)), None)
+        };
+
+        Self {
+            input: vec![0; config.max_frame_size],
+            last_end: 0,
+            handshaken: false,
+            streams: VecDeque::new(),
+            hpack_decoder: Decoder::new(config.metadata_capture()),
+            route_cache: Vec::new(),
+            auth_cache: config
+                .authenticator
+                .clone()
+                .map(|a| AuthCache::new(a, config.auth_cache_ttl)),
+            decompressed: Vec::new(),
+            admission,
+            conn_end: Some(conn_end),
+            resp_tx,
+            services,
+            config,
+            stats,
+            peer_identity,
+        }
     }
 
-    // in local-mode, this writes all responses;
-    // in dispatch-mode, this only writes dispatch-failure responses.
-    RESPONSE_END.set(ResponseEnd::new(c2, &config));
+    // Free space of the input buffer to read into.
+    pub(crate) fn input_buf(&mut self) -> &mut [u8] {
+        &mut self.input[self.last_end..]
+    }
 
-    // read and parse input data
-    let mut last_end = 0;
-    while let Ok(len) = c.read(&mut input[last_end..]) {
-        trace!("receive data {len}");
-        if len == 0 {
-            // connection closed
-            return Ok(());
+    // Process `len` bytes of new data read into `input_buf()`.
+    pub(crate) fn process(&mut self, len: usize) -> Result<(), Error> {
+        RESPONSE_END.set(self.conn_end.take());
+        if let Some(resp_tx) = &self.resp_tx {
+            dispatch::set_response_tx(resp_tx.clone(), &self.config);
         }
-        let end = last_end + len;
+
+        let result = self.process_frames(len);
+
+        self.conn_end = RESPONSE_END.take();
+        if self.resp_tx.is_some() {
+            dispatch::clear_response_tx();
+        }
+        result
+    }
+
+    fn process_frames(&mut self, len: usize) -> Result<(), Error> {
+        let Self {
+            services,
+            config,
+            stats,
+            peer_identity,
+            handshaken,
+            input,
+            last_end,
+            streams,
+            hpack_decoder,
+            route_cache,
+            auth_cache,
+            decompressed,
+            admission,
+            ..
+        } = self;
+
+        let end = *last_end + len;
+
+        stats.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
 
         let mut pos = 0;
+        if !*handshaken {
+            if end < PREFACE.len() {
+                *last_end = end;
+                return Ok(());
+            }
+            let mut frames = Vec::new();
+            handshake(&input[..PREFACE.len()], config, &mut frames)?;
+            with_conn_end(|conn_end| conn_end.build_frames(frames))?;
+            trace!("handshake done");
+            *handshaken = true;
+            pos = PREFACE.len();
+        }
+
         while let Some(frame) = Frame::parse(&input[pos..end]) {
             pos += Frame::HEAD_SIZE + frame.len; // for next loop
 
@@ -144,16 +807,19 @@ pub fn handle(
                 FrameKind::Headers => {
                     let headers_buf = frame.process_headers()?;
 
-                    let (isvc, req_disc) = match hpack_decoder.find_path(headers_buf)? {
+                    let mut metadata = Metadata::new();
+                    let path_kind = hpack_decoder.find_path(headers_buf, &mut metadata)?;
+
+                    let (isvc, req_disc, method) = match path_kind {
                         PathKind::Cached(cached) => {
                             trace!("route cache hit: {cached}");
-                            route_cache[cached]
+                            route_cache[cached].clone()
                         }
                         PathKind::Plain(path) => {
                             let len0 = route_cache.len();
                             for (i, svc) in services.iter().enumerate() {
                                 if let Some(req_disc) = svc.route(&path) {
-                                    route_cache.push((i, req_disc));
+                                    route_cache.push((i, req_disc, Method::get(&path)));
                                     break;
                                 }
                             }
@@ -166,14 +832,61 @@ pub fn handle(
                                 "route cache new ({len0}): {}",
                                 String::from_utf8_lossy(&path)
                             );
-                            route_cache[len0]
+                            route_cache[len0].clone()
+                        }
+                    };
+
+                    // admission control
+                    let admitted = match admission {
+                        Some(admission) => {
+                            let is_dispatch_mode = services[isvc].is_dispatch_mode();
+                            admission.admit(isvc, req_disc, is_dispatch_mode)
+                        }
+                        None => Ok(()),
+                    };
+
+                    // authentication
+                    let claims = admitted.and_then(|_| match auth_cache {
+                        Some(auth_cache) => auth_cache.authenticate(&metadata).map(Some),
+                        None => Ok(None),
+                    });
+
+                    // compression negotiation
+                    let negotiated = claims.and_then(|claims| {
+                        if config.codecs.is_empty() {
+                            return Ok((claims, Negotiated::default()));
                         }
+                        let negotiated = config.codecs.negotiate(
+                            &metadata,
+                            services[isvc].method_encoding(req_disc),
+                            config.compression_threshold,
+                        )?;
+                        Ok((claims, negotiated))
+                    });
+
+                    let mut context = RequestContext::new(method, stats.peer, metadata);
+                    context.peer_identity = peer_identity.clone();
+                    if config.tracing.is_some() {
+                        context.span = Some(SpanContext::from_metadata(
+                            &context.metadata,
+                            config.trace_sample_ratio,
+                        ));
+                    }
+                    let reject = match negotiated {
+                        Ok((claims, negotiated)) => {
+                            context.claims = claims;
+                            context.encoding = negotiated;
+                            None
+                        }
+                        Err(status) => Some(status),
                     };
 
                     streams.push_back(Stream {
                         id: frame.stream_id,
                         isvc,
                         req_disc,
+                        context,
+                        reject,
                     });
                 }
 
@@ -182,42 +895,144 @@ pub fn handle(
                     let req_buf = frame.process_data()?;
 
                     // unwrap grpc-level-protocal
-                    if req_buf.len() == 0 {
+                    if req_buf.is_empty() {
                         continue;
                     }
                     if req_buf.len() < 5 {
                         return Err(Error::InvalidHttp2("DATA frame too short for grpc"));
                     }
+                    let compressed = req_buf[0] != 0;
                     let req_buf = &req_buf[5..];
 
                     // check out request info
                     let Some(i) = streams.iter().position(|s| s.id == frame.stream_id) else {
                         return Err(Error::InvalidHttp2("DATA frame without HEADER"));
                     };
-                    let Stream { id, isvc, req_disc } = streams.remove(i).unwrap();
+                    let Stream {
+                        id,
+                        isvc,
+                        req_disc,
+                        context,
+                        reject,
+                    } = streams.remove(i).unwrap();
+
+                    let (req_buf, reject) = match reject {
+                        Some(status) => (req_buf, Some(status)),
+                        None => {
+                            match context
+                                .encoding
+                                .decompress(compressed, req_buf, decompressed)
+                            {
+                                Ok(req_buf) => (req_buf, None),
+                                Err(status) => (req_buf, Some(status)),
+                            }
+                        }
+                    };
+
+                    stats.requests.fetch_add(1, Ordering::Relaxed);
+
+                    let _ctx = context::enter(context);
+
+                    if let Some(status) = reject {
+                        trace!("reject request id:{id}, code:{:?}", status.code);
+                        let response: Response<()> = Err(status);
+                        local_build_response(id, response, frame.len)?;
+                        continue;
+                    }
 
                     trace!("handle isvc:{isvc}, req_disc:{req_disc}");
 
                     // handle request
-                    services[isvc].handle(req_disc, req_buf, id, frame.len as usize)?;
+                    let svc = &services[isvc];
+                    match admission {
+                        Some(admission)
+                            if admission.need_local_latency() && !svc.is_dispatch_mode() =>
+                        {
+                            let start = Instant::now();
+                            svc.handle(req_disc, req_buf, id, frame.len)?;
+                            admission.record_local_latency(start.elapsed());
+                        }
+                        _ => svc.handle(req_disc, req_buf, id, frame.len)?,
+                    }
+                }
+                FrameKind::Settings if !frame.flags.is_ack() => {
+                    let mut frames = Vec::new();
+                    build_settings_ack(&mut frames);
+                    with_conn_end(|conn_end| conn_end.build_frames(frames))?;
+                }
+                FrameKind::Ping if !frame.flags.is_ack() => {
+                    if frame.len != 8 {
+                        return Err(Error::InvalidHttp2("invalid PING frame"));
+                    }
+                    let mut frames = Vec::new();
+                    build_ping_ack(frame.payload, &mut frames);
+                    with_conn_end(|conn_end| conn_end.build_frames(frames))?;
                 }
                 _ => (),
             }
         }
 
-        RESPONSE_END.with_borrow_mut(|resp_end| resp_end.flush())?;
+        with_conn_end(|conn_end| conn_end.flush())?;
+
+        stats.streams.store(streams.len(), Ordering::Relaxed);
+
+        if let Some(max) = config.max_connection_panics {
+            if stats.panics.load(Ordering::Relaxed) >= max as u64 {
+                return Err(Error::TooManyPanics);
+            }
+        }
 
         // for next loop
-        if pos == 0 {
+        if pos == 0 && end == input.len() {
             return Err(Error::InvalidHttp2("too long frame"));
         }
         if pos < end {
             trace!("left data {}", end - pos);
             input.copy_within(pos..end, 0);
-            last_end = end - pos;
+            *last_end = end - pos;
         } else {
-            last_end = 0;
+            *last_end = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn refuse_many() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config::new().max_concurrent_connections(0);
+        thread::spawn(move || {
+            let services: Vec<Arc<dyn PajamaxService + Send + Sync>> = vec![Arc::new(EchoService)];
+            serve_with_config(services, config, vec![listener])
+        });
+
+        // more than MAX_PENDING_REFUSALS at once
+        let count = MAX_PENDING_REFUSALS * 3;
+        let clients: Vec<TcpStream> = (0..count)
+            .map(|_| {
+                let mut c = TcpStream::connect(addr).unwrap();
+                c.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                c.write_all(&client_preface()).unwrap();
+                c
+            })
+            .collect();
+
+        for mut c in clients {
+            let goaway = loop {
+                let (kind, payload) = read_frame(&mut c).expect("closed without GOAWAY");
+                if kind == GOAWAY {
+                    break payload;
+                }
+                assert_eq!(kind, SETTINGS);
+            };
+            assert_eq!(goaway[4..], ENHANCE_YOUR_CALM.to_be_bytes());
         }
     }
-    Ok(())
 }
@@ -0,0 +1,244 @@
+//! Context of the request being handled.
+//!
+//! Handlers do not take the context as argument. Instead, it is set
+//! in a thread-local variable before calling the handler, in both
+//! local-mode and dispatch-mode. So call [`with`] in handlers to
+//! access it.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! fn say_hello(&self, req: HelloRequest) -> Result<HelloReply, Status> {
+//!     let agent = pajamax::context::with(|ctx| {
+//!         ctx.and_then(|ctx| ctx.metadata().get_str("user-agent").map(String::from))
+//!     });
+//!     ...
+//! }
+//! ```
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::compression::Negotiated;
+use crate::metadata::Metadata;
+#[cfg(feature = "metrics")]
+use crate::metrics::MethodMetrics;
+use crate::tls::PeerIdentity;
+use crate::tracing::SpanContext;
+
+/// gRPC method. Shared by all requests of this method in all connections.
+pub(crate) struct Method {
+    pub(crate) path: Box<str>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: MethodMetrics,
+}
+
+// All methods that have been requested.
+pub(crate) static METHODS: Mutex<Vec<Arc<Method>>> = Mutex::new(Vec::new());
+
+impl Method {
+    // Called only once for each method in each connection, since
+    // the result is cached in connection's route-cache.
+    pub(crate) fn get(path: &[u8]) -> Arc<Method> {
+        let path = String::from_utf8_lossy(path);
+
+        let mut methods = METHODS.lock().unwrap();
+        if let Some(method) = methods.iter().find(|m| *m.path == *path) {
+            return method.clone();
+        }
+
+        let method = Arc::new(Method {
+            path: path.into(),
+            #[cfg(feature = "metrics")]
+            metrics: MethodMetrics::new(),
+        });
+        methods.push(method.clone());
+        method
+    }
+}
+
+/// Context of the request being handled.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub(crate) method: Arc<Method>,
+    pub(crate) peer: SocketAddr,
+    pub(crate) start: Instant,
+    handle_start: Instant,
+    handle_end: Option<Instant>, // set in dispatch-mode only
+    pub(crate) metadata: Metadata,
+    pub(crate) claims: Option<Arc<dyn Any + Send + Sync>>,
+    pub(crate) span: Option<SpanContext>,
+    pub(crate) encoding: Negotiated,
+    pub(crate) peer_identity: Option<Arc<PeerIdentity>>,
+    pub(crate) panicked: bool, // the handler panicked
+}
+
+impl RequestContext {
+    pub(crate) fn new(method: Arc<Method>, peer: SocketAddr, metadata: Metadata) -> Self {
+        let now = Instant::now();
+        Self {
+            method,
+            peer,
+            start: now,
+            handle_start: now,
+            handle_end: None,
+            metadata,
+            claims: None,
+            span: None,
+            encoding: Negotiated::default(),
+            peer_identity: None,
+            panicked: false,
+        }
+    }
+
+    // Handled without entering, e.g. answered later by `Responder`.
+    pub(crate) fn set_handle_start(&mut self) {
+        self.handle_start = Instant::now();
+    }
+    pub(crate) fn set_handle_end(&mut self) {
+        self.handle_end = Some(Instant::now());
+    }
+
+    /// Method path, e.g. `/helloworld.Greeter/SayHello`.
+    pub fn method(&self) -> &str {
+        &self.method.path
+    }
+
+    /// Address of the client. It's `0.0.0.0:0` for Unix domain sockets.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// When the request is received.
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    // Return (queue time, handler time) for access log. See
+    // `crate::access_log::AccessRecord`.
+    pub(crate) fn timing(&self) -> (Duration, Duration) {
+        let handle_end = self.handle_end.unwrap_or_else(Instant::now);
+        (
+            self.handle_start.duration_since(self.start),
+            handle_end.duration_since(self.handle_start),
+        )
+    }
+
+    /// Request metadata.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Claims returned by the authenticator.
+    ///
+    /// Return `None` if no authenticator is configured or the type
+    /// does not match. See [`crate::Config::authenticator`].
+    pub fn claims<T>(&self) -> Option<Arc<T>>
+    where
+        T: Any + Send + Sync,
+    {
+        self.claims.clone()?.downcast().ok()
+    }
+
+    /// Span context of this request.
+    ///
+    /// Return `None` if tracing is not configured. See
+    /// [`crate::Config::tracing`].
+    pub fn span(&self) -> Option<&SpanContext> {
+        self.span.as_ref()
+    }
+
+    /// Identity of the client by its TLS certificate.
+    ///
+    /// Return `None` if TLS is not configured or the client presents no
+    /// certificate. See [`crate::Config::tls`].
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_deref()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestContext>> = const { RefCell::new(None) };
+}
+
+/// Call `f` with the context of the request being handled.
+///
+/// The argument is `None` if not called in a handler.
+pub fn with<F, R>(f: F) -> R
+where
+    F: FnOnce(Option<&RequestContext>) -> R,
+{
+    CURRENT.with_borrow(|ctx| f(ctx.as_ref()))
+}
+
+/// Guard of the current context. Clear the context on drop.
+#[doc(hidden)]
+pub struct ContextGuard(());
+
+impl ContextGuard {
+    /// Take the context out, to be sent back with the response.
+    pub fn exit(self) -> RequestContext {
+        let mut ctx = CURRENT.take().expect("no request context");
+        ctx.handle_end = Some(Instant::now());
+        ctx
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+/// Set the current context before calling handlers.
+///
+/// Used by pajamax-build crate too.
+#[doc(hidden)]
+pub fn enter(mut ctx: RequestContext) -> ContextGuard {
+    ctx.handle_start = Instant::now();
+    CURRENT.set(Some(ctx));
+    ContextGuard(())
+}
+
+// Call `f` with the context entered, and put the context back even if
+// `f` panics, e.g. for each request in a batch.
+pub(crate) fn scope<R>(slot: &mut Option<RequestContext>, f: impl FnOnce() -> R) -> R {
+    struct Restore<'a>(&'a mut Option<RequestContext>);
+
+    impl Drop for Restore<'_> {
+        fn drop(&mut self) {
+            if let Some(mut ctx) = CURRENT.take() {
+                ctx.handle_end = Some(Instant::now());
+                *self.0 = Some(ctx);
+            }
+        }
+    }
+
+    let ctx = slot.take().expect("no request context");
+    let _ctx = enter(ctx);
+    let _restore = Restore(slot); // dropped before `_ctx`
+    f()
+}
+
+// Mark the current request as panicked in handler.
+pub(crate) fn set_panicked() {
+    CURRENT.with_borrow_mut(|ctx| {
+        if let Some(ctx) = ctx {
+            ctx.panicked = true;
+        }
+    });
+}
+
+// Take the current context out if any, e.g. to be forwarded to the
+// backend response thread with the local response.
+pub(crate) fn try_take() -> Option<RequestContext> {
+    CURRENT.take()
+}
+
+// Take the current context out, to be dispatched to backend threads.
+pub(crate) fn take() -> RequestContext {
+    CURRENT.take().expect("no request context")
+}
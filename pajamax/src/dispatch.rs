@@ -1,28 +1,174 @@
+use std::any::Any;
 use std::cell::RefCell;
-use std::net::TcpStream;
-use std::sync::{mpsc, Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::admin::ConnStats;
+use crate::admission::DepthGuard;
+use crate::catch_panic::catch_panic;
+use crate::channel::{
+    Backpressure, DispatchChannel, DispatchEvent, DispatchHook, Receiver, RecvError, SendError,
+    Sender, StdChannel, TryRecvError, TrySendError,
+};
 use crate::config::Config;
 use crate::connection::local_build_response;
+use crate::context::{self, RequestContext};
 use crate::error::Error;
 use crate::macros::*;
-use crate::response_end::ResponseEnd;
+use crate::metrics;
+use crate::notify::{Notifier, NotifyTx};
+use crate::response_end::{Output, ResponseEnd};
 use crate::status::{Code, Status};
+use crate::thread_pool;
 use crate::ReplyEncode;
 use crate::Response;
 
 /// Send end of request channel for dispatch mode.
-pub type RequestTx<Req> = mpsc::SyncSender<DispatchRequest<Req>>;
+///
+/// See [`crate::channel`] for the channel types.
+pub type RequestTx<Req, C = StdChannel> = <C as DispatchChannel>::Sender<DispatchRequest<Req>>;
 
 /// Receive end of request channel for dispatch mode.
-pub type RequestRx<Req> = mpsc::Receiver<DispatchRequest<Req>>;
+pub type RequestRx<Req, C = StdChannel> = <C as DispatchChannel>::Receiver<DispatchRequest<Req>>;
 
 /// Send end of response channel for dispatch mode.
-type ResponseTx = mpsc::SyncSender<DispatchResponse>;
+///
+/// The response is sent to the connection directly, or gathered with
+/// other partial responses of a scattered request first.
+#[derive(Clone)]
+pub struct ResponseTx {
+    kind: ResponseTxKind,
+
+    // Count the request in the dispatch depth, until all holders of
+    // its response end are dropped, e.g. the request or `Responder`.
+    _depth: Option<Arc<DepthGuard>>,
+}
+
+#[derive(Clone)]
+enum ResponseTxKind {
+    Conn(Arc<dyn ConnResponseTx>),
+    Gather(Arc<GatherPart>),
+}
+
+impl ResponseTx {
+    fn new(kind: ResponseTxKind) -> Self {
+        Self { kind, _depth: None }
+    }
+
+    // Response end to the connection directly, not counted in the
+    // dispatch depth.
+    #[cfg(test)]
+    pub(crate) fn to_conn(tx: Arc<dyn ConnResponseTx>) -> Self {
+        Self::new(ResponseTxKind::Conn(tx))
+    }
+
+    // Response end of a new request in the connection being processed,
+    // counted in the dispatch depth.
+    fn for_request() -> Self {
+        RESP_TX.with_borrow(|tx| Self {
+            kind: tx.as_ref().expect("no response channel").kind.clone(),
+            _depth: Some(Arc::new(DepthGuard::new())),
+        })
+    }
+
+    pub fn send(&self, resp: DispatchResponse) -> Result<(), Error> {
+        match &self.kind {
+            ResponseTxKind::Conn(tx) => tx.send(ConnOutput::Dispatched(resp)),
+            ResponseTxKind::Gather(part) => part.add(resp),
+        }
+    }
+
+    /// Send responses of the same channel in one chunk, see
+    /// [`Self::same_channel`].
+    pub fn send_batch(&self, resps: Vec<DispatchResponse>) -> Result<(), Error> {
+        match &self.kind {
+            ResponseTxKind::Conn(tx) => tx.send(ConnOutput::DispatchedBatch(resps)),
+            ResponseTxKind::Gather(part) => resps.into_iter().try_for_each(|r| part.add(r)),
+        }
+    }
+
+    /// If the two send to the same connection directly.
+    pub fn same_channel(&self, other: &ResponseTx) -> bool {
+        match (&self.kind, &other.kind) {
+            (ResponseTxKind::Conn(a), ResponseTxKind::Conn(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+// Output to the response thread, which owns the output end of the
+// connection in dispatch-mode. The connection thread forwards its
+// output here too, so all frames are written by one thread in order.
+pub(crate) enum ConnOutput {
+    // from the shard threads
+    Dispatched(DispatchResponse),
+    DispatchedBatch(Vec<DispatchResponse>),
+
+    // from the connection thread, e.g. by local-mode services, or
+    // failures before dispatching
+    Local {
+        stream_id: u32,
+        req_data_len: usize,
+        response: Response<Box<dyn ReplyEncode>>,
+        context: Option<RequestContext>,
+    },
+
+    // control frames from the connection thread, e.g. PING ACK
+    Frames(Vec<u8>),
+}
+
+// Reply of local-mode services, to be forwarded as `ConnOutput::Local`.
+pub(crate) struct LocalReply<Reply>(pub(crate) Reply);
+
+impl<Reply: prost::Message + 'static> ReplyEncode for LocalReply<Reply> {
+    fn encode(&self, output: &mut Vec<u8>) -> Result<(), prost::EncodeError> {
+        self.0.encode(output)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        Box::new(self.0)
+    }
+}
+
+// Response channel of the connection, in any `DispatchChannel` set by
+// `Config::dispatch_response_channel`. Object-safe wrappers of
+// `Sender` and `Receiver`, to avoid generic `Config` and connections.
+pub(crate) trait ConnResponseTx: Send + Sync {
+    fn send(&self, output: ConnOutput) -> Result<(), Error>;
+}
+
+impl<S: Sender<ConnOutput>> ConnResponseTx for S {
+    fn send(&self, output: ConnOutput) -> Result<(), Error> {
+        Sender::send(self, output).map_err(|_| Error::ChannelClosed)
+    }
+}
+
+pub(crate) trait ConnResponseRx: Send {
+    fn try_recv(&self) -> Result<ConnOutput, TryRecvError>;
+    fn recv(&self) -> Result<ConnOutput, RecvError>;
+}
+
+impl<R: Receiver<ConnOutput>> ConnResponseRx for R {
+    fn try_recv(&self) -> Result<ConnOutput, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+    fn recv(&self) -> Result<ConnOutput, RecvError> {
+        Receiver::recv(self)
+    }
+}
 
 /// Receive end of response channel for dispatch mode.
-type ResponseRx = mpsc::Receiver<DispatchResponse>;
+pub(crate) type ResponseRx = Box<dyn ConnResponseRx>;
+
+// Create the response channel, by `Config::dispatch_response_channel`.
+pub(crate) type NewResponseChannel = fn(usize) -> (Arc<dyn ConnResponseTx>, ResponseRx);
+
+pub(crate) fn new_response_channel<C: DispatchChannel>(
+    cap: usize,
+) -> (Arc<dyn ConnResponseTx>, ResponseRx) {
+    let (tx, rx) = C::bounded(cap);
+    (Arc::new(tx), Box::new(rx))
+}
 
 /// Dispatched request in dispatch mode.
 pub struct DispatchRequest<Req> {
@@ -30,6 +176,7 @@ pub struct DispatchRequest<Req> {
     pub req_data_len: usize,
     pub request: Req,
     pub resp_tx: ResponseTx,
+    pub context: RequestContext,
 }
 
 /// Dispatched response in dispatch mode.
@@ -40,89 +187,657 @@ pub struct DispatchResponse {
     // We use dynamic-dispatch `dyn` here to accept different
     // response from multiple services in one channel.
     pub response: Response<Box<dyn ReplyEncode>>,
+
+    // sent back from the shard thread
+    pub context: RequestContext,
+}
+
+// Backpressure policy and hook of the connection being processed.
+struct Policy {
+    backpressure: Backpressure,
+    hook: Option<DispatchHook>,
 }
 
 thread_local! {
-    static RESP_TX: RefCell<ResponseTx> = panic!();
+    static RESP_TX: RefCell<Option<ResponseTx>> = const { RefCell::new(None) };
+    static POLICY: RefCell<Policy> = const {
+        RefCell::new(Policy {
+            backpressure: Backpressure::FailFast,
+            hook: None,
+        })
+    };
 }
 
-// create a backend thread with response-channels
-pub fn new_response_routine(c: Arc<Mutex<TcpStream>>, config: &Config) {
-    let resp_end = ResponseEnd::new(c, config);
+// Create a backend thread with response-channels, which owns the
+// output end of the connection. Return the channel to it.
+pub(crate) fn new_response_routine(
+    c: Output,
+    config: &Config,
+    stats: Arc<ConnStats>,
+) -> Arc<dyn ConnResponseTx> {
+    let resp_end = ResponseEnd::new(c, config, stats);
 
-    let (resp_tx, resp_rx) = mpsc::sync_channel(config.max_concurrent_streams);
+    let (mut resp_tx, resp_rx) = (config.dispatch_response_channel)(config.max_concurrent_streams);
 
-    RESP_TX.set(resp_tx);
+    let wait = if config.dispatch_notify {
+        match Notifier::new() {
+            Ok(notifier) => {
+                let notifier = Arc::new(notifier);
+                resp_tx = Arc::new(NotifyTx::new(resp_tx, notifier.clone()));
+                ResponseWait::Notify(notifier)
+            }
+            Err(err) => {
+                error!(
+                    "fail to create dispatch notifier, blocking instead: {:?}",
+                    err
+                );
+                ResponseWait::Block
+            }
+        }
+    } else {
+        match config.dispatch_poll_interval {
+            Some(du) => ResponseWait::Poll(du),
+            None => ResponseWait::Block,
+        }
+    };
 
-    let poll_interval = config.dispatch_poll_interval;
     std::thread::Builder::new()
         .name(String::from("pajamax-r")) // response routine
-        .spawn(move || response_routine(resp_end, resp_rx, poll_interval))
+        .spawn(move || response_routine(resp_end, resp_rx, wait))
         .unwrap();
+
+    resp_tx
+}
+
+// Set the response channel and policy of the connection being processed.
+pub(crate) fn set_response_tx(resp_tx: Arc<dyn ConnResponseTx>, config: &Config) {
+    RESP_TX.set(Some(ResponseTx::new(ResponseTxKind::Conn(resp_tx))));
+    POLICY.set(Policy {
+        backpressure: config.dispatch_backpressure,
+        hook: config.dispatch_hook.clone(),
+    });
+}
+
+// Clear them after processing, so a thread serving many connections
+// does not keep the response thread of a closed one alive.
+pub(crate) fn clear_response_tx() {
+    RESP_TX.take();
+    POLICY.set(Policy {
+        backpressure: Backpressure::FailFast,
+        hook: None,
+    });
 }
 
 // dispatch the request to req_tx
-pub fn dispatch<Req>(
-    req_tx: &RequestTx<Req>,
+pub fn dispatch<Req, S>(
+    req_tx: &S,
     request: Req,
     stream_id: u32,
     req_data_len: usize,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    S: Sender<DispatchRequest<Req>>,
+{
     trace!("dispatch request id:{stream_id}");
 
     let disp_req = DispatchRequest {
         request,
         stream_id,
         req_data_len,
-        resp_tx: RESP_TX.with_borrow(|tx| tx.clone()),
+        resp_tx: ResponseTx::for_request(),
+        context: context::take(),
     };
 
-    match req_tx.try_send(disp_req) {
+    match send_request(req_tx, disp_req) {
         Ok(_) => Ok(()),
         Err(err) => {
             error!("dispatch fails (stream_id:{stream_id}): {:?}", err);
-            let status = match err {
-                mpsc::TrySendError::Full(_) => Status {
-                    code: Code::Unavailable,
-                    message: String::from("dispatch channel is full"),
-                },
-                mpsc::TrySendError::Disconnected(_) => Status {
-                    code: Code::Internal,
-                    message: String::from("dispatch channel is closed"),
-                },
-            };
+            let (status, disp_req) = dispatch_failure(err);
+
+            // put the context back for the response
+            let _ctx = context::enter(disp_req.context);
             let response: Response<()> = Err(status);
             local_build_response(stream_id, response, req_data_len)
         }
     }
 }
 
+// Send the request to the shard, waiting by the backpressure policy
+// if the channel is full. The error is the same as `try_send()`.
+#[allow(clippy::result_large_err)]
+fn send_request<Req, S>(
+    req_tx: &S,
+    disp_req: DispatchRequest<Req>,
+) -> Result<(), TrySendError<DispatchRequest<Req>>>
+where
+    S: Sender<DispatchRequest<Req>>,
+{
+    match req_tx.try_send(disp_req) {
+        Err(TrySendError::Full(disp_req)) => wait_request(req_tx, disp_req, block_deadline()),
+        result => result,
+    }
+}
+
+// Deadline of waiting for `Backpressure::Block`, from now.
+fn block_deadline() -> Instant {
+    match POLICY.with_borrow(|p| p.backpressure) {
+        Backpressure::Block(timeout) => Instant::now() + timeout,
+        _ => Instant::now(),
+    }
+}
+
+// Wait for the full channel by the backpressure policy, until the
+// deadline for `Backpressure::Block`.
+#[allow(clippy::result_large_err)]
+fn wait_request<Req, S>(
+    req_tx: &S,
+    disp_req: DispatchRequest<Req>,
+    deadline: Instant,
+) -> Result<(), TrySendError<DispatchRequest<Req>>>
+where
+    S: Sender<DispatchRequest<Req>>,
+{
+    let backpressure = POLICY.with_borrow(|p| p.backpressure);
+    if backpressure == Backpressure::FailFast {
+        return Err(TrySendError::Full(disp_req));
+    }
+
+    trace!(
+        "dispatch channel is full, wait (stream_id:{})",
+        disp_req.stream_id
+    );
+    metrics::dispatch_waiting();
+    report(DispatchEvent::Waiting, &disp_req.context);
+
+    match backpressure {
+        Backpressure::FailFast => unreachable!(),
+        Backpressure::Block(_) => send_timeout(req_tx, disp_req, deadline),
+        Backpressure::PauseReading => req_tx
+            .send(disp_req)
+            .map_err(|SendError(disp_req)| TrySendError::Disconnected(disp_req)),
+    }
+}
+
+// Retry sending until the deadline, spinning first and then sleeping
+// for longer and longer.
+fn send_timeout<T, S: Sender<T>>(
+    req_tx: &S,
+    mut t: T,
+    deadline: Instant,
+) -> Result<(), TrySendError<T>> {
+    let mut sleep = Duration::from_micros(10);
+    for i in 0.. {
+        match req_tx.try_send(t) {
+            Err(TrySendError::Full(back)) => t = back,
+            result => return result,
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        if i < 16 {
+            std::hint::spin_loop();
+        } else if i < 32 {
+            std::thread::yield_now();
+        } else {
+            std::thread::sleep(sleep.min(deadline - now));
+            sleep = (sleep * 2).min(Duration::from_millis(1));
+        }
+    }
+    Err(TrySendError::Full(t))
+}
+
+// Call the hook set by `Config::dispatch_hook`, if any.
+fn report(event: DispatchEvent, ctx: &RequestContext) {
+    POLICY.with_borrow(|p| {
+        if let Some(hook) = &p.hook {
+            hook.call(event, ctx);
+        }
+    });
+}
+
+// Status for the failure of sending request to the shard.
+fn dispatch_failure<Req>(
+    err: TrySendError<DispatchRequest<Req>>,
+) -> (Status, DispatchRequest<Req>) {
+    match err {
+        TrySendError::Full(disp_req) => {
+            metrics::dispatch_full();
+            report(DispatchEvent::Full, &disp_req.context);
+            let status = Status {
+                code: Code::Unavailable,
+                message: String::from("dispatch channel is full"),
+            };
+            (status, disp_req)
+        }
+        TrySendError::Disconnected(disp_req) => {
+            metrics::dispatch_closed();
+            report(DispatchEvent::Closed, &disp_req.context);
+            let status = Status {
+                code: Code::Internal,
+                message: String::from("dispatch channel is closed"),
+            };
+            (status, disp_req)
+        }
+    }
+}
+
+// Run the handler on the shared thread pool, for pool mode.
+//
+// The pool threads are shared by all connections, so the handler's
+// panic is always caught and answered with `INTERNAL` status.
+pub fn spawn<F>(stream_id: u32, req_data_len: usize, f: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Response<Box<dyn ReplyEncode>> + Send + 'static,
+{
+    trace!("spawn request id:{stream_id}");
+
+    if !thread_pool::reserve() {
+        error!("spawn fails (stream_id:{stream_id}): pool is full");
+        metrics::dispatch_full();
+        context::with(|ctx| {
+            if let Some(ctx) = ctx {
+                report(DispatchEvent::Full, ctx);
+            }
+        });
+        let response: Response<()> = Err(Status {
+            code: Code::Unavailable,
+            message: String::from("thread pool is full"),
+        });
+        return local_build_response(stream_id, response, req_data_len);
+    }
+
+    let mut context = Some(context::take());
+    let resp_tx = ResponseTx::for_request();
+
+    thread_pool::submit(Box::new(move || {
+        let response = context::scope(&mut context, || catch_panic(f));
+        let _ = resp_tx.send(DispatchResponse {
+            stream_id,
+            req_data_len,
+            response,
+            context: context.unwrap(),
+        });
+    }));
+    Ok(())
+}
+
+/// Shards to broadcast a request to, returned by `{Service}Dispatch::scatter_to()`.
+pub enum Scatter<'a, Req: Send, C: DispatchChannel = StdChannel> {
+    All(&'a [RequestTx<Req, C>]),
+    Subset(Vec<&'a RequestTx<Req, C>>),
+}
+
+/// Merge the partial replies of a scattered request into one.
+///
+/// Generated by pajamax-build crate, calling `{Service}Dispatch::reduce_{method}()`.
+pub type Reducer = fn(Vec<Box<dyn ReplyEncode>>) -> Response<Box<dyn ReplyEncode>>;
+
+// Gather the partial responses of a scattered request. The last one
+// merges them by the reducer, and sends the response to the connection.
+struct Gather {
+    stream_id: u32,
+    req_data_len: usize,
+    resp_tx: ResponseTx, // of the connection
+    reducer: Reducer,
+    state: Mutex<GatherState>,
+}
+
+struct GatherState {
+    pending: usize,
+    replies: Vec<Box<dyn ReplyEncode>>,
+    error: Option<Status>, // the first failed one
+    panicked: bool,
+    context: Option<RequestContext>, // of the latest part
+}
+
+impl Gather {
+    fn add(&self, response: Response<Box<dyn ReplyEncode>>, context: Option<RequestContext>) {
+        let mut state = self.state.lock().unwrap();
+        match response {
+            Ok(reply) => state.replies.push(reply),
+            Err(status) => {
+                state.error.get_or_insert(status);
+            }
+        }
+        if let Some(context) = context {
+            state.panicked |= context.panicked;
+            state.context = Some(context);
+        }
+
+        state.pending -= 1;
+        if state.pending > 0 {
+            return;
+        }
+
+        let response = match state.error.take() {
+            Some(status) => Err(status),
+            None => (self.reducer)(std::mem::take(&mut state.replies)),
+        };
+
+        // with the context of the last one, which ends the latest
+        let mut context = state.context.take().unwrap();
+        context.panicked = state.panicked;
+
+        // the connection may be closed
+        let _ = self.resp_tx.send(DispatchResponse {
+            stream_id: self.stream_id,
+            req_data_len: self.req_data_len,
+            response,
+            context,
+        });
+    }
+}
+
+// The response end of one shard's copy of a scattered request. If it's
+// dropped without response, e.g. the request is dropped by the shard,
+// an error is added for it, so the gather still finishes.
+struct GatherPart {
+    gather: Arc<Gather>,
+    answered: AtomicBool,
+}
+
+impl GatherPart {
+    fn add(&self, resp: DispatchResponse) -> Result<(), Error> {
+        if !self.answered.swap(true, Ordering::Relaxed) {
+            self.gather.add(resp.response, Some(resp.context));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for GatherPart {
+    fn drop(&mut self) {
+        if !*self.answered.get_mut() {
+            error!(
+                "scattered request dropped without reply (stream_id:{})",
+                self.gather.stream_id
+            );
+            let status = Status {
+                code: Code::Internal,
+                message: String::from("request dropped without reply"),
+            };
+            self.gather.add(Err(status), None);
+        }
+    }
+}
+
+// Broadcast the request to the shards, and the partial replies will be
+// merged by the reducer. Any partial failure fails the request.
+pub fn scatter<Req: Clone + Send, C: DispatchChannel>(
+    targets: Scatter<Req, C>,
+    request: Req,
+    stream_id: u32,
+    req_data_len: usize,
+    reducer: Reducer,
+) -> Result<(), Error> {
+    let req_txs: Vec<&RequestTx<Req, C>> = match targets {
+        Scatter::All(req_txs) => req_txs.iter().collect(),
+        Scatter::Subset(req_txs) => req_txs,
+    };
+
+    trace!("scatter request id:{stream_id} to {}", req_txs.len());
+
+    if req_txs.is_empty() {
+        let response: Response<()> = Err(Status {
+            code: Code::Internal,
+            message: String::from("no shard to scatter"),
+        });
+        return local_build_response(stream_id, response, req_data_len);
+    }
+
+    let context = context::take();
+    let gather = Arc::new(Gather {
+        stream_id,
+        req_data_len,
+        resp_tx: ResponseTx::for_request(), // one response for all
+        reducer,
+        state: Mutex::new(GatherState {
+            pending: req_txs.len(),
+            replies: Vec::with_capacity(req_txs.len()),
+            error: None,
+            panicked: false,
+            context: Some(context.clone()),
+        }),
+    });
+
+    // answer the part that fails to be sent, by its `GatherPart`
+    let scatter_failure = |err: TrySendError<DispatchRequest<Req>>| {
+        error!("scatter fails (stream_id:{stream_id}): {:?}", err);
+        let (status, disp_req) = dispatch_failure(err);
+        let _ = disp_req.resp_tx.send(DispatchResponse {
+            stream_id,
+            req_data_len,
+            response: Err(status),
+            context: disp_req.context,
+        });
+    };
+
+    let mut full = Vec::new();
+    for req_tx in req_txs {
+        let disp_req = DispatchRequest {
+            request: request.clone(),
+            stream_id,
+            req_data_len,
+            resp_tx: ResponseTx::new(ResponseTxKind::Gather(Arc::new(GatherPart {
+                gather: gather.clone(),
+                answered: AtomicBool::new(false),
+            }))),
+            context: context.clone(),
+        };
+
+        // try all shards first, so a full one does not delay the others
+        match req_tx.try_send(disp_req) {
+            Ok(()) => (),
+            Err(TrySendError::Full(disp_req)) => full.push((req_tx, disp_req)),
+            Err(err) => scatter_failure(err),
+        }
+    }
+
+    // then wait for the full ones, with one deadline for all
+    let deadline = block_deadline();
+    for (req_tx, disp_req) in full {
+        if let Err(err) = wait_request(req_tx, disp_req, deadline) {
+            scatter_failure(err);
+        }
+    }
+    Ok(())
+}
+
+// How the response thread waits for the response channel.
+enum ResponseWait {
+    Poll(Duration),
+    Block,
+    Notify(Arc<Notifier>),
+}
+
 // output thread
 fn response_routine(
     mut resp_end: ResponseEnd,
     resp_rx: ResponseRx,
-    poll_interval: Option<Duration>,
+    wait: ResponseWait,
 ) -> Result<(), Error> {
+    let mut spin_limit = 0;
     loop {
-        let resp = match resp_rx.try_recv() {
+        let output = match resp_rx.try_recv() {
             Ok(resp) => resp,
-            Err(mpsc::TryRecvError::Disconnected) => {
+            Err(TryRecvError::Disconnected) => {
+                resp_end.flush()?;
                 break Err(Error::ChannelClosed);
             }
-            Err(mpsc::TryRecvError::Empty) => {
+            Err(TryRecvError::Empty) => {
                 resp_end.flush()?;
 
-                match poll_interval {
-                    None => resp_rx.recv()?, // blocking mode
-                    Some(du) => {
-                        std::thread::sleep(du);
+                match &wait {
+                    ResponseWait::Poll(du) => {
+                        std::thread::sleep(*du);
                         continue;
                     }
+                    ResponseWait::Block => resp_rx.recv()?,
+                    ResponseWait::Notify(notifier) => notifier.recv(&*resp_rx, &mut spin_limit)?,
                 }
             }
         };
 
-        trace!("receive dispatched response {}", resp.stream_id);
-        resp_end.build_box(resp.stream_id, resp.response, resp.req_data_len)?;
+        match output {
+            ConnOutput::Dispatched(resp) => build_dispatched(&mut resp_end, resp)?,
+            ConnOutput::DispatchedBatch(resps) => {
+                trace!("receive dispatched batch {}", resps.len());
+                for resp in resps {
+                    build_dispatched(&mut resp_end, resp)?;
+                }
+            }
+            ConnOutput::Local {
+                stream_id,
+                req_data_len,
+                response,
+                context,
+            } => {
+                trace!("receive local response {stream_id}");
+                resp_end.build_box(stream_id, response, req_data_len, context.as_ref())?;
+            }
+            ConnOutput::Frames(frames) => resp_end.build_frames(&frames),
+        }
+    }
+}
+
+fn build_dispatched(resp_end: &mut ResponseEnd, resp: DispatchResponse) -> Result<(), Error> {
+    trace!("receive dispatched response {}", resp.stream_id);
+    resp_end.build_box(
+        resp.stream_id,
+        resp.response,
+        resp.req_data_len,
+        Some(&resp.context),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Method;
+    use crate::metadata::Metadata;
+
+    fn context() -> RequestContext {
+        let method = Method::get(b"/test.Svc/Scatter");
+        RequestContext::new(method, "127.0.0.1:1".parse().unwrap(), Metadata::new())
+    }
+
+    // count the replies, which must be `u32`, as the generated reducers
+    fn reducer(replies: Vec<Box<dyn ReplyEncode>>) -> Response<Box<dyn ReplyEncode>> {
+        let replies = replies
+            .into_iter()
+            .map(|r| r.into_any().downcast::<u32>().map(|r| *r))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Status {
+                code: Code::Internal,
+                message: String::from("mismatched reply"),
+            })?;
+        Ok(Box::new(LocalReply(replies.len() as u32)))
+    }
+
+    // The response ends of the parts, and the connection's response channel.
+    fn scatter(parts: usize) -> (Vec<ResponseTx>, ResponseRx) {
+        let (tx, rx) = new_response_channel::<StdChannel>(10);
+        let gather = Arc::new(Gather {
+            stream_id: 1,
+            req_data_len: 0,
+            resp_tx: ResponseTx::new(ResponseTxKind::Conn(tx)),
+            reducer,
+            state: Mutex::new(GatherState {
+                pending: parts,
+                replies: Vec::new(),
+                error: None,
+                panicked: false,
+                context: Some(context()),
+            }),
+        });
+        let parts = (0..parts)
+            .map(|_| {
+                ResponseTx::new(ResponseTxKind::Gather(Arc::new(GatherPart {
+                    gather: gather.clone(),
+                    answered: AtomicBool::new(false),
+                })))
+            })
+            .collect();
+        (parts, rx)
+    }
+
+    fn reply(part: &ResponseTx) {
+        part.send(DispatchResponse {
+            stream_id: 1,
+            req_data_len: 0,
+            response: Ok(Box::new(LocalReply(0_u32))),
+            context: context(),
+        })
+        .unwrap();
+    }
+
+    // Return the number of gathered replies, or the error code.
+    fn recv(rx: &ResponseRx) -> Option<Result<u32, Code>> {
+        match rx.try_recv() {
+            Ok(ConnOutput::Dispatched(resp)) => Some(match resp.response {
+                Ok(reply) => Ok(*reply.into_any().downcast::<u32>().unwrap()),
+                Err(status) => Err(status.code),
+            }),
+            Ok(_) => panic!("unexpected output"),
+            Err(_) => None,
+        }
+    }
+
+    #[test]
+    fn gather_all() {
+        let (parts, rx) = scatter(3);
+        reply(&parts[0]);
+        reply(&parts[1]);
+        assert_eq!(recv(&rx), None);
+        reply(&parts[2]);
+        assert_eq!(recv(&rx), Some(Ok(3)));
+
+        // answered parts add nothing more
+        drop(parts);
+        assert_eq!(recv(&rx), None);
+    }
+
+    #[test]
+    fn gather_dropped_part() {
+        let (mut parts, rx) = scatter(3);
+        reply(&parts[0]);
+
+        // the part is alive while any clone is
+        let clone = parts[2].clone();
+        drop(parts.pop());
+        assert_eq!(recv(&rx), None);
+        drop(clone);
+        assert_eq!(recv(&rx), None);
+
+        drop(parts.pop());
+        assert_eq!(recv(&rx), Some(Err(Code::Internal)));
+    }
+
+    #[test]
+    fn gather_mismatched_reply() {
+        let (parts, rx) = scatter(2);
+        reply(&parts[0]);
+        parts[1]
+            .send(DispatchResponse {
+                stream_id: 1,
+                req_data_len: 0,
+                response: Ok(Box::new(LocalReply(String::from("wrong")))),
+                context: context(),
+            })
+            .unwrap();
+        assert_eq!(recv(&rx), Some(Err(Code::Internal)));
+    }
+
+    #[test]
+    fn gather_answered_once() {
+        let (parts, rx) = scatter(2);
+        reply(&parts[0]);
+        reply(&parts[0]);
+        assert_eq!(recv(&rx), None);
+        reply(&parts[1]);
+        assert_eq!(recv(&rx), Some(Ok(2)));
     }
 }
@@ -8,6 +8,7 @@ pub enum Error {
     ChannelClosed,
     UnknownMethod(String),
     NoPathSet,
+    TooManyPanics,
 }
 
 impl From<std::io::Error> for Error {
@@ -40,6 +41,7 @@ impl fmt::Display for Error {
             Error::ChannelClosed => write!(f, "channel closed"),
             Error::UnknownMethod(m) => write!(f, "unknown method: {m}"),
             Error::NoPathSet => write!(f, "no :path set"),
+            Error::TooManyPanics => write!(f, "too many handler panics"),
         }
     }
 }
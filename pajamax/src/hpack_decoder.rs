@@ -1,7 +1,9 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::error::Error;
 use crate::huffman;
+use crate::metadata::Metadata;
 
 enum Representation {
     /// Indexed header field representation
@@ -126,18 +128,58 @@ pub enum PathKind {
     Plain(Vec<u8>),
 }
 
+// entry in dynamic table
+enum DynEntry {
+    // cache index of :path
+    Path(usize),
+    // captured header
+    Header(Arc<str>, Arc<[u8]>),
+    // pseudo-header, or any header if not capture
+    Ignored,
+}
+
+// header field in literal representations
+enum Field<'a> {
+    Path(OutStr<'a>),
+    Header(Arc<str>, OutStr<'a>),
+    Ignored,
+}
+
+/// Which headers other than `:path` are captured into metadata.
+pub enum Capture {
+    None,
+    All,
+    /// Only these headers, in lowercase.
+    Only(&'static [&'static str]),
+}
+
+impl Capture {
+    // Pseudo-headers are never captured.
+    fn wants(&self, name: &str) -> bool {
+        match self {
+            Capture::None => false,
+            Capture::All => !name.starts_with(':'),
+            Capture::Only(names) => !name.starts_with(':') && names.contains(&name),
+        }
+    }
+}
+
 pub struct Decoder {
+    capture: Capture,
     next_cache_index: usize,
-    dynamic_table: Vec<Option<usize>>,
+    dynamic_table: Vec<DynEntry>,
 
     huffman_paths: HashMap<Vec<u8>, usize>,
     plain_paths: HashMap<Vec<u8>, usize>,
 }
 
 impl Decoder {
-    /// Creates a new `Decoder` with all settings set to default values.
-    pub fn new() -> Self {
+    /// Creates a new `Decoder`.
+    ///
+    /// Headers other than `:path` are captured into metadata by `capture`.
+    pub fn new(capture: Capture) -> Self {
         Decoder {
+            capture,
             next_cache_index: 0,
             dynamic_table: Vec::new(),
             huffman_paths: HashMap::new(),
@@ -145,7 +187,11 @@ impl Decoder {
         }
     }
 
-    pub fn find_path(&mut self, mut buf: &[u8]) -> Result<PathKind, Error> {
+    pub fn find_path(
+        &mut self,
+        mut buf: &[u8],
+        metadata: &mut Metadata,
+    ) -> Result<PathKind, Error> {
         use self::Representation::*;
 
         let mut find_path = Err(Error::NoPathSet);
@@ -165,17 +211,26 @@ impl Decoder {
                         }
 
                         let index = 61 + table_len - index;
-                        if let Some(cached) = &self.dynamic_table[index] {
-                            find_path = Ok(PathKind::Cached(*cached));
+                        match &self.dynamic_table[index] {
+                            DynEntry::Path(cached) => find_path = Ok(PathKind::Cached(*cached)),
+                            DynEntry::Header(name, value) => {
+                                metadata.push(name.clone(), value.clone())
+                            }
+                            DynEntry::Ignored => (),
+                        }
+                    } else if index > 0 {
+                        let (name, value) = STATIC_TABLE[index - 1];
+                        if self.capture.wants(name) {
+                            metadata.push(name.into(), value.as_bytes().into());
                         }
                     }
                     adv
                 }
                 LiteralWithIndexing => {
-                    let (path, adv) = decode_literal_path(buf, true)?;
+                    let (field, adv) = self.decode_literal(buf, true)?;
 
-                    let opt_index = match path {
-                        Some(path) => {
+                    let entry = match field {
+                        Field::Path(path) => {
                             let path_buf = match path {
                                 OutStr::Plain(path) => path.to_vec(),
                                 OutStr::Huffman(huff_path) => {
@@ -188,47 +243,58 @@ impl Decoder {
 
                             // the caller level should update the index too
                             self.next_cache_index += 1;
-                            Some(self.next_cache_index - 1)
+                            DynEntry::Path(self.next_cache_index - 1)
+                        }
+                        Field::Header(name, value) => {
+                            let value: Arc<[u8]> = value.decode()?.into();
+                            metadata.push(name.clone(), value.clone());
+                            DynEntry::Header(name, value)
                         }
-                        None => None,
+                        Field::Ignored => DynEntry::Ignored,
                     };
-                    self.dynamic_table.push(opt_index);
+                    self.dynamic_table.push(entry);
 
                     adv
                 }
                 LiteralWithoutIndexing | LiteralNeverIndexed => {
-                    let (path, adv) = decode_literal_path(buf, false)?;
-
-                    if let Some(path) = path {
-                        find_path = Ok(match path {
-                            OutStr::Plain(path) => match self.plain_paths.get(path) {
-                                Some(cached) => PathKind::Cached(*cached),
-                                None => {
-                                    let cached = self.next_cache_index;
-                                    self.next_cache_index += 1;
-                                    self.plain_paths.insert(path.to_vec(), cached);
-
-                                    PathKind::Plain(path.to_vec())
-                                }
-                            },
-                            OutStr::Huffman(huff_path) => match self.huffman_paths.get(huff_path) {
-                                Some(cached) => PathKind::Cached(*cached),
-                                None => {
-                                    let cached = self.next_cache_index;
-                                    self.next_cache_index += 1;
-                                    self.huffman_paths.insert(huff_path.to_vec(), cached);
-
-                                    let mut plain = Vec::with_capacity(32);
-                                    huffman::decode(huff_path, &mut plain)?;
-                                    PathKind::Plain(plain)
+                    let (field, adv) = self.decode_literal(buf, false)?;
+
+                    match field {
+                        Field::Path(path) => {
+                            find_path = Ok(match path {
+                                OutStr::Plain(path) => match self.plain_paths.get(path) {
+                                    Some(cached) => PathKind::Cached(*cached),
+                                    None => {
+                                        let cached = self.next_cache_index;
+                                        self.next_cache_index += 1;
+                                        self.plain_paths.insert(path.to_vec(), cached);
+
+                                        PathKind::Plain(path.to_vec())
+                                    }
+                                },
+                                OutStr::Huffman(huff_path) => {
+                                    match self.huffman_paths.get(huff_path) {
+                                        Some(cached) => PathKind::Cached(*cached),
+                                        None => {
+                                            let cached = self.next_cache_index;
+                                            self.next_cache_index += 1;
+                                            self.huffman_paths.insert(huff_path.to_vec(), cached);
+
+                                            let mut plain = Vec::with_capacity(32);
+                                            huffman::decode(huff_path, &mut plain)?;
+                                            PathKind::Plain(plain)
+                                        }
+                                    }
                                 }
-                            },
-                        });
+                            });
+                        }
+                        Field::Header(name, value) => metadata.push(name, value.decode()?.into()),
+                        Field::Ignored => (),
                     }
                     adv
                 }
                 SizeUpdate => {
-                    let (_, adv) = decode_int(buf, 7)?;
+                    let (_, adv) = decode_int(buf, 5)?;
                     adv
                 }
             };
@@ -237,6 +303,79 @@ impl Decoder {
 
         find_path
     }
+
+    fn decode_literal<'a>(
+        &self,
+        mut buf: &'a [u8],
+        index: bool,
+    ) -> Result<(Field<'a>, usize), Error> {
+        let prefix = if index { 6 } else { 4 };
+
+        // Extract the table index for the name, or 0 if not indexed
+        let (table_idx, index_adv) = decode_int(buf, prefix)?;
+        buf = &buf[index_adv..];
+
+        // parse name
+        let (name, name_adv) = if table_idx == 0 {
+            let (name_str, name_adv) = decode_string(buf)?;
+            let name = if name_str.eq_str(":path") {
+                NameKind::Path
+            } else {
+                match &self.capture {
+                    Capture::None => NameKind::Ignored,
+                    Capture::All => {
+                        let name = name_str.decode()?;
+                        if name.starts_with(b":") {
+                            NameKind::Ignored
+                        } else {
+                            let name = String::from_utf8(name)
+                                .map_err(|_| Error::InvalidHpack("invalid header name"))?;
+                            NameKind::Header(name.into())
+                        }
+                    }
+                    Capture::Only(names) => match names.iter().find(|n| name_str.eq_str(n)) {
+                        Some(name) if self.capture.wants(name) => NameKind::Header((*name).into()),
+                        _ => NameKind::Ignored,
+                    },
+                }
+            };
+            (name, name_adv)
+        } else if table_idx <= 61 {
+            let name = match STATIC_TABLE[table_idx - 1].0 {
+                ":path" => NameKind::Path,
+                name if self.capture.wants(name) => NameKind::Header(name.into()),
+                _ => NameKind::Ignored,
+            };
+            (name, 0)
+        } else {
+            let table_len = self.dynamic_table.len();
+            if table_idx > 61 + table_len {
+                return Err(Error::InvalidHpack("invalid dynamic table index"));
+            }
+            let name = match &self.dynamic_table[61 + table_len - table_idx] {
+                DynEntry::Path(_) => NameKind::Path,
+                DynEntry::Header(name, _) => NameKind::Header(name.clone()),
+                DynEntry::Ignored => NameKind::Ignored,
+            };
+            (name, 0)
+        };
+
+        // parse value
+        let (value_str, value_adv) = decode_string(&buf[name_adv..])?;
+
+        let field = match name {
+            NameKind::Path => Field::Path(value_str),
+            NameKind::Header(name) => Field::Header(name, value_str),
+            NameKind::Ignored => Field::Ignored,
+        };
+        Ok((field, index_adv + name_adv + value_adv))
+    }
+}
+
+enum NameKind {
+    Path,
+    Header(Arc<str>),
+    Ignored,
 }
 
 enum OutStr<'a> {
@@ -258,39 +397,15 @@ impl<'a> OutStr<'a> {
             }
         }
     }
-}
-
-fn decode_literal_path<'a>(
-    mut buf: &'a [u8],
-    index: bool,
-) -> Result<(Option<OutStr<'a>>, usize), Error> {
-    let prefix = if index { 6 } else { 4 };
-
-    // Extract the table index for the name, or 0 if not indexed
-    let (table_idx, index_adv) = decode_int(buf, prefix)?;
-    buf = &buf[index_adv..];
-
-    if table_idx == 0 {
-        // parse name and value
-        let (name_str, name_adv) = decode_string(buf)?;
-        let (value_str, value_adv) = decode_string(&buf[name_adv..])?;
-
-        let adv = index_adv + name_adv + value_adv;
-
-        if name_str.eq_str(":path") {
-            Ok((Some(value_str), adv))
-        } else {
-            Ok((None, adv))
-        }
-    } else {
-        // name is indexed, so parse value only
-        let (value_str, value_adv) = decode_string(buf)?;
 
-        let adv = index_adv + value_adv;
-        if table_idx == 4 || table_idx == 5 {
-            Ok((Some(value_str), adv))
-        } else {
-            Ok((None, adv))
+    fn decode(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Plain(out) => Ok(out.to_vec()),
+            Self::Huffman(out) => {
+                let mut buf = Vec::with_capacity(out.len() * 8 / 5);
+                huffman::decode(out, &mut buf)?;
+                Ok(buf)
+            }
         }
     }
 }
@@ -328,7 +443,7 @@ fn decode_int(buf: &[u8], prefix_size: u8) -> Result<(usize, usize), Error> {
     const VARINT_MASK: u8 = 0b0111_1111;
     const VARINT_FLAG: u8 = 0b1000_0000;
 
-    if prefix_size < 1 || prefix_size > 8 {
+    if !(1..=8).contains(&prefix_size) {
         return Err(Error::InvalidHpack("invalid integer"));
     }
 
@@ -359,7 +474,7 @@ fn decode_int(buf: &[u8], prefix_size: u8) -> Result<(usize, usize), Error> {
     // bit to indicate if it is the last byte.
     let mut shift = 0;
 
-    while !buf.is_empty() {
+    while bytes < buf.len() {
         let b = buf[bytes];
 
         bytes += 1;
@@ -378,3 +493,250 @@ fn decode_int(buf: &[u8], prefix_size: u8) -> Result<(usize, usize), Error> {
 
     Err(Error::InvalidHpack("need more"))
 }
+
+// (name, value)
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn is_no_path(result: Result<PathKind, Error>) -> bool {
+        matches!(result, Err(Error::NoPathSet))
+    }
+
+    // RFC 7541 C.1
+    #[test]
+    fn integer() {
+        // C.1.1: 10 with 5-bit prefix
+        assert_eq!(decode_int(&[0b0000_1010], 5).unwrap(), (10, 1));
+        // C.1.2: 1337 with 5-bit prefix
+        assert_eq!(decode_int(&[0x1f, 0x9a, 0x0a], 5).unwrap(), (1337, 3));
+        // C.1.3: 42 starting at an octet boundary
+        assert_eq!(decode_int(&[0x2a], 8).unwrap(), (42, 1));
+    }
+
+    #[test]
+    fn integer_truncated() {
+        assert!(decode_int(&[0x1f], 5).is_err());
+        assert!(decode_int(&[0x1f, 0x9a], 5).is_err());
+        assert!(decode_int(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0x0f], 5).is_err());
+    }
+
+    // RFC 7541 C.2.2: literal without indexing, `:path: /sample/path`
+    #[test]
+    fn literal_path() {
+        let block = hex("040c 2f73 616d 706c 652f 7061 7468");
+        let mut decoder = Decoder::new(Capture::None);
+
+        match decoder.find_path(&block, &mut Metadata::new()).unwrap() {
+            PathKind::Plain(path) => assert_eq!(path, b"/sample/path"),
+            PathKind::Cached(_) => panic!("not cached yet"),
+        }
+        match decoder.find_path(&block, &mut Metadata::new()).unwrap() {
+            PathKind::Cached(i) => assert_eq!(i, 0),
+            PathKind::Plain(_) => panic!("should be cached"),
+        }
+    }
+
+    #[test]
+    fn huffman_path() {
+        let mut block = Vec::new();
+        huffman::encode(b"/sample/path", &mut block);
+        block.insert(0, 0x80 | block.len() as u8);
+        block.insert(0, 0x04);
+
+        match Decoder::new(Capture::None)
+            .find_path(&block, &mut Metadata::new())
+            .unwrap()
+        {
+            PathKind::Plain(path) => assert_eq!(path, b"/sample/path"),
+            PathKind::Cached(_) => panic!("not cached yet"),
+        }
+    }
+
+    // RFC 7541 C.3, requests without Huffman coding in one connection.
+    // The `:path` are indexed in static table, so not found, but the
+    // dynamic table references must be followed.
+    #[test]
+    fn requests_without_huffman() {
+        let mut decoder = Decoder::new(Capture::None);
+
+        let c31 = hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        assert!(is_no_path(decoder.find_path(&c31, &mut Metadata::new())));
+
+        let c32 = hex("8286 84be 5808 6e6f 2d63 6163 6865");
+        assert!(is_no_path(decoder.find_path(&c32, &mut Metadata::new())));
+
+        let c33 = hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65");
+        assert!(is_no_path(decoder.find_path(&c33, &mut Metadata::new())));
+
+        assert_eq!(decoder.dynamic_table.len(), 3);
+    }
+
+    // RFC 7541 C.4, the same requests with Huffman coding.
+    #[test]
+    fn requests_with_huffman() {
+        let mut decoder = Decoder::new(Capture::None);
+
+        let c41 = hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff");
+        assert!(is_no_path(decoder.find_path(&c41, &mut Metadata::new())));
+
+        let c42 = hex("8286 84be 5886 a8eb 1064 9cbf");
+        assert!(is_no_path(decoder.find_path(&c42, &mut Metadata::new())));
+
+        let c43 = hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf");
+        assert!(is_no_path(decoder.find_path(&c43, &mut Metadata::new())));
+
+        assert_eq!(decoder.dynamic_table.len(), 3);
+    }
+
+    // RFC 7541 C.3 and C.4, with headers captured.
+    #[test]
+    fn capture_headers() {
+        let mut decoder = Decoder::new(Capture::All);
+
+        let c31 = hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        let mut metadata = Metadata::new();
+        assert!(is_no_path(decoder.find_path(&c31, &mut metadata)));
+        assert!(metadata.is_empty());
+
+        let c32 = hex("8286 84be 5808 6e6f 2d63 6163 6865");
+        let mut metadata = Metadata::new();
+        assert!(is_no_path(decoder.find_path(&c32, &mut metadata)));
+        assert_eq!(metadata.get_str("cache-control"), Some("no-cache"));
+
+        // indexed `cache-control` in dynamic table
+        let mut metadata = Metadata::new();
+        assert!(is_no_path(decoder.find_path(&[0xbe], &mut metadata)));
+        assert_eq!(metadata.get_str("cache-control"), Some("no-cache"));
+
+        let mut decoder = Decoder::new(Capture::All);
+        let c41 = hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff");
+        let c42 = hex("8286 84be 5886 a8eb 1064 9cbf");
+        let c43 = hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf");
+        let mut metadata = Metadata::new();
+        assert!(is_no_path(decoder.find_path(&c41, &mut metadata)));
+        assert!(is_no_path(decoder.find_path(&c42, &mut metadata)));
+        assert!(is_no_path(decoder.find_path(&c43, &mut metadata)));
+        assert_eq!(metadata.get_str("cache-control"), Some("no-cache"));
+        assert_eq!(metadata.get_str("custom-key"), Some("custom-value"));
+    }
+
+    // RFC 7541 C.3 and C.4, with some headers captured.
+    #[test]
+    fn capture_some_headers() {
+        for (c1, c2, c3) in [
+            (
+                hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"),
+                hex("8286 84be 5808 6e6f 2d63 6163 6865"),
+                hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65"),
+            ),
+            (
+                hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+                hex("8286 84be 5886 a8eb 1064 9cbf"),
+                hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+            ),
+        ] {
+            let mut decoder = Decoder::new(Capture::Only(&["custom-key", ":authority"]));
+            let mut metadata = Metadata::new();
+            assert!(is_no_path(decoder.find_path(&c1, &mut metadata)));
+            assert!(is_no_path(decoder.find_path(&c2, &mut metadata)));
+            assert!(is_no_path(decoder.find_path(&c3, &mut metadata)));
+            assert_eq!(metadata.get_str("custom-key"), Some("custom-value"));
+            assert_eq!(metadata.get_str("cache-control"), None);
+            assert_eq!(metadata.get_str(":authority"), None);
+
+            // indexed `custom-key` in dynamic table
+            let mut metadata = Metadata::new();
+            assert!(is_no_path(decoder.find_path(&[0xbe], &mut metadata)));
+            assert_eq!(metadata.get_str("custom-key"), Some("custom-value"));
+            assert_eq!(decoder.dynamic_table.len(), 3);
+        }
+    }
+
+    // Dynamic table size update to 4096, in 5-bit prefix, before C.2.2.
+    #[test]
+    fn size_update() {
+        let mut block = hex("3fe1 1f");
+        block.extend(hex("040c 2f73 616d 706c 652f 7061 7468"));
+
+        match Decoder::new(Capture::None)
+            .find_path(&block, &mut Metadata::new())
+            .unwrap()
+        {
+            PathKind::Plain(path) => assert_eq!(path, b"/sample/path"),
+            PathKind::Cached(_) => panic!("not cached yet"),
+        }
+    }
+}
@@ -3,6 +3,8 @@ pub struct Encoder {
     dynamic_table_size: usize,
     rank_grpc_status_zero: Option<usize>,
     rank_content_type: Option<usize>,
+    rank_grpc_accept_encoding: Option<usize>,
+    rank_grpc_encoding: Vec<(&'static str, usize)>,
 }
 
 impl Encoder {
@@ -11,6 +13,8 @@ impl Encoder {
             dynamic_table_size: 0,
             rank_grpc_status_zero: None,
             rank_content_type: None,
+            rank_grpc_accept_encoding: None,
+            rank_grpc_encoding: Vec::new(),
         }
     }
 
@@ -38,8 +42,29 @@ impl Encoder {
         }
     }
 
+    pub fn encode_grpc_accept_encoding(&mut self, value: &str, dst: &mut Vec<u8>) {
+        match self.rank_grpc_accept_encoding {
+            Some(rank) => self.encode_dynamic_index(rank, dst),
+            None => {
+                self.encode_and_index_header("grpc-accept-encoding", value, dst);
+                self.rank_grpc_accept_encoding = Some(self.dynamic_table_size);
+            }
+        }
+    }
+
+    pub fn encode_grpc_encoding(&mut self, value: &'static str, dst: &mut Vec<u8>) {
+        match self.rank_grpc_encoding.iter().find(|(v, _)| *v == value) {
+            Some(&(_, rank)) => self.encode_dynamic_index(rank, dst),
+            None => {
+                self.encode_and_index_header("grpc-encoding", value, dst);
+                self.rank_grpc_encoding
+                    .push((value, self.dynamic_table_size));
+            }
+        }
+    }
+
     pub fn encode_grpc_status_nonzero(&mut self, code: usize, dst: &mut Vec<u8>) {
-        const CODES: [&'static str; 17] = [
+        const CODES: [&str; 17] = [
             "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15",
             "16",
         ];
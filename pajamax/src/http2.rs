@@ -1,6 +1,3 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
-
 use crate::config::*;
 use crate::error::Error;
 use crate::hpack_encoder::Encoder;
@@ -103,7 +100,7 @@ impl<'a> Frame<'a> {
 
     fn skip_padded<'b>(&self, buf: &'b [u8]) -> Result<&'b [u8], Error> {
         if self.flags.is_padded() {
-            if buf.len() < 1 {
+            if buf.is_empty() {
                 return Err(Error::InvalidHttp2("invalid padded"));
             }
             let pad_len = buf[0] as usize;
@@ -129,30 +126,32 @@ impl<'a> Frame<'a> {
     }
 }
 
-pub fn handshake(connection: &mut TcpStream, config: &Config) -> Result<(), Error> {
-    // parse the magic
-    let mut input = vec![0; 24];
-    let len = connection.read(&mut input)?;
-    if len != 24 {
-        return Err(Error::InvalidHttp2("too short handshake"));
-    }
-    if input != *b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n" {
+// Error codes, used in GOAWAY.
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Check the client preface, and build SETTINGS.
+pub fn handshake(input: &[u8], config: &Config, output: &mut Vec<u8>) -> Result<(), Error> {
+    if input != PREFACE {
         return Err(Error::InvalidHttp2("invalid handshake message"));
     }
 
-    // send SETTINGS
-    let mut output = Vec::new();
-    build_settings(3, config.max_concurrent_streams as u32, &mut output);
-    build_settings(5, config.max_frame_size as u32, &mut output);
-    connection.write_all(&output)?;
-
+    build_server_settings(config, output);
     Ok(())
 }
 
+// The SETTINGS frames sent at the start of connection.
+pub fn build_server_settings(config: &Config, output: &mut Vec<u8>) {
+    build_settings(3, config.max_concurrent_streams as u32, output);
+    build_settings(5, config.max_frame_size as u32, output);
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct HeadFlags(u8);
 impl HeadFlags {
     const END_STREAM: u8 = 0x1;
+    const ACK: u8 = 0x1; // for SETTINGS and PING
     const END_HEADERS: u8 = 0x4;
     const PADDED: u8 = 0x8;
     const PRIORITY: u8 = 0x20;
@@ -163,6 +162,9 @@ impl HeadFlags {
     fn is_end_stream(self) -> bool {
         self.0 & Self::END_STREAM != 0
     }
+    pub fn is_ack(self) -> bool {
+        self.0 & Self::ACK != 0
+    }
     fn is_end_headers(self) -> bool {
         self.0 & Self::END_HEADERS != 0
     }
@@ -174,9 +176,13 @@ impl HeadFlags {
     }
 }
 
+// The `reply_fn` should write the compressed message if `encoding` is set.
+// The `accept_encoding` is `None` if no codec is supported.
 pub fn build_response(
     stream_id: u32,
     reply_fn: impl FnOnce(&mut Vec<u8>),
+    encoding: Option<&'static str>,
+    accept_encoding: Option<&str>,
     hpack_encoder: &mut Encoder,
     output: &mut Vec<u8>,
 ) {
@@ -185,6 +191,12 @@ pub fn build_response(
     output.resize(start + Frame::HEAD_SIZE, 0);
     hpack_encoder.encode_status_200(output);
     hpack_encoder.encode_content_type(output);
+    if let Some(accept_encoding) = accept_encoding {
+        hpack_encoder.encode_grpc_accept_encoding(accept_encoding, output);
+    }
+    if let Some(encoding) = encoding {
+        hpack_encoder.encode_grpc_encoding(encoding, output);
+    }
 
     Frame::build_head(
         output.len() - start - Frame::HEAD_SIZE,
@@ -213,6 +225,7 @@ pub fn build_response(
         &mut output[data_start..],
     );
 
+    output[payload_start] = encoding.is_some() as u8; // compressed-flag
     build_u32(
         msg_len as u32,
         &mut output[payload_start + 1..payload_start + 5],
@@ -235,9 +248,11 @@ pub fn build_response(
     );
 }
 
+// The `accept_encoding` is `None` if no codec is supported.
 pub fn build_status(
     stream_id: u32,
     status: Status,
+    accept_encoding: Option<&str>,
     hpack_encoder: &mut Encoder,
     output: &mut Vec<u8>,
 ) {
@@ -253,6 +268,9 @@ pub fn build_status(
     hpack_encoder.encode_content_type(output);
     hpack_encoder.encode_grpc_status_nonzero(status.code as usize, output);
     hpack_encoder.encode_grpc_message(&status.message, output);
+    if let Some(accept_encoding) = accept_encoding {
+        hpack_encoder.encode_grpc_accept_encoding(accept_encoding, output);
+    }
 
     Frame::build_head(
         output.len() - start - Frame::HEAD_SIZE,
@@ -272,6 +290,47 @@ pub fn build_window_update(len: usize, output: &mut Vec<u8>) {
     build_u32(len as u32, &mut output[start + Frame::HEAD_SIZE..]);
 }
 
+// Tell the client to close the connection, after processing streams
+// up to `last_stream_id`.
+pub fn build_goaway(last_stream_id: u32, error_code: u32, output: &mut Vec<u8>) {
+    let start = output.len();
+    output.resize(start + Frame::HEAD_SIZE + 8, 0);
+
+    Frame::build_head(8, FrameKind::GoAway, 0, 0, &mut output[start..]);
+
+    let pos = start + Frame::HEAD_SIZE;
+    build_u32(last_stream_id, &mut output[pos..pos + 4]);
+    build_u32(error_code, &mut output[pos + 4..pos + 8]);
+}
+
+pub fn build_settings_ack(output: &mut Vec<u8>) {
+    let start = output.len();
+    output.resize(start + Frame::HEAD_SIZE, 0);
+
+    Frame::build_head(
+        0,
+        FrameKind::Settings,
+        HeadFlags::ACK,
+        0,
+        &mut output[start..],
+    );
+}
+
+// Answer PING with the same opaque data.
+pub fn build_ping_ack(payload: &[u8], output: &mut Vec<u8>) {
+    let start = output.len();
+    output.resize(start + Frame::HEAD_SIZE, 0);
+
+    Frame::build_head(
+        payload.len(),
+        FrameKind::Ping,
+        HeadFlags::ACK,
+        0,
+        &mut output[start..],
+    );
+    output.extend_from_slice(payload);
+}
+
 fn build_settings(ident: u16, value: u32, output: &mut Vec<u8>) {
     let start = output.len();
     output.resize(start + Frame::HEAD_SIZE + 6, 0);
@@ -299,4 +358,8 @@ fn build_u16(n: u16, buf: &mut [u8]) {
 // Used by `pajamax-build` crate.
 pub trait ReplyEncode: Send {
     fn encode(&self, output: &mut Vec<u8>) -> Result<(), prost::EncodeError>;
+
+    // For the reducer to get the reply message back, in scatter-gather.
+    // Return the message, not the wrapper of it.
+    fn into_any(self: Box<Self>) -> Box<dyn std::any::Any>;
 }
@@ -1,3 +1,4 @@
+#[allow(clippy::large_const_arrays)]
 mod table;
 
 use self::table::{DECODE_TABLE, ENCODE_TABLE};
@@ -29,6 +29,10 @@
 //! or any `tokio` components. Since the connections are very stable, there
 //! is even no need to use a thread pool.
 //!
+//! For deployments with many mostly idle connections, a fixed pool of
+//! worker threads multiplexing connections by epoll is also available, and
+//! a single-threaded reactor for extreme latency work. See [`ExecutionModel`].
+//!
 //! # Optimization: Deep into HTTP/2
 //!
 //! gRPC runs over HTTP/2. gRPC and HTTP/2 are independent layers, and they SHOULD
@@ -77,7 +81,7 @@
 //! Loss:
 //!
 //! - No gRPC Streaming mode, but only Unary mode;
-//! - No gRPC headers, such as `grpc-timeout`;
+//! - No gRPC headers by default, such as `grpc-timeout`, but see [`Config::capture_metadata`];
 //! - No `tower`'s ecosystem of middleware, services, and utilities, compared to `tonic`;
 //! - maybe something else.
 //!
@@ -141,6 +145,12 @@
 //! See the [dict-store](https://github.com/WuBingzheng/pajamax/blob/main/examples/src/dict_store.rs)
 //! example for more details.
 //!
+//! For stateless but CPU-heavy handlers, there is also *Pool* mode. The
+//! requests are decoded in the input thread as in *Dispatch* mode, and
+//! then handled in a shared work-stealing thread pool of Pajamax, so
+//! one slow request does not stall the other requests of the connection.
+//! The handlers are defined as in *Local* mode. See [`Config::pool_threads`].
+//!
 //! Each service has one mode, but you can mix services with different modes
 //! in one server.
 //!
@@ -161,13 +171,39 @@
 //! - More test;
 //! - Hooks like tower's Layer.
 
+mod admin;
+mod catch_panic;
 mod config;
 mod connection;
+#[cfg(target_os = "linux")]
+mod epoll;
 mod hpack_decoder;
 mod hpack_encoder;
 mod http2;
 mod huffman;
 mod macros;
+mod notify;
+#[cfg(target_os = "linux")]
+mod reactor;
+#[cfg(test)]
+mod testing;
+mod thread_pool;
+#[cfg(target_os = "linux")]
+mod worker_pool;
+
+pub mod access_log;
+pub mod admission;
+pub mod auth;
+pub mod channel;
+pub mod compression;
+pub mod context;
+pub mod listener;
+pub mod log_writer;
+pub mod metadata;
+pub mod metrics;
+pub mod shard;
+pub mod tls;
+pub mod tracing;
 
 #[doc(hidden)]
 pub mod dispatch;
@@ -177,8 +213,10 @@ pub mod error;
 pub mod response_end;
 
 pub mod status;
-pub use config::{Config, ConfigedServer};
+pub use config::{Config, ConfigedServer, ExecutionModel};
 
+#[doc(hidden)]
+pub use catch_panic::catch_panic;
 #[doc(hidden)]
 pub use connection::local_build_response;
 #[doc(hidden)]
@@ -214,4 +252,10 @@ pub trait PajamaxService {
 
     // Take `self` for object-safe.
     fn is_dispatch_mode(&self) -> bool;
+
+    // Response encoding of the method, set in pajamax-build.
+    // See `crate::compression` for details.
+    fn method_encoding(&self, _req_disc: usize) -> Option<&'static str> {
+        None
+    }
 }
@@ -0,0 +1,453 @@
+//! Listeners that the server accepts connections from.
+//!
+//! Besides [`crate::ConfigedServer::serve`] and
+//! [`crate::ConfigedServer::serve_unix`] which bind by themselves, the
+//! server can also serve on a listener created by you, by
+//! [`crate::ConfigedServer::serve_listener`], or on an inherited file
+//! descriptor, by [`crate::ConfigedServer::serve_from_fd`].
+//!
+//! # Socket activation
+//!
+//! [`listen_fds`] returns the listening sockets passed by systemd socket
+//! activation, or by the predecessor process in a binary upgrade.
+//!
+//! ```rust,ignore
+//! let server = pajamax::Config::new().add_service(GreeterServer::new(greeter));
+//! match pajamax::listener::listen_fds().pop() {
+//!     Some(fd) => server.serve_from_fd(fd),
+//!     None => server.serve(addr),
+//! }
+//! ```
+//!
+//! # Zero-downtime binary upgrade
+//!
+//! Keep a clone of the listener, and pass it to the new process by
+//! [`pass_to_child`] on upgrade, e.g. on a signal. The new process
+//! gets it by [`listen_fds`] as above, and starts accepting on the same
+//! socket, so no connection is refused during the upgrade. Then the old
+//! process can exit after its connections are finished.
+//!
+//! ```rust,ignore
+//! let listener = TcpListener::bind(addr)?;
+//! let listener2 = listener.try_clone()?;
+//! std::thread::spawn(move || {
+//!     wait_for_upgrade_signal();
+//!     let mut cmd = Command::new(std::env::current_exe().unwrap());
+//!     pajamax::listener::pass_to_child(&listener2, &mut cmd).spawn().unwrap();
+//! });
+//! server.serve_listener(listener)
+//! ```
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::process::Command;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(unix)]
+use crate::macros::*;
+
+/// Where connections are accepted from.
+///
+/// Implemented for `TcpListener` and `UnixListener`.
+pub trait Listener: Send + Sync + 'static {
+    type Connection: Connection;
+
+    /// Accept a new connection, with the peer address.
+    fn accept(&self) -> std::io::Result<(Self::Connection, SocketAddr)>;
+}
+
+/// Accepted connection.
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    /// Another handle, for the output end.
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()>;
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> std::io::Result<()>;
+
+    /// For epoll in the event-driven execution models.
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> RawFd;
+}
+
+impl Listener for TcpListener {
+    type Connection = TcpStream;
+
+    fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self)
+    }
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+    fn set_write_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_write_timeout(self, dur)
+    }
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> RawFd {
+        AsRawFd::as_raw_fd(self)
+    }
+}
+
+// Unix socket peers have no IP address, so the unspecified address
+// `0.0.0.0:0` is used in context, access log, tracing and admin.
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+
+    fn accept(&self) -> std::io::Result<(UnixStream, SocketAddr)> {
+        let (c, _) = UnixListener::accept(self)?;
+        Ok((c, SocketAddr::from(([0, 0, 0, 0], 0))))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, dur)
+    }
+    fn set_write_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_write_timeout(self, dur)
+    }
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> RawFd {
+        AsRawFd::as_raw_fd(self)
+    }
+}
+
+// Bind the Unix socket, and set the permissions if `mode` is set.
+//
+// A stale socket file left by a dead server is removed first. It's
+// stale if nobody accepts on it. Otherwise fail with `AddrInUse`.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("not a socket file: {}", path.display()),
+            ));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("socket in use: {}", path.display()),
+                ))
+            }
+            Err(_) => std::fs::remove_file(path)?,
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+// The first passed fd, by the `sd_listen_fds` protocol.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Return the listening sockets passed by systemd socket activation, or
+/// by [`pass_to_child`].
+///
+/// This follows the `sd_listen_fds` protocol: the number of fds is in
+/// the `LISTEN_FDS` environment variable, starting from fd 3, and
+/// `LISTEN_PID` must be this process if set. Fds that are not sockets
+/// are skipped, and a `LISTEN_FDS` larger than 256 is taken as invalid.
+///
+/// The fds are returned by the first call only, and later calls return
+/// none, so they are not owned twice. The environment is not modified,
+/// which is unsafe with other threads running, so `LISTEN_FDS` is still
+/// inherited by child processes. The fds are not, as they are set
+/// close-on-exec. Remove the variables by [`Command::env_remove`] when
+/// spawning children other than by [`pass_to_child`].
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<OwnedFd> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    take_listen_fds(
+        &TAKEN,
+        LISTEN_FDS_START,
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::env::var("LISTEN_PID").ok().as_deref(),
+    )
+}
+
+// Max number of passed fds. A larger `LISTEN_FDS` is taken as invalid,
+// e.g. stale, rather than owning and closing unrelated fds.
+#[cfg(unix)]
+const MAX_LISTEN_FDS: RawFd = 256;
+
+// The fds start from `start` for tests. Fds that are not sockets are
+// skipped, and left untouched.
+#[cfg(unix)]
+fn take_listen_fds(
+    taken: &AtomicBool,
+    start: RawFd,
+    count: Option<&str>,
+    pid: Option<&str>,
+) -> Vec<OwnedFd> {
+    if taken.swap(true, Ordering::Relaxed) {
+        return Vec::new();
+    }
+
+    let Some(count) = count.and_then(|n| n.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    if !(0..=MAX_LISTEN_FDS).contains(&count) {
+        error!("invalid LISTEN_FDS: {count}");
+        return Vec::new();
+    }
+    if matches!(pid, Some(pid) if pid.parse() != Ok(std::process::id())) {
+        return Vec::new();
+    }
+
+    (start..start + count)
+        .filter(|&fd| is_socket(fd))
+        .filter_map(|fd| unsafe {
+            // do not pass to children
+            (libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == 0)
+                .then(|| OwnedFd::from_raw_fd(fd))
+        })
+        .collect()
+}
+
+// Whether the fd is an open socket.
+#[cfg(unix)]
+fn is_socket(fd: RawFd) -> bool {
+    let mut ty: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    ret == 0
+}
+
+/// Configure the command to pass the listener to the child process,
+/// which gets it by [`listen_fds`].
+///
+/// The listener is still owned by this process, which may keep
+/// accepting on it until the child is ready.
+#[cfg(unix)]
+pub fn pass_to_child<'a>(listener: &impl AsRawFd, cmd: &'a mut Command) -> &'a mut Command {
+    use std::os::unix::process::CommandExt;
+
+    let fd = listener.as_raw_fd();
+    cmd.env("LISTEN_FDS", "1")
+        .env_remove("LISTEN_PID") // unknown until spawned
+        .env_remove("LISTEN_FDNAMES");
+
+    // Between fork and exec, so only async-signal-safe calls.
+    unsafe {
+        cmd.pre_exec(move || {
+            let ret = if fd == LISTEN_FDS_START {
+                libc::fcntl(fd, libc::F_SETFD, 0) // clear FD_CLOEXEC
+            } else {
+                libc::dup2(fd, LISTEN_FDS_START) // FD_CLOEXEC is not copied
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    }
+}
+
+// Bind `n` listeners on the same address with `SO_REUSEPORT`.
+#[cfg(unix)]
+pub(crate) fn bind_reuse_port<A: ToSocketAddrs>(
+    addr: A,
+    n: usize,
+) -> std::io::Result<Vec<TcpListener>> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_reuse_port_one(addr) {
+            Ok(first) => {
+                // the real port if `addr`'s is 0
+                let addr = first.local_addr()?;
+                let mut listeners = vec![first];
+                for _ in 1..n {
+                    listeners.push(bind_reuse_port_one(addr)?);
+                }
+                return Ok(listeners);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+#[cfg(unix)]
+fn bind_reuse_port_one(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = check(unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) }; // closed on error
+
+    let one: libc::c_int = 1;
+    for opt in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        check(unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                opt,
+                &one as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&one) as libc::socklen_t,
+            )
+        })?;
+    }
+
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_scope_id = a.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    check(unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    })?;
+    check(unsafe { libc::listen(fd.as_raw_fd(), 128) })?;
+
+    Ok(TcpListener::from(fd))
+}
+
+// TCP or Unix listener, by the socket family.
+#[cfg(unix)]
+pub(crate) enum FdListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[cfg(unix)]
+impl From<OwnedFd> for FdListener {
+    fn from(fd: OwnedFd) -> Self {
+        let tcp = TcpListener::from(fd);
+        if tcp.local_addr().is_ok() {
+            Self::Tcp(tcp)
+        } else {
+            Self::Unix(UnixListener::from(OwnedFd::from(tcp)))
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    // Put the fds at `start..`, as passed by the parent process.
+    fn pass_fds(start: RawFd, fds: Vec<OwnedFd>) {
+        for (i, fd) in fds.into_iter().enumerate() {
+            let fd = fd.into_raw_fd();
+            assert_eq!(
+                unsafe { libc::dup2(fd, start + i as RawFd) },
+                start + i as RawFd
+            );
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+    }
+
+    #[test]
+    fn take_fds() {
+        // a socket, a regular file, and a closed fd
+        const START: RawFd = 900;
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let file = std::fs::File::open("/dev/null").unwrap();
+        pass_fds(START, vec![socket.into(), file.into()]);
+        unsafe { libc::close(START + 2) };
+
+        let pid = std::process::id().to_string();
+
+        // mismatched pid
+        let taken = AtomicBool::new(false);
+        assert!(take_listen_fds(&taken, START, Some("3"), Some("1")).is_empty());
+
+        // invalid count
+        for count in ["-1", "100000", "x"] {
+            let taken = AtomicBool::new(false);
+            assert!(take_listen_fds(&taken, START, Some(count), None).is_empty());
+        }
+
+        // the socket only, and only once
+        let taken = AtomicBool::new(false);
+        let fds = take_listen_fds(&taken, START, Some("3"), Some(&pid));
+        assert_eq!(fds.len(), 1);
+        assert_eq!(fds[0].as_raw_fd(), START);
+        assert!(take_listen_fds(&taken, START, Some("3"), Some(&pid)).is_empty());
+
+        let listener = TcpListener::from(fds.into_iter().next().unwrap());
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        // the file is left untouched
+        assert!(is_open(START + 1));
+        assert_eq!(unsafe { libc::fcntl(START + 1, libc::F_GETFD) }, 0);
+        unsafe { libc::close(START + 1) };
+    }
+}
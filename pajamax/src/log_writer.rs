@@ -0,0 +1,89 @@
+//! Line writer on a background thread, so that the callers never block
+//! on IO. It's used by [`crate::access_log::FileSink`] and
+//! [`crate::tracing::JsonExporter`], and can be used by custom sinks too.
+
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+use crate::macros::*;
+
+/// Writer of lines on a background thread.
+///
+/// Lines are formatted on the calling thread, and then sent to the
+/// background thread which writes them with buffering. The buffer is
+/// flushed once the queue is drained, so lines are batched in busy time
+/// and not delayed in idle time. If the background thread falls behind
+/// by the queue size, new lines are dropped instead of blocking the
+/// caller, and counted in [`Self::dropped`].
+pub struct LogWriter {
+    tx: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl LogWriter {
+    /// Start the writer thread. At most `queue_size` lines are pending.
+    pub fn new<W>(w: W, queue_size: usize) -> std::io::Result<Self>
+    where
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(queue_size);
+        std::thread::Builder::new()
+            .name(String::from("pajamax-log"))
+            .spawn(move || writer_routine(w, rx))?;
+
+        Ok(Self {
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue the line, which should end with `'\n'`. Drop it if the
+    /// queue is full.
+    pub fn write_line(&self, line: String) {
+        match self.tx.try_send(line) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// Number of lines dropped because the queue is full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Flush once the queue is drained, so lines are batched in busy time
+// and not delayed in idle time.
+fn writer_routine<W: Write>(w: W, rx: Receiver<String>) {
+    let mut w = BufWriter::new(w);
+    while let Ok(line) = rx.recv() {
+        let mut result = w.write_all(line.as_bytes());
+        while let Ok(line) = rx.try_recv() {
+            if result.is_ok() {
+                result = w.write_all(line.as_bytes());
+            }
+        }
+        if let Err(err) = result.and_then(|_| w.flush()) {
+            error!("write log fail: {:?}", err);
+        }
+    }
+}
+
+// Write a JSON string with quotes.
+pub(crate) fn write_json_str(f: &mut impl fmt::Write, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for ch in s.chars() {
+        match ch {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            ch if ch.is_control() => write!(f, "\\u{:04x}", ch as u32)?,
+            ch => f.write_char(ch)?,
+        }
+    }
+    f.write_char('"')
+}
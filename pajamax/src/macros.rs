@@ -20,9 +20,10 @@ macro_rules! log {
     ($level: ident, $($t:tt)*) => {{
         #[cfg(feature = "log")]
         { log::$level!($($t)*) }
-        // Silence unused variables warnings.
+        // Silence unused variables warnings. The arguments are only
+        // borrowed by `format_args!`, as by `log`, but not moved.
         #[cfg(not(feature = "log"))]
-        { if false { let _ = ( $($t)* ); } }
+        { if false { let _ = format_args!($($t)*); } }
     }}
 }
 
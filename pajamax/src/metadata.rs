@@ -0,0 +1,64 @@
+//! Request metadata.
+
+use std::sync::Arc;
+
+/// Request headers, except the pseudo-headers such as `:path`.
+///
+/// Pajamax does not parse request headers by default for performance.
+/// Metadata is captured only if [`crate::Config::capture_metadata`] is
+/// set or some option that depends on it is configured, such as
+/// [`crate::Config::authenticator`]. Otherwise it's always empty.
+///
+/// Access it in handlers by [`crate::context::with`].
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    entries: Vec<(Arc<str>, Arc<[u8]>)>,
+}
+
+impl Metadata {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn push(&mut self, name: Arc<str>, value: Arc<[u8]>) {
+        self.entries.push((name, value));
+    }
+
+    /// Return the value of the first header with this name.
+    ///
+    /// The `name` should be in lowercase.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(n, _)| **n == *name)
+            .map(|(_, v)| &**v)
+    }
+
+    /// Return the value of the first header with this name as `&str`.
+    ///
+    /// Return `None` if not found or the value is not valid UTF-8.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// Return values of all headers with this name.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| **n == *name)
+            .map(|(_, v)| &**v)
+    }
+
+    /// Iterate all headers.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries.iter().map(|(n, v)| (&**n, &**v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
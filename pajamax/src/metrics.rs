@@ -0,0 +1,416 @@
+//! Metrics in Prometheus text format.
+//!
+//! Enable the `metrics` cargo feature to record metrics, and call
+//! [`render_prometheus`] to get the text output, which you can serve
+//! from your own admin port.
+//!
+//! Without the feature, recording is no-op.
+//!
+//! Metrics:
+//!
+//! - `pajamax_requests_total{method,code}`, requests by gRPC status code;
+//! - `pajamax_request_duration_seconds{method}`, histogram of latency, from
+//!   receiving the request to building the response;
+//! - `pajamax_request_bytes_total{method}` and `pajamax_response_bytes_total{method}`;
+//! - `pajamax_connections`, number of live connections;
+//! - `pajamax_connections_refused_total`, connections refused for the limit;
+//! - `pajamax_dispatch_depth`, requests dispatched but not responded yet;
+//! - `pajamax_dispatch_failures_total{reason}`, dispatch channel is full or closed;
+//! - `pajamax_dispatch_waits_total`, waits for the full dispatch channel, see
+//!   `Config::dispatch_backpressure`;
+//! - `pajamax_flush_batch_requests`, histogram of responses in each flush;
+//! - `pajamax_handler_panics_total{method}`, handler panics caught, see
+//!   `pajamax_build::Builder::catch_panics`.
+
+#[cfg(feature = "metrics")]
+use std::fmt::Write;
+use std::sync::atomic::AtomicUsize;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::sync::Mutex;
+
+use crate::context::RequestContext;
+use crate::status::Code;
+
+#[cfg(feature = "metrics")]
+struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>, // not cumulative
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        if let Some(i) = self.bounds.iter().position(|&b| value <= b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    // Values are multiplied by `scale` in output, e.g. nanoseconds to seconds.
+    fn render(&self, name: &str, labels: &str, scale: f64, out: &mut String) {
+        let (sep, braced) = if labels.is_empty() {
+            ("", String::new())
+        } else {
+            (",", format!("{{{labels}}}"))
+        };
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *bound as f64 * scale;
+            writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 * scale;
+        writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}").unwrap();
+        writeln!(out, "{name}_sum{braced} {sum}").unwrap();
+        writeln!(out, "{name}_count{braced} {count}").unwrap();
+    }
+}
+
+// in nanoseconds
+#[cfg(feature = "metrics")]
+const LATENCY_BOUNDS: &[u64] = &[
+    50_000,
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    2_500_000,
+    5_000_000,
+    10_000_000,
+    25_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    500_000_000,
+    1_000_000_000,
+    2_500_000_000,
+    5_000_000_000,
+    10_000_000_000,
+];
+
+#[cfg(feature = "metrics")]
+const FLUSH_BATCH_BOUNDS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500];
+
+// Metrics of each method.
+#[cfg(feature = "metrics")]
+pub(crate) struct MethodMetrics {
+    codes: [AtomicU64; 17],
+    latency: Histogram,
+    req_bytes: AtomicU64,
+    resp_bytes: AtomicU64,
+    panics: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl MethodMetrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            codes: std::array::from_fn(|_| AtomicU64::new(0)),
+            latency: Histogram::new(LATENCY_BOUNDS),
+            req_bytes: AtomicU64::new(0),
+            resp_bytes: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+struct Global {
+    connections_refused: AtomicU64,
+    dispatch_full: AtomicU64,
+    dispatch_closed: AtomicU64,
+    dispatch_waits: AtomicU64,
+    flush_batch: std::sync::LazyLock<Histogram>,
+}
+
+#[cfg(feature = "metrics")]
+static GLOBAL: Global = Global {
+    connections_refused: AtomicU64::new(0),
+    dispatch_full: AtomicU64::new(0),
+    dispatch_closed: AtomicU64::new(0),
+    dispatch_waits: AtomicU64::new(0),
+    flush_batch: std::sync::LazyLock::new(|| Histogram::new(FLUSH_BATCH_BOUNDS)),
+};
+
+// Counters of live connections of each server, which are maintained
+// by the servers for the concurrent limit.
+#[cfg(feature = "metrics")]
+static CONNECTIONS: Mutex<Vec<Arc<AtomicUsize>>> = Mutex::new(Vec::new());
+
+pub(crate) fn record_request(
+    ctx: &RequestContext,
+    code: Code,
+    req_bytes: usize,
+    resp_bytes: usize,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        let m = &ctx.method.metrics;
+        m.codes[code as usize].fetch_add(1, Ordering::Relaxed);
+        m.latency
+            .observe(ctx.start.elapsed().as_nanos().min(u64::MAX as u128) as u64);
+        m.req_bytes.fetch_add(req_bytes as u64, Ordering::Relaxed);
+        m.resp_bytes.fetch_add(resp_bytes as u64, Ordering::Relaxed);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (ctx, code, req_bytes, resp_bytes);
+}
+
+pub(crate) fn handler_panicked(ctx: &RequestContext) {
+    #[cfg(feature = "metrics")]
+    ctx.method.metrics.panics.fetch_add(1, Ordering::Relaxed);
+    #[cfg(not(feature = "metrics"))]
+    let _ = ctx;
+}
+
+pub(crate) fn register_connections(counter: &Arc<AtomicUsize>) {
+    #[cfg(feature = "metrics")]
+    CONNECTIONS.lock().unwrap().push(counter.clone());
+    #[cfg(not(feature = "metrics"))]
+    let _ = counter;
+}
+
+pub(crate) fn connection_refused() {
+    #[cfg(feature = "metrics")]
+    GLOBAL.connections_refused.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn dispatch_full() {
+    #[cfg(feature = "metrics")]
+    GLOBAL.dispatch_full.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn dispatch_closed() {
+    #[cfg(feature = "metrics")]
+    GLOBAL.dispatch_closed.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn dispatch_waiting() {
+    #[cfg(feature = "metrics")]
+    GLOBAL.dispatch_waits.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_flush(requests: usize) {
+    #[cfg(feature = "metrics")]
+    GLOBAL.flush_batch.observe(requests as u64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = requests;
+}
+
+// Escape label value, by the Prometheus text exposition format.
+#[cfg(feature = "metrics")]
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Render all metrics in Prometheus text format.
+#[cfg(feature = "metrics")]
+pub fn render_prometheus() -> String {
+    use crate::context::METHODS;
+
+    const CODE_NAMES: [&str; 17] = [
+        "OK",
+        "CANCELLED",
+        "UNKNOWN",
+        "INVALID_ARGUMENT",
+        "DEADLINE_EXCEEDED",
+        "NOT_FOUND",
+        "ALREADY_EXISTS",
+        "PERMISSION_DENIED",
+        "RESOURCE_EXHAUSTED",
+        "FAILED_PRECONDITION",
+        "ABORTED",
+        "OUT_OF_RANGE",
+        "UNIMPLEMENTED",
+        "INTERNAL",
+        "UNAVAILABLE",
+        "DATA_LOSS",
+        "UNAUTHENTICATED",
+    ];
+
+    let methods = METHODS.lock().unwrap().clone();
+    let mut out = String::new();
+
+    out.push_str("# TYPE pajamax_requests_total counter\n");
+    for m in methods.iter() {
+        let method = escape(&m.path);
+        for (code, count) in CODE_NAMES.iter().zip(m.metrics.codes.iter()) {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                writeln!(
+                    out,
+                    "pajamax_requests_total{{method=\"{method}\",code=\"{code}\"}} {count}"
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    out.push_str("# TYPE pajamax_request_duration_seconds histogram\n");
+    for m in methods.iter() {
+        let labels = format!("method=\"{}\"", escape(&m.path));
+        m.metrics
+            .latency
+            .render("pajamax_request_duration_seconds", &labels, 1e-9, &mut out);
+    }
+
+    out.push_str("# TYPE pajamax_request_bytes_total counter\n");
+    for m in methods.iter() {
+        let bytes = m.metrics.req_bytes.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "pajamax_request_bytes_total{{method=\"{}\"}} {bytes}",
+            escape(&m.path)
+        )
+        .unwrap();
+    }
+
+    out.push_str("# TYPE pajamax_response_bytes_total counter\n");
+    for m in methods.iter() {
+        let bytes = m.metrics.resp_bytes.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "pajamax_response_bytes_total{{method=\"{}\"}} {bytes}",
+            escape(&m.path)
+        )
+        .unwrap();
+    }
+
+    out.push_str("# TYPE pajamax_handler_panics_total counter\n");
+    for m in methods.iter() {
+        let panics = m.metrics.panics.load(Ordering::Relaxed);
+        if panics != 0 {
+            writeln!(
+                out,
+                "pajamax_handler_panics_total{{method=\"{}\"}} {panics}",
+                escape(&m.path)
+            )
+            .unwrap();
+        }
+    }
+
+    let connections: usize = CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed))
+        .sum();
+    out.push_str("# TYPE pajamax_connections gauge\n");
+    writeln!(out, "pajamax_connections {connections}").unwrap();
+
+    let refused = GLOBAL.connections_refused.load(Ordering::Relaxed);
+    out.push_str("# TYPE pajamax_connections_refused_total counter\n");
+    writeln!(out, "pajamax_connections_refused_total {refused}").unwrap();
+
+    let depth = crate::admission::DISPATCH_DEPTH.load(Ordering::Relaxed);
+    out.push_str("# TYPE pajamax_dispatch_depth gauge\n");
+    writeln!(out, "pajamax_dispatch_depth {depth}").unwrap();
+
+    let full = GLOBAL.dispatch_full.load(Ordering::Relaxed);
+    let closed = GLOBAL.dispatch_closed.load(Ordering::Relaxed);
+    out.push_str("# TYPE pajamax_dispatch_failures_total counter\n");
+    writeln!(
+        out,
+        "pajamax_dispatch_failures_total{{reason=\"full\"}} {full}"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "pajamax_dispatch_failures_total{{reason=\"closed\"}} {closed}"
+    )
+    .unwrap();
+
+    let waits = GLOBAL.dispatch_waits.load(Ordering::Relaxed);
+    out.push_str("# TYPE pajamax_dispatch_waits_total counter\n");
+    writeln!(out, "pajamax_dispatch_waits_total {waits}").unwrap();
+
+    out.push_str("# TYPE pajamax_flush_batch_requests histogram\n");
+    GLOBAL
+        .flush_batch
+        .render("pajamax_flush_batch_requests", "", 1.0, &mut out);
+
+    out
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::context::Method;
+    use crate::metadata::Metadata;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn render() {
+        let method = Method::get(b"/test.Metrics/Render\"");
+        let mut ctx = RequestContext::new(method, "127.0.0.1:1".parse().unwrap(), Metadata::new());
+
+        ctx.start = Instant::now() - Duration::from_millis(3);
+        record_request(&ctx, Code::Ok, 10, 20);
+        ctx.start = Instant::now() - Duration::from_millis(30);
+        record_request(&ctx, Code::NotFound, 5, 0);
+
+        let counter = Arc::new(AtomicUsize::new(2));
+        register_connections(&counter);
+
+        let out = render_prometheus();
+        let lines: Vec<&str> = out.lines().collect();
+        let name = "/test.Metrics/Render\\\"";
+        for line in [
+            format!("pajamax_requests_total{{method=\"{name}\",code=\"OK\"}} 1"),
+            format!("pajamax_requests_total{{method=\"{name}\",code=\"NOT_FOUND\"}} 1"),
+            format!("pajamax_request_bytes_total{{method=\"{name}\"}} 15"),
+            format!("pajamax_response_bytes_total{{method=\"{name}\"}} 20"),
+            // cumulative buckets
+            format!("pajamax_request_duration_seconds_bucket{{method=\"{name}\",le=\"0.0025\"}} 0"),
+            format!("pajamax_request_duration_seconds_bucket{{method=\"{name}\",le=\"0.005\"}} 1"),
+            format!("pajamax_request_duration_seconds_bucket{{method=\"{name}\",le=\"0.05\"}} 2"),
+            format!("pajamax_request_duration_seconds_bucket{{method=\"{name}\",le=\"+Inf\"}} 2"),
+            format!("pajamax_request_duration_seconds_count{{method=\"{name}\"}} 2"),
+            String::from("pajamax_connections 2"),
+        ] {
+            assert!(lines.contains(&line.as_str()), "missing: {line}\n{out}");
+        }
+
+        counter.store(0, Ordering::Relaxed);
+        assert!(render_prometheus()
+            .lines()
+            .any(|l| l == "pajamax_connections 0"));
+    }
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(escape("/pkg.Svc/Method"), "/pkg.Svc/Method");
+        assert_eq!(escape("a\\b"), "a\\\\b");
+        assert_eq!(escape("a\"b"), "a\\\"b");
+        assert_eq!(escape("a\nb"), "a\\nb");
+    }
+}
@@ -0,0 +1,326 @@
+// Wakeup of the dispatch response thread, by `Config::dispatch_notify`.
+//
+// The response thread spins a while on the response channel before
+// sleeping, and the spinning limit adapts to whether the responses
+// come in time recently. Before sleeping it marks itself waiting, and
+// only the first sender after that pays for the wakeup syscall, while
+// the other senders of the same batch see the flag cleared and skip.
+//
+// It sleeps on an eventfd on Linux, and on a condvar on others.
+
+use std::sync::atomic::{fence, AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::channel::{RecvError, TryRecvError};
+use crate::dispatch::{ConnOutput, ConnResponseRx, ConnResponseTx};
+use crate::error::Error;
+use crate::macros::*;
+
+const SPIN_MIN: u32 = 4;
+const SPIN_MAX: u32 = 256;
+
+pub(crate) struct Notifier {
+    waiting: AtomicBool,
+    closed: AtomicBool,
+    event: Event,
+}
+
+impl Notifier {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            waiting: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            event: Event::new()?,
+        })
+    }
+
+    // Called by senders after sending.
+    fn notify(&self) {
+        // pairs with the fence in `recv()`, so either the sender sees
+        // the waiting flag, or the receiver sees the sent response
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) && self.waiting.swap(false, Ordering::AcqRel) {
+            self.set_event();
+        }
+    }
+
+    // Called when the last sender is dropped.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.waiting.store(false, Ordering::Relaxed);
+        self.set_event();
+    }
+
+    fn set_event(&self) {
+        if let Err(err) = self.event.set() {
+            error!("fail to wake up dispatch response thread: {:?}", err);
+        }
+    }
+
+    // Receive a response, spinning first and then sleeping.
+    pub(crate) fn recv(
+        &self,
+        rx: &dyn ConnResponseRx,
+        spin_limit: &mut u32,
+    ) -> Result<ConnOutput, RecvError> {
+        for i in 0..*spin_limit {
+            match rx.try_recv() {
+                Ok(output) => {
+                    *spin_limit = (*spin_limit * 2).min(SPIN_MAX);
+                    return Ok(output);
+                }
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) if i < 16 => std::hint::spin_loop(),
+                Err(TryRecvError::Empty) => std::thread::yield_now(),
+            }
+        }
+        *spin_limit = (*spin_limit / 2).max(SPIN_MIN);
+
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                // the senders are being dropped
+                return rx.recv();
+            }
+
+            self.waiting.store(true, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            match rx.try_recv() {
+                Ok(output) => {
+                    self.waiting.store(false, Ordering::Relaxed);
+                    return Ok(output);
+                }
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {
+                    trace!("dispatch response thread sleeps");
+                    match self.event.wait() {
+                        Ok(()) => (),
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                        Err(err) => {
+                            // not to spin on the broken event
+                            error!("fail to sleep on event: {:?}, block on channel", err);
+                            self.waiting.store(false, Ordering::Relaxed);
+                            return rx.recv();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Response channel sender which wakes the response thread up.
+pub(crate) struct NotifyTx {
+    tx: Arc<dyn ConnResponseTx>,
+    notifier: Arc<Notifier>,
+}
+
+impl NotifyTx {
+    pub(crate) fn new(tx: Arc<dyn ConnResponseTx>, notifier: Arc<Notifier>) -> Self {
+        Self { tx, notifier }
+    }
+}
+
+impl ConnResponseTx for NotifyTx {
+    fn send(&self, output: ConnOutput) -> Result<(), Error> {
+        self.tx.send(output)?;
+        self.notifier.notify();
+        Ok(())
+    }
+}
+
+impl Drop for NotifyTx {
+    fn drop(&mut self) {
+        self.notifier.close();
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Event(std::os::fd::OwnedFd);
+
+#[cfg(target_os = "linux")]
+impl Event {
+    fn new() -> std::io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn set(&self) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let n: u64 = 1;
+        let ret = unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &n as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Wait and reset the event. Return `Interrupted` error on interrupt,
+    // and the caller will check the channel again.
+    fn wait(&self) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mut n: u64 = 0;
+        let ret = unsafe {
+            libc::read(
+                self.0.as_raw_fd(),
+                &mut n as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Event {
+    set: std::sync::Mutex<bool>,
+    cond: std::sync::Condvar,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Event {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            set: std::sync::Mutex::new(false),
+            cond: std::sync::Condvar::new(),
+        })
+    }
+
+    fn set(&self) -> std::io::Result<()> {
+        *self.set.lock().unwrap() = true;
+        self.cond.notify_one();
+        Ok(())
+    }
+
+    fn wait(&self) -> std::io::Result<()> {
+        let mut set = self.set.lock().unwrap();
+        while !*set {
+            set = self.cond.wait(set).unwrap();
+        }
+        *set = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::StdChannel;
+    use crate::dispatch::new_response_channel;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Pending wakeups in the eventfd, without waiting.
+    #[cfg(target_os = "linux")]
+    fn pending_wakeups(notifier: &Notifier) -> u64 {
+        use std::os::fd::AsRawFd;
+
+        let fd = notifier.event.0.as_raw_fd();
+        let mut n: u64 = 0;
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            libc::read(fd, &mut n as *mut u64 as *mut libc::c_void, 8);
+            libc::fcntl(fd, libc::F_SETFL, flags);
+        }
+        n
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn coalesce_wakeups() {
+        let notifier = Arc::new(Notifier::new().unwrap());
+
+        // no wakeup if the receiver is not waiting
+        notifier.notify();
+        assert_eq!(pending_wakeups(&notifier), 0);
+
+        // one wakeup for many senders
+        notifier.waiting.store(true, Ordering::SeqCst);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let notifier = notifier.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        notifier.notify();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(pending_wakeups(&notifier), 1);
+    }
+
+    #[test]
+    fn no_lost_wakeup() {
+        const SENDERS: usize = 4;
+        const ROUNDS: usize = 1000;
+
+        let notifier = Arc::new(Notifier::new().unwrap());
+        let (tx, rx) = new_response_channel::<StdChannel>(100);
+        let tx = Arc::new(NotifyTx::new(tx, notifier.clone()));
+
+        // Receive and acknowledge each, so the receiver goes to sleep
+        // between the rounds.
+        let (ack_tx, ack_rx) = mpsc::channel();
+        let receiver = thread::spawn(move || {
+            let mut spin_limit = SPIN_MIN;
+            for _ in 0..SENDERS * ROUNDS {
+                let ConnOutput::Frames(frame) = notifier.recv(&*rx, &mut spin_limit).unwrap()
+                else {
+                    panic!("unexpected output");
+                };
+                ack_tx.send(frame[0] as usize).unwrap();
+            }
+        });
+
+        let acks: Vec<_> = (0..SENDERS).map(|_| mpsc::channel()).collect();
+        let (ack_txs, ack_rxs): (Vec<_>, Vec<_>) = acks.into_iter().unzip();
+        let dispatcher = thread::spawn(move || {
+            while let Ok(sender) = ack_rx.recv() {
+                ack_txs[sender].send(()).unwrap();
+            }
+        });
+
+        let senders: Vec<_> = ack_rxs
+            .into_iter()
+            .enumerate()
+            .map(|(i, ack_rx)| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        tx.send(ConnOutput::Frames(vec![i as u8])).unwrap();
+                        // lost if not received in time
+                        ack_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for s in senders {
+            s.join().unwrap();
+        }
+
+        // the sender is still alive, so no wakeup by closing
+        receiver.join().unwrap();
+        drop(tx);
+        dispatcher.join().unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+// Reactor execution model: one thread, optionally pinned to a CPU,
+// drives all connections by epoll, and never blocks except on
+// `epoll_wait()`.
+//
+// Requests are parsed and handled by `ConnState::process()`, with
+// local-mode handlers called inline. Responses are flushed into a
+// per-connection output buffer instead of the socket, and then written
+// to the socket without blocking. If the socket is not writable, the
+// rest is kept and written when it's writable again, and the reading is
+// paused if too much output is pending.
+//
+// Dispatch-mode services are not supported, since their responses are
+// written by other threads.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::fd::RawFd;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::connection::ConnState;
+use crate::epoll::{self, Epoll};
+use crate::error::Error;
+use crate::macros::*;
+use crate::response_end::Output;
+use crate::worker_pool::OnClose;
+
+// How often to check idle and blocked connections.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+// Max events for each `epoll_wait()`.
+const MAX_EVENTS: usize = 256;
+
+// Pause reading the connection if more output is pending.
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+
+// Output buffer of a connection, as the output end of `ConnState`.
+// Shared with the reactor to write to the socket, both on the reactor
+// thread.
+#[derive(Clone, Default)]
+pub(crate) struct OutputBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ReactorConn {
+    fd: RawFd,
+    _c: Box<dyn Send>, // keep the socket open
+    state: ConnState,
+    output: OutputBuffer,
+    interest: u32,
+    last_active: Instant,
+    write_blocked: Option<Instant>, // since when output is pending
+    on_close: OnClose,
+}
+
+pub(crate) struct Reactor {
+    epoll: Arc<Epoll>,
+    tx: mpsc::Sender<ReactorConn>,
+}
+
+impl Reactor {
+    pub(crate) fn new(
+        cpu: Option<usize>,
+        idle_timeout: Duration,
+        write_timeout: Duration,
+    ) -> std::io::Result<Self> {
+        let epoll = Arc::new(Epoll::new()?);
+        let (tx, rx) = mpsc::channel();
+
+        let epoll2 = epoll.clone();
+        thread::Builder::new()
+            .name(String::from("pajamax-e"))
+            .spawn(move || {
+                if let Some(cpu) = cpu {
+                    if let Err(err) = epoll::pin_to_cpu(cpu) {
+                        error!("fail to pin reactor to CPU {cpu}: {:?}", err);
+                    }
+                }
+                if let Err(err) = reactor_routine(&epoll2, rx, idle_timeout, write_timeout) {
+                    error!("reactor fail: {:?}", err);
+                }
+            })?;
+
+        Ok(Self { epoll, tx })
+    }
+
+    // The output end for `ConnState` of a new connection, and the
+    // buffer behind it for `add()`.
+    pub(crate) fn new_output() -> (Output, OutputBuffer) {
+        let output = OutputBuffer::default();
+        (Box::new(output.clone()), output)
+    }
+
+    pub(crate) fn add<C: Send + 'static>(
+        &self,
+        c: C,
+        fd: RawFd,
+        state: ConnState,
+        output: OutputBuffer,
+        on_close: OnClose,
+    ) -> std::io::Result<()> {
+        let conn = ReactorConn {
+            fd,
+            _c: Box::new(c),
+            state,
+            output,
+            interest: epoll::READABLE,
+            last_active: Instant::now(),
+            write_blocked: None,
+            on_close,
+        };
+
+        // Register before sending, so on failure the connection is not
+        // owned by the reactor, and `on_close` is not called. The events
+        // before the reactor receives the connection are skipped, and
+        // reported again as epoll is level-triggered.
+        self.epoll.add(fd, fd as u64, epoll::READABLE)?;
+        if self.tx.send(conn).is_err() {
+            let _ = self.epoll.delete(fd);
+            return Err(std::io::Error::other("reactor exited"));
+        }
+        Ok(())
+    }
+}
+
+fn reactor_routine(
+    epoll: &Epoll,
+    rx: mpsc::Receiver<ReactorConn>,
+    idle_timeout: Duration,
+    write_timeout: Duration,
+) -> std::io::Result<()> {
+    let mut conns: HashMap<RawFd, ReactorConn> = HashMap::new();
+    let mut events = Vec::with_capacity(MAX_EVENTS);
+    let mut last_sweep = Instant::now();
+
+    let close = |conns: &mut HashMap<RawFd, ReactorConn>, fd, result| {
+        let conn: ReactorConn = conns.remove(&fd).unwrap();
+        let _ = epoll.delete(fd);
+        epoll::shutdown(fd);
+        (conn.on_close)(result);
+    };
+
+    loop {
+        epoll.wait(&mut events, SWEEP_INTERVAL)?;
+
+        // new connections
+        while let Ok(conn) = rx.try_recv() {
+            conns.insert(conn.fd, conn);
+        }
+
+        for event in events.iter() {
+            let fd = event.u64 as RawFd;
+            let Some(conn) = conns.get_mut(&fd) else {
+                continue;
+            };
+
+            match on_event(conn, event.events, epoll) {
+                Ok(true) => (),
+                Ok(false) => close(&mut conns, fd, Ok(())),
+                Err(err) => close(&mut conns, fd, Err(err)),
+            }
+        }
+
+        // close idle connections, and blocked ones
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            last_sweep = Instant::now();
+            let expired: Vec<(RawFd, Result<(), Error>)> = conns
+                .values()
+                .filter_map(|conn| match conn.write_blocked {
+                    Some(t) if t.elapsed() >= write_timeout => {
+                        let err = std::io::Error::from(std::io::ErrorKind::TimedOut);
+                        Some((conn.fd, Err(err.into())))
+                    }
+                    None if conn.last_active.elapsed() >= idle_timeout => Some((conn.fd, Ok(()))),
+                    _ => None,
+                })
+                .collect();
+            for (fd, result) in expired {
+                trace!("close expired connection");
+                close(&mut conns, fd, result);
+            }
+        }
+    }
+}
+
+// Return false if the connection is closed by peer.
+fn on_event(conn: &mut ReactorConn, events: u32, epoll: &Epoll) -> Result<bool, Error> {
+    if events & epoll::WRITABLE != 0 {
+        write_output(conn)?;
+    }
+
+    if events & !epoll::WRITABLE != 0 && conn.interest & epoll::READABLE != 0 {
+        match epoll::recv_nonblocking(conn.fd, conn.state.input_buf()) {
+            Ok(0) => return Ok(false),
+            Ok(len) => {
+                trace!("receive data {len}");
+                conn.last_active = Instant::now();
+                conn.state.process(len)?;
+                write_output(conn)?;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => (),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // update the interest by pending output
+    let pending = conn.output.0.lock().unwrap().len();
+    let interest = if pending == 0 {
+        epoll::READABLE
+    } else if pending < MAX_PENDING_OUTPUT {
+        epoll::READABLE | epoll::WRITABLE
+    } else {
+        epoll::WRITABLE
+    };
+    if interest != conn.interest {
+        epoll.modify(conn.fd, conn.fd as u64, interest)?;
+        conn.interest = interest;
+    }
+    Ok(true)
+}
+
+// Write the pending output as much as possible.
+fn write_output(conn: &mut ReactorConn) -> std::io::Result<()> {
+    let mut buf = conn.output.0.lock().unwrap();
+
+    let mut written = 0;
+    while written < buf.len() {
+        match epoll::send_nonblocking(conn.fd, &buf[written..]) {
+            Ok(n) => written += n,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    buf.drain(..written);
+
+    if buf.is_empty() {
+        conn.write_blocked = None;
+    } else if written > 0 || conn.write_blocked.is_none() {
+        trace!("partial write, pending {}", buf.len());
+        conn.write_blocked = Some(Instant::now());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::*;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    // Add a connection of the echo service to the reactor, and return the
+    // peer end, and the receiver of the `on_close` result.
+    fn add_conn(reactor: &Reactor) -> (UnixStream, mpsc::Receiver<Result<(), Error>>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (output, buffer) = Reactor::new_output();
+        let (state, _) = echo_conn_state(output, Config::new());
+        let (tx, rx) = mpsc::channel();
+        let fd = server.as_raw_fd();
+        let on_close = Box::new(move |result| {
+            let _ = tx.send(result);
+        });
+        reactor.add(server, fd, state, buffer, on_close).unwrap();
+        (client, rx)
+    }
+
+    // Write requests of `count` strings of `len` bytes in another thread,
+    // and return whether all are written.
+    fn write_requests(client: &UnixStream, count: u32, len: usize) -> thread::JoinHandle<bool> {
+        let mut client = client.try_clone().unwrap();
+        let s = "x".repeat(len);
+        thread::spawn(move || {
+            let mut buf = client_preface();
+            for i in 0..count {
+                buf.extend(echo_request(i * 2 + 1, &s));
+            }
+            client.write_all(&buf).is_ok()
+        })
+    }
+
+    // Requests (and replies) of 16000 bytes fit in one frame.
+    const COUNT: u32 = 256;
+    const LEN: usize = 16000;
+
+    #[test]
+    fn request_and_peer_close() {
+        let reactor = Reactor::new(None, WAIT, WAIT).unwrap();
+        let (mut client, closed) = add_conn(&reactor);
+
+        client.write_all(&client_preface()).unwrap();
+        client.write_all(&echo_request(1, "hello")).unwrap();
+        assert_eq!(read_echo_reply(&mut client).as_deref(), Some("hello"));
+
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(closed.recv_timeout(WAIT).unwrap().is_ok());
+    }
+
+    #[test]
+    fn pause_reading() {
+        let reactor = Reactor::new(None, WAIT, WAIT).unwrap();
+        let (mut client, closed) = add_conn(&reactor);
+
+        // The peer does not read, so the output is pending more than
+        // MAX_PENDING_OUTPUT, and then the reading is paused, and the
+        // writing of requests is blocked.
+        assert!(COUNT as usize * LEN > MAX_PENDING_OUTPUT * 2);
+        let writer = write_requests(&client, COUNT, LEN);
+        thread::sleep(Duration::from_millis(300));
+        assert!(!writer.is_finished());
+
+        // The partial written output is continued when the peer reads.
+        for _ in 0..COUNT {
+            assert_eq!(read_echo_reply(&mut client).unwrap().len(), LEN);
+        }
+        assert!(writer.join().unwrap());
+        assert!(closed.try_recv().is_err());
+    }
+
+    #[test]
+    fn write_timeout() {
+        let write_timeout = Duration::from_millis(300);
+        let reactor = Reactor::new(None, WAIT, write_timeout).unwrap();
+        let (client, closed) = add_conn(&reactor);
+
+        let start = Instant::now();
+        let writer = write_requests(&client, COUNT, LEN);
+        let err = closed.recv_timeout(WAIT).unwrap().unwrap_err();
+        assert!(matches!(err, Error::IoFail(e) if e.kind() == std::io::ErrorKind::TimedOut));
+        assert!(start.elapsed() >= write_timeout);
+
+        // shut down, so the blocked writer fails
+        assert!(!writer.join().unwrap());
+    }
+}
@@ -1,15 +1,27 @@
 use std::io::Write;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
 
+use crate::access_log::{AccessLogSink, AccessRecord};
+use crate::admin::ConnStats;
+use crate::compression::{Codec, Codecs};
 use crate::config::Config;
+use crate::context::RequestContext;
 use crate::hpack_encoder::Encoder;
 use crate::http2;
 use crate::macros::*;
+use crate::metrics;
+use crate::status::Code;
+use crate::tracing::{Span, SpanExporter};
 use crate::Response;
 
+// Output end of the connection, TCP or TLS. Owned by the connection
+// thread, or by the response thread in dispatch-mode.
+pub(crate) type Output = Box<dyn Write + Send>;
+
 pub struct ResponseEnd {
-    c: Arc<Mutex<TcpStream>>,
+    c: Output,
     req_count: usize,
     req_data_len: usize,
     hpack_encoder: Encoder,
@@ -17,10 +29,17 @@ pub struct ResponseEnd {
 
     max_flush_requests: usize,
     max_flush_size: usize,
+
+    stats: Arc<ConnStats>,
+    access_log: Option<Arc<dyn AccessLogSink>>,
+    tracing: Option<Arc<dyn SpanExporter>>,
+
+    codecs: Codecs,
+    uncompressed: Vec<u8>, // buffer for compression
 }
 
 impl ResponseEnd {
-    pub fn new(c: Arc<Mutex<TcpStream>>, config: &Config) -> Self {
+    pub(crate) fn new(c: Output, config: &Config, stats: Arc<ConnStats>) -> Self {
         Self {
             c,
             req_count: 0,
@@ -30,6 +49,13 @@ impl ResponseEnd {
 
             max_flush_requests: config.max_flush_requests,
             max_flush_size: config.max_flush_size,
+
+            stats,
+            access_log: config.access_log.clone(),
+            tracing: config.tracing.clone(),
+
+            codecs: config.codecs.clone(),
+            uncompressed: Vec::new(),
         }
     }
 
@@ -40,22 +66,39 @@ impl ResponseEnd {
         stream_id: u32,
         response: Response<Reply>,
         req_data_len: usize,
+        ctx: Option<&RequestContext>,
     ) -> Result<(), std::io::Error>
     where
         Reply: prost::Message,
     {
-        match response {
+        let start = self.output.len();
+        let code = match response {
             Ok(reply) => {
-                http2::build_response(
+                let encoding = ctx.and_then(|ctx| ctx.encoding.response.as_ref());
+                self.build_reply(stream_id, |output| reply.encode(output).unwrap(), encoding);
+                Code::Ok
+            }
+            Err(status) => {
+                let code = status.code;
+                http2::build_status(
                     stream_id,
-                    |output| reply.encode(output).unwrap(),
+                    status,
+                    self.codecs.accept_encoding(),
                     &mut self.hpack_encoder,
                     &mut self.output,
                 );
+                code
             }
-            Err(status) => {
-                http2::build_status(stream_id, status, &mut self.hpack_encoder, &mut self.output);
-            }
+        };
+
+        if let Some(ctx) = ctx {
+            self.record(
+                stream_id,
+                ctx,
+                code,
+                req_data_len,
+                self.output.len() - start,
+            );
         }
 
         self.update(req_data_len)
@@ -69,24 +112,138 @@ impl ResponseEnd {
         stream_id: u32,
         response: Response<Box<dyn http2::ReplyEncode>>,
         req_data_len: usize,
+        ctx: Option<&RequestContext>,
     ) -> Result<(), std::io::Error> {
-        match response {
+        let start = self.output.len();
+        let code = match response {
             Ok(reply) => {
-                http2::build_response(
+                let encoding = ctx.and_then(|ctx| ctx.encoding.response.as_ref());
+                self.build_reply(stream_id, |output| reply.encode(output).unwrap(), encoding);
+                Code::Ok
+            }
+            Err(status) => {
+                let code = status.code;
+                http2::build_status(
                     stream_id,
-                    |output| reply.encode(output).unwrap(),
+                    status,
+                    self.codecs.accept_encoding(),
                     &mut self.hpack_encoder,
                     &mut self.output,
                 );
+                code
             }
-            Err(status) => {
-                http2::build_status(stream_id, status, &mut self.hpack_encoder, &mut self.output);
-            }
+        };
+
+        if let Some(ctx) = ctx {
+            self.record(
+                stream_id,
+                ctx,
+                code,
+                req_data_len,
+                self.output.len() - start,
+            );
         }
 
         self.update(req_data_len)
     }
 
+    // Append control frames, e.g. SETTINGS and PING ACK, which are
+    // written with the responses in order.
+    pub(crate) fn build_frames(&mut self, frames: &[u8]) {
+        self.output.extend_from_slice(frames);
+    }
+
+    // Build reply. Compress it by the negotiated codec if the message
+    // is not smaller than the threshold.
+    fn build_reply(
+        &mut self,
+        stream_id: u32,
+        reply_fn: impl FnOnce(&mut Vec<u8>),
+        encoding: Option<&(Arc<dyn Codec>, usize)>,
+    ) {
+        let accept_encoding = self.codecs.accept_encoding();
+
+        let Some((codec, threshold)) = encoding else {
+            http2::build_response(
+                stream_id,
+                reply_fn,
+                None,
+                accept_encoding,
+                &mut self.hpack_encoder,
+                &mut self.output,
+            );
+            return;
+        };
+
+        self.uncompressed.clear();
+        reply_fn(&mut self.uncompressed);
+
+        let uncompressed = &self.uncompressed;
+        if uncompressed.len() < *threshold {
+            http2::build_response(
+                stream_id,
+                |output| output.extend_from_slice(uncompressed),
+                None,
+                accept_encoding,
+                &mut self.hpack_encoder,
+                &mut self.output,
+            );
+        } else {
+            http2::build_response(
+                stream_id,
+                |output| codec.compress(uncompressed, output),
+                Some(codec.name()),
+                accept_encoding,
+                &mut self.hpack_encoder,
+                &mut self.output,
+            );
+        }
+    }
+
+    // record metrics, access log and span for a completed request
+    fn record(
+        &self,
+        stream_id: u32,
+        ctx: &RequestContext,
+        code: Code,
+        req_size: usize,
+        resp_size: usize,
+    ) {
+        metrics::record_request(ctx, code, req_size, resp_size);
+
+        if ctx.panicked {
+            self.stats.panics.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(sink) = &self.access_log {
+            let (queue_time, handler_time) = ctx.timing();
+            sink.log(&AccessRecord {
+                peer: ctx.peer,
+                method: ctx.method(),
+                stream_id,
+                code,
+                req_size,
+                resp_size,
+                queue_time,
+                handler_time,
+            });
+        }
+
+        if let (Some(exporter), Some(sc)) = (&self.tracing, &ctx.span) {
+            if sc.is_sampled() {
+                let end = SystemTime::now();
+                exporter.export(&Span {
+                    context: sc,
+                    method: ctx.method(),
+                    peer: ctx.peer,
+                    start: end - ctx.start.elapsed(),
+                    end,
+                    code,
+                });
+            }
+        }
+    }
+
     fn update(&mut self, req_data_len: usize) -> Result<(), std::io::Error> {
         self.req_count += 1;
         self.req_data_len += req_data_len;
@@ -100,7 +257,7 @@ impl ResponseEnd {
 
     // flush the output buffer
     pub fn flush(&mut self) -> Result<(), std::io::Error> {
-        if self.output.len() == 0 {
+        if self.output.is_empty() {
             return Ok(());
         }
 
@@ -110,9 +267,21 @@ impl ResponseEnd {
             self.output.len()
         );
 
-        http2::build_window_update(self.req_data_len, &mut self.output);
+        // responses only, not control frames
+        if self.req_count > 0 {
+            metrics::record_flush(self.req_count);
+        }
+
+        // zero increment is a protocol error, e.g. for control frames only
+        if self.req_data_len > 0 {
+            http2::build_window_update(self.req_data_len, &mut self.output);
+        }
 
-        self.c.lock().unwrap().write_all(&self.output)?;
+        self.c.write_all(&self.output)?;
+
+        self.stats
+            .bytes_out
+            .fetch_add(self.output.len() as u64, Ordering::Relaxed);
 
         self.output.clear();
         self.req_count = 0;
@@ -120,3 +289,50 @@ impl ResponseEnd {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::Registry;
+    use crate::http2::Frame;
+
+    // Compress anything into "z".
+    struct Z;
+
+    impl Codec for Z {
+        fn name(&self) -> &'static str {
+            "z"
+        }
+        fn compress(&self, _input: &[u8], output: &mut Vec<u8>) {
+            output.push(b'z');
+        }
+        fn decompress(&self, _input: &[u8], _output: &mut Vec<u8>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Build the reply, and return the compressed-flag and the message
+    // in the DATA frame.
+    fn build_reply(reply: &[u8], threshold: usize) -> (u8, Vec<u8>) {
+        let config = Config::new().codec(Z);
+        let stats = Registry::default().register("127.0.0.1:1".parse().unwrap());
+        let mut resp_end = ResponseEnd::new(Box::new(std::io::sink()), &config, stats);
+
+        let encoding: (Arc<dyn Codec>, usize) = (Arc::new(Z), threshold);
+        resp_end.build_reply(1, |output| output.extend_from_slice(reply), Some(&encoding));
+
+        // skip the HEADERS frame
+        let output = &resp_end.output;
+        let headers_len = u32::from_be_bytes([0, output[0], output[1], output[2]]) as usize;
+        let data = &output[Frame::HEAD_SIZE + headers_len + Frame::HEAD_SIZE..];
+        let msg_len = u32::from_be_bytes(data[1..5].try_into().unwrap()) as usize;
+        (data[0], data[5..5 + msg_len].to_vec())
+    }
+
+    #[test]
+    fn compression_threshold() {
+        assert_eq!(build_reply(b"small", 6), (0, b"small".to_vec()));
+        assert_eq!(build_reply(b"bigger", 6), (1, b"z".to_vec()));
+        assert_eq!(build_reply(b"", 0), (1, b"z".to_vec()));
+    }
+}
@@ -0,0 +1,667 @@
+//! Built-in shard runtime for dispatch mode.
+//!
+//! In dispatch mode, requests are handled by backend shard threads.
+//! [`ShardPool`] owns these threads and the request channels. It creates
+//! one `{Service}Shard` instance for each shard by your factory closure,
+//! and dispatches requests to the shards by hashing the key you pick
+//! from each request.
+//!
+//! The generated code implements `{Service}Dispatch` for
+//! `ShardPool<{Service}Request>`, so the pool can be used as the
+//! dispatch server directly. Or wrap it in your own dispatch server,
+//! e.g. to scatter some requests to all shards by [`ShardPool::channels`].
+//!
+//! Each shard thread takes the queued requests in batch, up to
+//! [`ShardPoolBuilder::max_batch_size`], and handles them by
+//! `{Service}Shard::handle_batch()`. It handles them one by one by
+//! default, while the shard can override it to amortize the work, e.g.
+//! one lock or one DB write for the batch. The replies of a batch are
+//! sent back to each connection in one chunk.
+//!
+//! The methods set by `pajamax_build::Builder::deferred_method` are
+//! answered by [`Responder`] instead of returning the reply, so the
+//! shard can store it and answer later from any thread, e.g. after disk
+//! I/O or replication.
+//!
+//! The shard threads run as long as their request channels are open.
+//! [`ShardPool::shutdown`] closes them, and waits for the threads to
+//! handle the queued requests and exit. Note that the server never drops
+//! its services, so the pool of a serving service runs until the process
+//! exits.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! let pool = pajamax::shard::ShardPoolBuilder::new(8)
+//!     .thread_name("dict-shard")
+//!     .start(
+//!         // create the shard, called in each shard thread
+//!         |_i| DictStoreShardServer::new(MyDictShard::new()),
+//!         // pick the key to shard by
+//!         |req| match req {
+//!             DictStoreRequest::Get(req) => pajamax::shard::hash_key(&req.key),
+//!             DictStoreRequest::ListShard(req) => req.shard as u64,
+//!             // ...
+//!         },
+//!     )?;
+//!
+//! pajamax::Config::new()
+//!     .add_service(DictStoreServer::new(pool))
+//!     .serve(addr)?;
+//! ```
+
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::channel::{DispatchChannel, Receiver, StdChannel};
+use crate::context::{self, RequestContext};
+use crate::dispatch::{DispatchRequest, DispatchResponse, RequestTx, ResponseTx};
+use crate::macros::*;
+use crate::status::{Code, Status};
+use crate::{ReplyEncode, Response};
+
+/// Shard server which handles dispatched requests.
+///
+/// Implemented for `{Service}ShardServer` by the generated code.
+pub trait Shard: Send + 'static {
+    type Request: Send + 'static;
+
+    fn handle(&mut self, req: DispatchRequest<Self::Request>);
+
+    /// Handle a batch of requests. One by one by default.
+    fn handle_batch(&mut self, reqs: impl Iterator<Item = DispatchRequest<Self::Request>>)
+    where
+        Self: Sized,
+    {
+        for req in reqs {
+            self.handle(req);
+        }
+    }
+}
+
+/// Request in a batch for `{Service}Shard::handle_batch()`, and its reply.
+pub struct BatchItem<Req, Reply> {
+    request: Option<Req>,
+    context: Option<RequestContext>,
+    reply: Option<Reply>,
+    deferred: bool, // answered by `Responder`
+
+    stream_id: u32,
+    req_data_len: usize,
+    resp_tx: ResponseTx,
+}
+
+impl<Req, Reply> BatchItem<Req, Reply> {
+    /// Used by pajamax-build crate.
+    #[doc(hidden)]
+    pub fn new(disp_req: DispatchRequest<Req>) -> Self {
+        Self {
+            request: Some(disp_req.request),
+            context: Some(disp_req.context),
+            reply: None,
+            deferred: false,
+            stream_id: disp_req.stream_id,
+            req_data_len: disp_req.req_data_len,
+            resp_tx: disp_req.resp_tx,
+        }
+    }
+
+    /// The request, if not taken yet.
+    pub fn request(&self) -> Option<&Req> {
+        self.request.as_ref()
+    }
+
+    /// Take the request out, to handle it.
+    pub fn take_request(&mut self) -> Option<Req> {
+        self.request.take()
+    }
+
+    /// Context of the request. It's not entered, so
+    /// [`crate::context::with`] does not work, unless in [`Self::handle`].
+    pub fn context(&self) -> &RequestContext {
+        self.context.as_ref().expect("no request context")
+    }
+
+    /// Set the reply. The request without reply is answered with
+    /// `INTERNAL` status.
+    pub fn set_reply(&mut self, reply: Reply) {
+        self.reply = Some(reply);
+    }
+
+    /// Handle the request by `f` with its context entered, just like
+    /// handling a single request, and set the reply.
+    pub fn handle<F>(&mut self, f: F)
+    where
+        F: FnOnce(Req) -> Reply,
+    {
+        let request = self.request.take().expect("request taken");
+        let reply = context::scope(&mut self.context, || f(request));
+        self.reply = Some(reply);
+    }
+
+    /// Take the request out to be answered by the returned [`Responder`],
+    /// rather than replied in batch.
+    ///
+    /// Used by pajamax-build crate, for the deferred methods.
+    #[doc(hidden)]
+    pub fn defer<Out>(&mut self, convert: fn(Out) -> Box<dyn ReplyEncode>) -> Responder<Out> {
+        self.deferred = true;
+        Responder::new(
+            self.stream_id,
+            self.req_data_len,
+            self.resp_tx.clone(),
+            self.context.take().expect("no request context"),
+            convert,
+        )
+    }
+}
+
+/// Handle to answer a request later, from any thread.
+///
+/// Passed to the shard methods set by
+/// `pajamax_build::Builder::deferred_method`. The request is answered
+/// with `INTERNAL` status if the responder is dropped without replying.
+///
+/// The request context is not entered in these methods, so
+/// [`crate::context::with`] does not work. Use [`Self::context`] instead.
+pub struct Responder<Reply> {
+    stream_id: u32,
+    req_data_len: usize,
+    resp_tx: Option<ResponseTx>, // taken when replied
+    context: Option<RequestContext>,
+    convert: fn(Reply) -> Box<dyn ReplyEncode>,
+}
+
+impl<Reply> Responder<Reply> {
+    /// Used by pajamax-build crate.
+    #[doc(hidden)]
+    pub fn new(
+        stream_id: u32,
+        req_data_len: usize,
+        resp_tx: ResponseTx,
+        mut context: RequestContext,
+        convert: fn(Reply) -> Box<dyn ReplyEncode>,
+    ) -> Self {
+        context.set_handle_start();
+        Self {
+            stream_id,
+            req_data_len,
+            resp_tx: Some(resp_tx),
+            context: Some(context),
+            convert,
+        }
+    }
+
+    /// Context of the request.
+    pub fn context(&self) -> &RequestContext {
+        self.context.as_ref().expect("no request context")
+    }
+
+    /// Answer the request.
+    pub fn reply(mut self, response: Response<Reply>) {
+        let response = response.map(self.convert);
+        self.send(response);
+    }
+
+    fn send(&mut self, response: Response<Box<dyn ReplyEncode>>) {
+        let (Some(resp_tx), Some(mut context)) = (self.resp_tx.take(), self.context.take()) else {
+            return;
+        };
+        context.set_handle_end();
+
+        // the connection may be closed
+        let _ = resp_tx.send(DispatchResponse {
+            stream_id: self.stream_id,
+            req_data_len: self.req_data_len,
+            response,
+            context,
+        });
+    }
+}
+
+impl<Reply> Drop for Responder<Reply> {
+    fn drop(&mut self) {
+        if self.resp_tx.is_some() {
+            error!(
+                "request dropped without reply (stream_id:{})",
+                self.stream_id
+            );
+            self.send(Err(Status {
+                code: Code::Internal,
+                message: String::from("request dropped without reply"),
+            }));
+        }
+    }
+}
+
+/// Send the replies of the batch, in one chunk for each connection.
+///
+/// Used by pajamax-build crate, with `convert` from the reply enum.
+#[doc(hidden)]
+pub fn send_batch<Req, Reply, F>(batch: Vec<BatchItem<Req, Reply>>, convert: F)
+where
+    F: Fn(Reply) -> Response<Box<dyn ReplyEncode>>,
+{
+    let mut chunks: Vec<(ResponseTx, Vec<DispatchResponse>)> = Vec::new();
+
+    for item in batch {
+        if item.deferred {
+            continue;
+        }
+        let response = match item.reply {
+            Some(reply) => convert(reply),
+            None => Err(Status {
+                code: Code::Internal,
+                message: String::from("no reply in batch"),
+            }),
+        };
+        let resp = DispatchResponse {
+            stream_id: item.stream_id,
+            req_data_len: item.req_data_len,
+            response,
+            context: item.context.expect("no request context"),
+        };
+
+        match chunks
+            .iter_mut()
+            .find(|(tx, _)| tx.same_channel(&item.resp_tx))
+        {
+            Some((_, resps)) => resps.push(resp),
+            None => chunks.push((item.resp_tx, vec![resp])),
+        }
+    }
+
+    for (resp_tx, resps) in chunks {
+        let _ = resp_tx.send_batch(resps);
+    }
+}
+
+/// Builder of [`ShardPool`].
+pub struct ShardPoolBuilder<C = StdChannel> {
+    shards: usize,
+    channel_size: usize,
+    max_batch_size: usize,
+    thread_name: String,
+    cpus: Vec<usize>,
+    _channel: PhantomData<C>,
+}
+
+impl ShardPoolBuilder<StdChannel> {
+    /// Create a builder of pool with `shards` threads.
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0);
+        Self {
+            shards,
+            channel_size: 1000,
+            max_batch_size: 32,
+            thread_name: String::from("pajamax-s"),
+            cpus: Vec::new(),
+            _channel: PhantomData,
+        }
+    }
+}
+
+impl<C: DispatchChannel> ShardPoolBuilder<C> {
+    /// Channel type of the request channels. It must be the same with
+    /// `pajamax_build::Builder::dispatch_channel` of the service.
+    ///
+    /// See [`crate::channel`] for details.
+    ///
+    /// Default: [`StdChannel`]
+    pub fn channel<C2: DispatchChannel>(self) -> ShardPoolBuilder<C2> {
+        ShardPoolBuilder {
+            shards: self.shards,
+            channel_size: self.channel_size,
+            max_batch_size: self.max_batch_size,
+            thread_name: self.thread_name,
+            cpus: self.cpus,
+            _channel: PhantomData,
+        }
+    }
+
+    /// Capacity of the request channel of each shard. Requests are
+    /// refused with `UNAVAILABLE` status if the channel is full.
+    ///
+    /// Default: 1000
+    pub fn channel_size(self, n: usize) -> Self {
+        Self {
+            channel_size: n,
+            ..self
+        }
+    }
+
+    /// Max number of queued requests that a shard thread takes and
+    /// handles in batch. Set 1 to handle requests one by one.
+    ///
+    /// Default: 32
+    pub fn max_batch_size(self, n: usize) -> Self {
+        assert!(n > 0, "batch size must be positive");
+        Self {
+            max_batch_size: n,
+            ..self
+        }
+    }
+
+    /// Name of the shard threads.
+    ///
+    /// Default: "pajamax-s"
+    pub fn thread_name(self, name: &str) -> Self {
+        Self {
+            thread_name: String::from(name),
+            ..self
+        }
+    }
+
+    /// Pin the shard threads to these CPUs, the i-th shard to
+    /// `cpus[i % cpus.len()]`. Linux only, and ignored on others.
+    ///
+    /// Default: empty, not pinned
+    pub fn pin_cpus(self, cpus: Vec<usize>) -> Self {
+        Self { cpus, ..self }
+    }
+
+    /// Start the shard threads.
+    ///
+    /// The `factory` is called in each shard thread with the shard
+    /// index, to create the shard server. The `key` returns the key of
+    /// the request, and the request is dispatched to the shard of
+    /// `key % shards`. See [`hash_key`] for hashing.
+    pub fn start<S, F, K>(self, factory: F, key: K) -> std::io::Result<ShardPool<S::Request, C>>
+    where
+        S: Shard,
+        F: Fn(usize) -> S + Send + Sync + 'static,
+        K: Fn(&S::Request) -> u64 + Send + Sync + 'static,
+    {
+        let factory = Arc::new(factory);
+
+        let mut req_txs = Vec::with_capacity(self.shards);
+        let mut threads = Vec::with_capacity(self.shards);
+        for i in 0..self.shards {
+            let (req_tx, req_rx) = C::bounded(self.channel_size);
+            let factory = factory.clone();
+            let cpu = (!self.cpus.is_empty()).then(|| self.cpus[i % self.cpus.len()]);
+            let max_batch_size = self.max_batch_size;
+
+            let handle = thread::Builder::new()
+                .name(self.thread_name.clone())
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        pin_to_cpu(cpu);
+                    }
+                    let mut shard = factory(i);
+                    let mut batch = Vec::with_capacity(max_batch_size);
+                    while let Ok(req) = req_rx.recv() {
+                        batch.push(req);
+                        while batch.len() < max_batch_size {
+                            match req_rx.try_recv() {
+                                Ok(req) => batch.push(req),
+                                Err(_) => break,
+                            }
+                        }
+                        shard.handle_batch(batch.drain(..));
+                    }
+                })?;
+
+            req_txs.push(req_tx);
+            threads.push(handle);
+        }
+
+        Ok(ShardPool {
+            req_txs,
+            key: Box::new(key),
+            threads,
+        })
+    }
+}
+
+/// Shard threads and their request channels.
+///
+/// See the [module-level documentation](self) for details.
+pub struct ShardPool<Req: Send, C: DispatchChannel = StdChannel> {
+    req_txs: Vec<RequestTx<Req, C>>,
+    key: Box<dyn Fn(&Req) -> u64 + Send + Sync>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<Req: Send, C: DispatchChannel> ShardPool<Req, C> {
+    /// Number of shards.
+    pub fn shards(&self) -> usize {
+        self.req_txs.len()
+    }
+
+    /// Request channels of all shards, e.g. for
+    /// [`Scatter::All`](crate::dispatch::Scatter::All).
+    pub fn channels(&self) -> &[RequestTx<Req, C>] {
+        &self.req_txs
+    }
+
+    /// Request channel of the shard which the request belongs to.
+    ///
+    /// Used by the generated `{Service}Dispatch` implementation.
+    pub fn pick(&self, req: &Req) -> &RequestTx<Req, C> {
+        let i = (self.key)(req) % self.req_txs.len() as u64;
+        &self.req_txs[i as usize]
+    }
+
+    /// Close the request channels, and wait for the shard threads to
+    /// handle the queued requests and exit.
+    ///
+    /// The threads exit only after all clones of the channels, e.g. from
+    /// [`Self::channels`], are dropped too, so drop them first. If the
+    /// pool is just dropped, the threads exit the same way, but are not
+    /// waited for.
+    pub fn shutdown(self) {
+        drop(self.req_txs);
+        for handle in self.threads {
+            if handle.join().is_err() {
+                error!("shard thread panicked");
+            }
+        }
+    }
+}
+
+/// Hash the key, e.g. a string field of the request, for
+/// [`ShardPoolBuilder::start`].
+pub fn hash_key<K>(key: &K) -> u64
+where
+    K: Hash + ?Sized,
+{
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn pin_to_cpu(cpu: usize) {
+    #[cfg(target_os = "linux")]
+    if let Err(err) = crate::epoll::pin_to_cpu(cpu) {
+        error!("fail to pin shard to CPU {cpu}: {:?}", err);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = cpu;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Method;
+    use crate::dispatch::{new_response_channel, ConnOutput, LocalReply, ResponseRx};
+    use crate::metadata::Metadata;
+    use std::sync::mpsc;
+
+    fn context() -> RequestContext {
+        let method = Method::get(b"/test.Svc/Shard");
+        RequestContext::new(method, "127.0.0.1:1".parse().unwrap(), Metadata::new())
+    }
+
+    fn request<Req>(stream_id: u32, request: Req, resp_tx: &ResponseTx) -> DispatchRequest<Req> {
+        DispatchRequest {
+            stream_id,
+            req_data_len: 0,
+            request,
+            resp_tx: resp_tx.clone(),
+            context: context(),
+        }
+    }
+
+    fn conn() -> (ResponseTx, ResponseRx) {
+        let (tx, rx) = new_response_channel::<StdChannel>(100);
+        (ResponseTx::to_conn(tx), rx)
+    }
+
+    // The stream id and the reply, or the error code, of the responses.
+    fn responses(output: ConnOutput) -> Vec<(u32, Result<u32, Code>)> {
+        let resps = match output {
+            ConnOutput::Dispatched(resp) => vec![resp],
+            ConnOutput::DispatchedBatch(resps) => resps,
+            _ => panic!("unexpected output"),
+        };
+        resps
+            .into_iter()
+            .map(|resp| {
+                let response = match resp.response {
+                    Ok(reply) => Ok(*reply.into_any().downcast::<u32>().unwrap()),
+                    Err(status) => Err(status.code),
+                };
+                (resp.stream_id, response)
+            })
+            .collect()
+    }
+
+    // Reply the shard index, and report the request.
+    struct IndexShard {
+        index: usize,
+        handled: mpsc::Sender<(usize, u64)>,
+    }
+
+    impl Shard for IndexShard {
+        type Request = u64;
+
+        fn handle(&mut self, req: DispatchRequest<u64>) {
+            self.handled.send((self.index, req.request)).unwrap();
+            let _ = req.resp_tx.send(DispatchResponse {
+                stream_id: req.stream_id,
+                req_data_len: req.req_data_len,
+                response: Ok(Box::new(LocalReply(self.index as u32))),
+                context: req.context,
+            });
+        }
+    }
+
+    #[test]
+    fn pool_routing() {
+        let (handled, handled_rx) = mpsc::channel();
+        let pool = ShardPoolBuilder::new(4)
+            .start(
+                move |index| IndexShard {
+                    index,
+                    handled: handled.clone(),
+                },
+                |req: &u64| *req,
+            )
+            .unwrap();
+        assert_eq!(pool.shards(), 4);
+
+        let (resp_tx, resp_rx) = conn();
+        for i in 0..16 {
+            let req = request(i as u32 * 2 + 1, i, &resp_tx);
+            pool.pick(&req.request).send(req).ok().unwrap();
+        }
+        drop(resp_tx);
+
+        // all queued requests are handled before exit
+        pool.shutdown();
+        let mut handled: Vec<(usize, u64)> = handled_rx.try_iter().collect();
+        handled.sort_by_key(|(_, req)| *req);
+        assert_eq!(handled.len(), 16);
+        for (index, req) in handled {
+            assert_eq!(index as u64, req % 4);
+        }
+
+        let mut count = 0;
+        while let Ok(output) = resp_rx.try_recv() {
+            for (stream_id, response) in responses(output) {
+                assert_eq!(response, Ok((stream_id - 1) / 2 % 4));
+                count += 1;
+            }
+        }
+        assert_eq!(count, 16);
+    }
+
+    #[test]
+    fn hash_key_routing() {
+        assert_eq!(hash_key("apple"), hash_key(&String::from("apple")));
+        assert_ne!(hash_key("apple"), hash_key("banana"));
+
+        let (handled, _handled_rx) = mpsc::channel();
+        let pool = ShardPoolBuilder::new(8)
+            .start(
+                move |index| IndexShard {
+                    index,
+                    handled: handled.clone(),
+                },
+                |req: &u64| hash_key(req),
+            )
+            .unwrap();
+
+        // the same key to the same shard, and the keys spread
+        let mut used = [false; 8];
+        for key in 0..64_u64 {
+            let tx = pool.pick(&key);
+            assert!(std::ptr::eq(tx, pool.pick(&key)));
+            let i = pool.channels().iter().position(|c| std::ptr::eq(c, tx));
+            used[i.unwrap()] = true;
+        }
+        assert!(used.iter().filter(|u| **u).count() > 1);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn batch_replies() {
+        let (tx1, rx1) = conn();
+        let (tx2, rx2) = conn();
+
+        let mut batch: Vec<BatchItem<u64, u32>> = vec![
+            BatchItem::new(request(1, 10, &tx1)),
+            BatchItem::new(request(1, 20, &tx2)),
+            BatchItem::new(request(3, 30, &tx1)),
+            BatchItem::new(request(5, 40, &tx1)),
+        ];
+        batch[0].set_reply(10);
+        batch[1].set_reply(20);
+        let responder = batch[3].defer(|r: u32| Box::new(LocalReply(r)));
+
+        // one chunk for each connection, without the deferred one
+        send_batch(batch, |r| {
+            Ok(Box::new(LocalReply(r)) as Box<dyn ReplyEncode>)
+        });
+        assert_eq!(
+            responses(rx1.try_recv().ok().unwrap()),
+            [(1, Ok(10)), (3, Err(Code::Internal))]
+        );
+        assert_eq!(responses(rx2.try_recv().ok().unwrap()), [(1, Ok(20))]);
+        assert!(rx1.try_recv().is_err());
+
+        responder.reply(Ok(40));
+        assert_eq!(responses(rx1.try_recv().ok().unwrap()), [(5, Ok(40))]);
+    }
+
+    #[test]
+    fn responder_dropped() {
+        let (tx, rx) = conn();
+        let mut item: BatchItem<u64, u32> = BatchItem::new(request(7, 70, &tx));
+        let responder = item.defer(|r: u32| Box::new(LocalReply(r)));
+        send_batch(vec![item], |r| {
+            Ok(Box::new(LocalReply(r)) as Box<dyn ReplyEncode>)
+        });
+        assert!(rx.try_recv().is_err());
+
+        drop(responder);
+        assert_eq!(
+            responses(rx.try_recv().ok().unwrap()),
+            [(7, Err(Code::Internal))]
+        );
+    }
+}
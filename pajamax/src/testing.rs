@@ -0,0 +1,108 @@
+// Helpers for tests of connections: an echo service, and a raw HTTP/2
+// client which sends requests and reads the frames.
+
+use std::io::Read;
+use std::sync::Arc;
+
+use prost::Message;
+
+use crate::admin::{ConnStats, Registry};
+use crate::admission::Admission;
+use crate::config::Config;
+use crate::connection::{local_build_response, ConnState};
+use crate::error::Error;
+use crate::http2::PREFACE;
+use crate::response_end::Output;
+use crate::PajamaxService;
+
+pub(crate) const ECHO_PATH: &str = "/test.Echo/Echo";
+
+// Local-mode service, which replies the request string.
+pub(crate) struct EchoService;
+
+impl PajamaxService for EchoService {
+    fn route(&self, path: &[u8]) -> Option<usize> {
+        (path == ECHO_PATH.as_bytes()).then_some(0)
+    }
+
+    fn handle(
+        &self,
+        _req_disc: usize,
+        req_buf: &[u8],
+        stream_id: u32,
+        data_len: usize,
+    ) -> Result<(), Error> {
+        let request = String::decode(req_buf)?;
+        local_build_response(stream_id, Ok(request), data_len)
+    }
+
+    fn is_dispatch_mode(&self) -> bool {
+        false
+    }
+}
+
+// State of a connection to the echo service.
+pub(crate) fn echo_conn_state(output: Output, config: Config) -> (ConnState, Arc<ConnStats>) {
+    let services: Vec<Arc<dyn PajamaxService + Send + Sync>> = vec![Arc::new(EchoService)];
+    let admission = Arc::new(Admission::new(&config, &services).unwrap());
+    let stats = Registry::default().register("127.0.0.1:1".parse().unwrap());
+    let state = ConnState::new(services, output, None, config, admission, stats.clone());
+    (state, stats)
+}
+
+pub(crate) const DATA: u8 = 0;
+pub(crate) const HEADERS: u8 = 1;
+pub(crate) const SETTINGS: u8 = 4;
+pub(crate) const GOAWAY: u8 = 7;
+
+fn push_frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.push(kind);
+    buf.push(flags);
+    buf.extend_from_slice(&stream_id.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+// The connection preface and an empty SETTINGS frame.
+pub(crate) fn client_preface() -> Vec<u8> {
+    let mut buf = PREFACE.to_vec();
+    push_frame(SETTINGS, 0, 0, &[], &mut buf);
+    buf
+}
+
+// An echo request of the string, in HEADERS and DATA frames.
+pub(crate) fn echo_request(stream_id: u32, s: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    // `:path` in literal without indexing, with indexed name
+    let mut headers = vec![0x04, ECHO_PATH.len() as u8];
+    headers.extend_from_slice(ECHO_PATH.as_bytes());
+    push_frame(HEADERS, 0x4, stream_id, &headers, &mut buf); // END_HEADERS
+
+    let msg = s.to_string().encode_to_vec();
+    let mut data = vec![0];
+    data.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    data.extend_from_slice(&msg);
+    push_frame(DATA, 0x1, stream_id, &data, &mut buf); // END_STREAM
+    buf
+}
+
+// Read a frame, and return its kind and payload. None if closed.
+pub(crate) fn read_frame(c: &mut impl Read) -> Option<(u8, Vec<u8>)> {
+    let mut head = [0; 9];
+    c.read_exact(&mut head).ok()?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    let mut payload = vec![0; len];
+    c.read_exact(&mut payload).ok()?;
+    Some((head[3], payload))
+}
+
+// Read frames until the reply of echo service. None if closed.
+pub(crate) fn read_echo_reply(c: &mut impl Read) -> Option<String> {
+    loop {
+        let (kind, payload) = read_frame(c)?;
+        if kind == DATA {
+            return Some(String::decode(&payload[5..]).unwrap());
+        }
+    }
+}
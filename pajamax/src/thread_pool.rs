@@ -0,0 +1,220 @@
+// Shared thread pool for pool-mode services, which are stateless and
+// CPU-heavy, so one slow request does not block the connection.
+//
+// Each worker has its own queue. Jobs are submitted to the queues in
+// turn, and an idle worker steals jobs from the others' queues before
+// sleeping. The pool is started on the first job, by the settings of
+// the first server. The settings of later servers are ignored, with an
+// error logged if they differ.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+
+use crate::macros::*;
+
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+struct ThreadPool {
+    queues: Vec<Mutex<VecDeque<Job>>>,
+    next: AtomicUsize, // queue to submit to
+
+    // jobs submitted and not taken yet, limited by `max_pending`
+    pending: AtomicUsize,
+    max_pending: usize,
+
+    // for idle workers to sleep
+    idle: AtomicUsize,
+    lock: Mutex<()>,
+    cond: Condvar,
+}
+
+static SETTINGS: OnceLock<(usize, usize)> = OnceLock::new();
+static POOL: OnceLock<Arc<ThreadPool>> = OnceLock::new();
+
+// Set the size of the pool, by `Config::pool_threads` and
+// `Config::pool_queue_size`. Only the first one works.
+pub(crate) fn configure(threads: usize, queue_size: usize) {
+    let &settings = SETTINGS.get_or_init(|| (threads, queue_size));
+    if settings != (threads, queue_size) {
+        error!(
+            "ignore thread pool settings ({} threads, queue size {}), configured already ({}, {})",
+            threads, queue_size, settings.0, settings.1
+        );
+    }
+}
+
+fn pool() -> &'static Arc<ThreadPool> {
+    POOL.get_or_init(|| {
+        let &(threads, queue_size) = SETTINGS.get_or_init(|| (default_threads(), 1000));
+        ThreadPool::start(threads, queue_size)
+    })
+}
+
+pub(crate) fn default_threads() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+// Take a place in the pool for a job. Return false if full.
+pub(crate) fn reserve() -> bool {
+    pool().reserve()
+}
+
+// Submit a job after `reserve()`.
+pub(crate) fn submit(job: Job) {
+    pool().submit(job)
+}
+
+impl ThreadPool {
+    fn start(threads: usize, queue_size: usize) -> Arc<Self> {
+        let pool = Arc::new(ThreadPool {
+            queues: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            next: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            max_pending: queue_size,
+            idle: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            cond: Condvar::new(),
+        });
+
+        for i in 0..threads {
+            let pool = pool.clone();
+            thread::Builder::new()
+                .name(String::from("pajamax-t"))
+                .spawn(move || pool.worker(i))
+                .unwrap();
+        }
+        pool
+    }
+
+    fn reserve(&self) -> bool {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn submit(&self, job: Job) {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.queues.len();
+        self.queues[i].lock().unwrap().push_back(job);
+
+        // pairs with the `idle` and `pending` in `worker()`
+        if self.idle.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.cond.notify_one();
+        }
+    }
+
+    fn worker(&self, i: usize) {
+        loop {
+            match self.take(i) {
+                Some(job) => {
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("job panics in thread pool");
+                    }
+                }
+                None => self.sleep(),
+            }
+        }
+    }
+
+    // Take from the own queue, or steal from others.
+    fn take(&self, i: usize) -> Option<Job> {
+        if let Some(job) = self.queues[i].lock().unwrap().pop_front() {
+            return Some(job);
+        }
+        let n = self.queues.len();
+        (1..n).find_map(|k| self.queues[(i + k) % n].lock().unwrap().pop_back())
+    }
+
+    fn sleep(&self) {
+        let mut lock = self.lock.lock().unwrap();
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        while self.pending.load(Ordering::SeqCst) == 0 {
+            lock = self.cond.wait(lock).unwrap();
+        }
+        self.idle.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    // Submit a job which blocks the worker until the returned sender is
+    // dropped, and wait for it to start. Return the worker's thread.
+    fn block(pool: &ThreadPool) -> (mpsc::Sender<()>, thread::ThreadId) {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        assert!(pool.reserve());
+        pool.submit(Box::new(move || {
+            started_tx.send(thread::current().id()).unwrap();
+            let _ = release_rx.recv();
+        }));
+        let worker = started_rx.recv_timeout(WAIT).unwrap();
+        (release_tx, worker)
+    }
+
+    #[test]
+    fn steal() {
+        let pool = ThreadPool::start(2, 100);
+        let (release, blocked) = block(&pool);
+
+        // Half of the jobs are queued to the blocked worker, and all
+        // are run by the other one.
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..10 {
+            let done_tx = done_tx.clone();
+            assert!(pool.reserve());
+            pool.submit(Box::new(move || {
+                done_tx.send(thread::current().id()).unwrap();
+            }));
+        }
+        for _ in 0..10 {
+            assert_ne!(done_rx.recv_timeout(WAIT).unwrap(), blocked);
+        }
+        drop(release);
+    }
+
+    #[test]
+    fn queue_full() {
+        let pool = ThreadPool::start(1, 2);
+        let (release, _) = block(&pool);
+
+        // the running job is not counted
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..2 {
+            let done_tx = done_tx.clone();
+            assert!(pool.reserve());
+            pool.submit(Box::new(move || done_tx.send(()).unwrap()));
+        }
+        assert!(!pool.reserve());
+
+        drop(release);
+        for _ in 0..2 {
+            done_rx.recv_timeout(WAIT).unwrap();
+        }
+        assert!(pool.reserve());
+    }
+
+    #[test]
+    fn panic_job() {
+        let pool = ThreadPool::start(1, 10);
+        assert!(pool.reserve());
+        pool.submit(Box::new(|| panic!("job panics")));
+
+        // the worker goes on
+        let (done_tx, done_rx) = mpsc::channel();
+        assert!(pool.reserve());
+        pool.submit(Box::new(move || done_tx.send(()).unwrap()));
+        done_rx.recv_timeout(WAIT).unwrap();
+    }
+}
@@ -0,0 +1,597 @@
+//! TLS by rustls, with the `tls` feature.
+//!
+//! Configure by [`crate::Config::tls`]. Serving fails if the `tls` feature
+//! is not enabled. Accepted connections are wrapped
+//! in a synchronous rustls `ServerConnection`, which advertises ALPN `h2`.
+//! The TLS handshake is done on the connection thread, so it does not
+//! block the accepting.
+//!
+//! Client certificates are verified if [`TlsConfig::client_ca`] is set
+//! (mTLS). The verified identity is available to handlers by
+//! [`crate::context::RequestContext::peer_identity`].
+//!
+//! The certificate, key and CA files are checked every
+//! [`TlsConfig::RELOAD_INTERVAL`], and reloaded if modified. New
+//! connections use the new files, while existing connections are not
+//! affected. If the reloading fails, the old ones are kept.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! pajamax::Config::new()
+//!     .tls(TlsConfig::new("server.crt", "server.key").client_ca("ca.crt"))
+//!     .add_service(GreeterServer::new(greeter))
+//!     .serve(addr)
+//!     .unwrap();
+//!
+//! // in handler
+//! fn say_hello(&self, req: HelloRequest) -> Result<HelloReply, Status> {
+//!     let caller = pajamax::context::with(|ctx| {
+//!         ctx?.peer_identity()?.uris().first().cloned()
+//!     });
+//!     ...
+//! }
+//! ```
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+pub(crate) use acceptor::TlsAcceptor;
+
+/// TLS configuration, with file paths in PEM format.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    client_auth_optional: bool,
+}
+
+impl TlsConfig {
+    /// How often to check the files for reloading.
+    pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// The `cert_path` is the certificate chain, with the end-entity
+    /// certificate first. The `key_path` is the private key.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+            client_auth_optional: false,
+        }
+    }
+
+    /// Verify client certificates by the CA certificates in this file.
+    /// Clients without certificate are refused, unless
+    /// [`Self::client_auth_optional`] is set.
+    pub fn client_ca(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            client_ca_path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Accept clients without certificate too. Certificates are still
+    /// verified if presented.
+    pub fn client_auth_optional(self, b: bool) -> Self {
+        Self {
+            client_auth_optional: b,
+            ..self
+        }
+    }
+}
+
+/// Identity of a TLS client, by its verified certificate.
+#[derive(Debug)]
+pub struct PeerIdentity {
+    certificates: Vec<Vec<u8>>,
+    server_name: Option<String>,
+    common_name: Option<String>,
+    dns_names: Vec<String>,
+    uris: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// The end-entity certificate in DER. Parse it by yourself if you
+    /// need more fields.
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificates[0]
+    }
+
+    /// The certificate chain in DER, with the end-entity certificate first.
+    pub fn chain(&self) -> impl Iterator<Item = &[u8]> {
+        self.certificates.iter().map(Vec::as_slice)
+    }
+
+    /// The SNI server name requested by the client.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// The CommonName in the subject.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The DNS names in SubjectAltName.
+    pub fn dns_names(&self) -> &[String] {
+        &self.dns_names
+    }
+
+    /// The URIs in SubjectAltName, e.g. SPIFFE IDs.
+    pub fn uris(&self) -> &[String] {
+        &self.uris
+    }
+}
+
+#[cfg(feature = "tls")]
+mod acceptor {
+    use std::io::{self, Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Instant, SystemTime};
+
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+    use super::{PeerIdentity, TlsConfig};
+    use crate::listener::Connection;
+    use crate::macros::*;
+
+    impl TlsConfig {
+        fn paths(&self) -> impl Iterator<Item = &Path> {
+            [
+                Some(&self.cert_path),
+                Some(&self.key_path),
+                self.client_ca_path.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+        }
+
+        fn load(&self) -> io::Result<Arc<ServerConfig>> {
+            let certs = CertificateDer::pem_file_iter(&self.cert_path)
+                .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+                .map_err(|err| invalid(&self.cert_path, err))?;
+            let key = PrivateKeyDer::from_pem_file(&self.key_path)
+                .map_err(|err| invalid(&self.key_path, err))?;
+
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let builder = ServerConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()
+                .map_err(io::Error::other)?;
+
+            let builder = match &self.client_ca_path {
+                None => builder.with_no_client_auth(),
+                Some(path) => {
+                    let mut roots = RootCertStore::empty();
+                    for cert in
+                        CertificateDer::pem_file_iter(path).map_err(|err| invalid(path, err))?
+                    {
+                        let cert = cert.map_err(|err| invalid(path, err))?;
+                        roots.add(cert).map_err(|err| invalid(path, err))?;
+                    }
+                    let verifier =
+                        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                    let verifier = if self.client_auth_optional {
+                        verifier.allow_unauthenticated()
+                    } else {
+                        verifier
+                    };
+                    builder.with_client_cert_verifier(verifier.build().map_err(io::Error::other)?)
+                }
+            };
+
+            let mut config = builder
+                .with_single_cert(certs, key)
+                .map_err(io::Error::other)?;
+            config.alpn_protocols = vec![b"h2".to_vec()];
+            Ok(Arc::new(config))
+        }
+
+        fn modified(&self) -> Vec<Option<SystemTime>> {
+            self.paths()
+                .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+                .collect()
+        }
+    }
+
+    fn new_identity(certs: &[CertificateDer<'static>], server_name: Option<&str>) -> PeerIdentity {
+        let mut identity = PeerIdentity {
+            certificates: certs.iter().map(|c| c.to_vec()).collect(),
+            server_name: server_name.map(String::from),
+            common_name: None,
+            dns_names: Vec::new(),
+            uris: Vec::new(),
+        };
+        if super::x509::parse(&certs[0], &mut identity).is_none() {
+            error!("fail to parse client certificate");
+
+            // no partial names
+            identity.common_name = None;
+            identity.dns_names.clear();
+            identity.uris.clear();
+        }
+        identity
+    }
+
+    fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid TLS file {}: {err}", path.display()),
+        )
+    }
+
+    struct Loaded {
+        server_config: Arc<ServerConfig>,
+        modified: Vec<Option<SystemTime>>,
+        checked: Instant,
+    }
+
+    // Shared by all connections.
+    pub(crate) struct TlsAcceptor {
+        config: TlsConfig,
+        loaded: Mutex<Loaded>,
+    }
+
+    impl TlsAcceptor {
+        // Load files at starting, to find errors early.
+        pub(crate) fn new(config: &TlsConfig) -> io::Result<Self> {
+            let loaded = Loaded {
+                modified: config.modified(),
+                server_config: config.load()?,
+                checked: Instant::now(),
+            };
+            Ok(Self {
+                config: config.clone(),
+                loaded: Mutex::new(loaded),
+            })
+        }
+
+        fn server_config(&self) -> Arc<ServerConfig> {
+            let mut loaded = self.loaded.lock().unwrap();
+            if loaded.checked.elapsed() >= TlsConfig::RELOAD_INTERVAL {
+                loaded.checked = Instant::now();
+
+                let modified = self.config.modified();
+                if modified != loaded.modified {
+                    loaded.modified = modified;
+                    match self.config.load() {
+                        Ok(server_config) => {
+                            info!("TLS files reloaded");
+                            loaded.server_config = server_config;
+                        }
+                        Err(err) => error!("fail to reload TLS files: {:?}", err),
+                    }
+                }
+            }
+            loaded.server_config.clone()
+        }
+
+        // Finish the handshake, and split the connection into 2 ends.
+        #[allow(clippy::type_complexity)]
+        pub(crate) fn accept<S: Connection>(
+            &self,
+            mut c: S,
+        ) -> io::Result<(TlsReader<S>, TlsWriter<S>, Option<Arc<PeerIdentity>>)> {
+            let mut conn = ServerConnection::new(self.server_config()).map_err(io::Error::other)?;
+            while conn.is_handshaking() {
+                conn.complete_io(&mut c)?;
+            }
+
+            let identity = conn
+                .peer_certificates()
+                .filter(|certs| !certs.is_empty())
+                .map(|certs| Arc::new(new_identity(certs, conn.server_name())));
+
+            let conn = Arc::new(Mutex::new(conn));
+            let reader = TlsReader {
+                conn: conn.clone(),
+                c: c.try_clone()?,
+                raw: Vec::new(),
+                pos: 0,
+                eof: false,
+            };
+            let writer = TlsWriter { conn, c };
+            Ok((reader, writer, identity))
+        }
+    }
+
+    // Read end of a TLS connection.
+    //
+    // The rustls connection is shared with the write end, so it's locked
+    // only when processing, but not when blocking on reading the socket.
+    pub(crate) struct TlsReader<S> {
+        conn: Arc<Mutex<ServerConnection>>,
+        c: S,
+        raw: Vec<u8>, // TLS data read but not processed yet
+        pos: usize,
+        eof: bool,
+    }
+
+    impl<S: Connection> Read for TlsReader<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let mut conn = self.conn.lock().unwrap();
+
+                // process TLS data, until rustls's plaintext buffer is full
+                while self.pos < self.raw.len() {
+                    let mut rd = &self.raw[self.pos..];
+                    match conn.read_tls(&mut rd) {
+                        Ok(n) => self.pos += n,
+                        Err(_) => break, // plaintext buffer full
+                    }
+                    if let Err(err) = conn.process_new_packets() {
+                        let _ = conn.write_tls(&mut self.c); // send the alert
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                }
+
+                // e.g. key-update, or alert
+                while conn.wants_write() {
+                    conn.write_tls(&mut self.c)?;
+                }
+
+                match conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                    result => return result,
+                }
+                if self.eof {
+                    return Ok(0);
+                }
+                drop(conn);
+
+                // read more TLS data, without lock
+                self.raw.resize(16 * 1024, 0);
+                let len = self.c.read(&mut self.raw)?;
+                self.raw.truncate(len);
+                self.pos = 0;
+                if len == 0 {
+                    self.eof = true;
+                    let _ = self.conn.lock().unwrap().read_tls(&mut &[][..]);
+                }
+            }
+        }
+    }
+
+    // Write end of a TLS connection.
+    pub(crate) struct TlsWriter<S> {
+        conn: Arc<Mutex<ServerConnection>>,
+        c: S,
+    }
+
+    impl<S: Connection> Write for TlsWriter<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut conn = self.conn.lock().unwrap();
+            let n = conn.writer().write(buf)?;
+            while conn.wants_write() {
+                conn.write_tls(&mut self.c)?;
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let mut conn = self.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut self.c)?;
+            }
+            self.c.flush()
+        }
+    }
+}
+
+// Minimal DER parsing for the subject CommonName and SubjectAltName.
+#[cfg(feature = "tls")]
+mod x509 {
+    use super::PeerIdentity;
+
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    const OID: u8 = 0x06;
+    const OCTET_STRING: u8 = 0x04;
+    const BOOLEAN: u8 = 0x01;
+    const EXPLICIT_0: u8 = 0xa0; // version
+    const EXPLICIT_3: u8 = 0xa3; // extensions
+
+    const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03]; // 2.5.4.3
+    const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11]; // 2.5.29.17
+
+    const SAN_DNS: u8 = 0x82; // [2] IMPLICIT IA5String
+    const SAN_URI: u8 = 0x86; // [6] IMPLICIT IA5String
+
+    // Split one TLV. Return (tag, value, rest).
+    fn next(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, buf) = buf.split_first()?;
+        let (&first, buf) = buf.split_first()?;
+        let (len, buf) = if first < 0x80 {
+            (first as usize, buf)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || buf.len() < n {
+                return None;
+            }
+            let len = buf[..n].iter().fold(0, |acc, &b| acc << 8 | b as usize);
+            (len, &buf[n..])
+        };
+        if buf.len() < len {
+            return None;
+        }
+        Some((tag, &buf[..len], &buf[len..]))
+    }
+
+    fn expect(buf: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+        let (t, value, rest) = next(buf)?;
+        (t == tag).then_some((value, rest))
+    }
+
+    pub(super) fn parse(cert: &[u8], identity: &mut PeerIdentity) -> Option<()> {
+        let (cert, _) = expect(cert, SEQUENCE)?;
+        let (tbs, _) = expect(cert, SEQUENCE)?;
+
+        // version (optional), serial, signature, issuer, validity
+        let (tag, _, mut rest) = next(tbs)?;
+        if tag == EXPLICIT_0 {
+            (_, _, rest) = next(rest)?;
+        }
+        for _ in 0..3 {
+            (_, _, rest) = next(rest)?;
+        }
+
+        // subject: SEQUENCE OF SET OF SEQUENCE { OID, value }
+        let (mut subject, mut rest) = expect(rest, SEQUENCE)?;
+        while !subject.is_empty() {
+            let (mut rdn, more) = expect(subject, SET)?;
+            subject = more;
+            while !rdn.is_empty() {
+                let (atv, more) = expect(rdn, SEQUENCE)?;
+                rdn = more;
+                let (oid, value) = expect(atv, OID)?;
+                if oid == OID_COMMON_NAME {
+                    let (_, value, _) = next(value)?;
+                    identity.common_name = Some(String::from_utf8_lossy(value).into_owned());
+                }
+            }
+        }
+
+        // skip subjectPublicKeyInfo, and find extensions
+        (_, _, rest) = next(rest)?;
+        while !rest.is_empty() {
+            let (tag, value, more) = next(rest)?;
+            rest = more;
+            if tag == EXPLICIT_3 {
+                parse_extensions(value, identity)?;
+            }
+        }
+        Some(())
+    }
+
+    fn parse_extensions(buf: &[u8], identity: &mut PeerIdentity) -> Option<()> {
+        let (mut exts, _) = expect(buf, SEQUENCE)?;
+        while !exts.is_empty() {
+            let (ext, more) = expect(exts, SEQUENCE)?;
+            exts = more;
+
+            let (oid, mut rest) = expect(ext, OID)?;
+            if oid != OID_SUBJECT_ALT_NAME {
+                continue;
+            }
+            if let Some((_, more)) = expect(rest, BOOLEAN) {
+                rest = more; // critical
+            }
+            let (value, _) = expect(rest, OCTET_STRING)?;
+            let (mut names, _) = expect(value, SEQUENCE)?;
+            while !names.is_empty() {
+                let (tag, name, more) = next(names)?;
+                names = more;
+                let name = String::from_utf8_lossy(name).into_owned();
+                match tag {
+                    SAN_DNS => identity.dns_names.push(name),
+                    SAN_URI => identity.uris.push(name),
+                    _ => (),
+                }
+            }
+        }
+        Some(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const CLIENT: &[u8] = include_bytes!("../testdata/client.der");
+        const MULTI: &[u8] = include_bytes!("../testdata/multi.der");
+        const NOSAN: &[u8] = include_bytes!("../testdata/nosan.der");
+        const NOCN: &[u8] = include_bytes!("../testdata/nocn.der");
+
+        fn parse_der(der: &[u8]) -> Option<PeerIdentity> {
+            let mut identity = PeerIdentity {
+                certificates: vec![der.to_vec()],
+                server_name: None,
+                common_name: None,
+                dns_names: Vec::new(),
+                uris: Vec::new(),
+            };
+            parse(der, &mut identity)?;
+            Some(identity)
+        }
+
+        // CN, and critical SAN with IP and email names ignored
+        #[test]
+        fn client_cert() {
+            let identity = parse_der(CLIENT).unwrap();
+            assert_eq!(identity.common_name(), Some("client-cn"));
+            assert_eq!(identity.dns_names(), ["client.local"]);
+            assert_eq!(identity.uris(), ["spiffe://test/client"]);
+        }
+
+        #[test]
+        fn multi_rdn_cert() {
+            let identity = parse_der(MULTI).unwrap();
+            assert_eq!(identity.common_name(), Some("multi-cn"));
+            assert_eq!(identity.dns_names(), ["a.local", "b.local"]);
+            assert!(identity.uris().is_empty());
+        }
+
+        #[test]
+        fn no_san_cert() {
+            let identity = parse_der(NOSAN).unwrap();
+            assert_eq!(identity.common_name(), Some("nosan-cn"));
+            assert!(identity.dns_names().is_empty());
+            assert!(identity.uris().is_empty());
+        }
+
+        #[test]
+        fn no_cn_cert() {
+            let identity = parse_der(NOCN).unwrap();
+            assert_eq!(identity.common_name(), None);
+            assert_eq!(identity.uris(), ["spiffe://test/nocn"]);
+        }
+
+        #[test]
+        fn truncated() {
+            for cert in [CLIENT, MULTI, NOSAN, NOCN] {
+                for len in 0..cert.len() {
+                    assert!(parse_der(&cert[..len]).is_none(), "len {len}");
+                }
+            }
+        }
+
+        // Must not panic, while the result does not matter.
+        #[test]
+        fn malformed() {
+            for cert in [CLIENT, MULTI, NOSAN, NOCN] {
+                let mut buf = cert.to_vec();
+                for i in 0..buf.len() {
+                    let orig = buf[i];
+                    for b in [0x00, 0x7f, 0x80, 0x84, 0x85, 0xff, orig ^ 0x01] {
+                        buf[i] = b;
+                        let _ = parse_der(&buf);
+                    }
+                    buf[i] = orig;
+                }
+            }
+        }
+
+        #[test]
+        fn bad_length() {
+            // indefinite length
+            assert!(next(&[SEQUENCE, 0x80, 0x00, 0x00]).is_none());
+            // length of 5 bytes
+            assert!(next(&[SEQUENCE, 0x85, 0, 0, 0, 0, 1, 0]).is_none());
+            // longer than the buffer
+            assert!(next(&[SEQUENCE, 0x03, 0x00, 0x00]).is_none());
+            assert!(next(&[SEQUENCE, 0x82, 0x01]).is_none());
+            assert!(next(&[SEQUENCE, 0x84, 0xff, 0xff, 0xff, 0xff, 0x00]).is_none());
+
+            // long form
+            let (tag, value, rest) = next(&[OID, 0x81, 0x01, 0x55, 0x04]).unwrap();
+            assert_eq!((tag, value, rest), (OID, &[0x55][..], &[0x04][..]));
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! Tracing, with W3C Trace Context propagation.
+//!
+//! Configure an exporter by [`crate::Config::tracing`]. Then for each
+//! request, pajamax parses the `traceparent` and `tracestate` headers,
+//! and creates a server span as a child of the incoming one. A new trace
+//! is started if there is no valid `traceparent`, and sampled by
+//! [`crate::Config::trace_sample_ratio`].
+//!
+//! The span context is available to handlers, in both local-mode and
+//! dispatch-mode, by [`current`]. Use [`SpanContext::traceparent`] to
+//! propagate it to downstream services.
+//!
+//! Finished spans are passed to the exporter, if sampled. The exporter is
+//! called on the thread that builds the response, so it should not block.
+//! [`JsonExporter`] is a built-in exporter that writes spans in OTLP/JSON
+//! format to a file or stdout on a background thread.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! pajamax::Config::new()
+//!     .tracing(JsonExporter::file("spans.jsonl", "dict-store")?)
+//!     .add_service(GreeterServer::new(greeter))
+//!     .serve(addr)
+//!     .unwrap();
+//!
+//! // in handler
+//! fn say_hello(&self, req: HelloRequest) -> Result<HelloReply, Status> {
+//!     let traceparent = pajamax::tracing::current().map(|sc| sc.traceparent());
+//!     ...
+//! }
+//! ```
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Write};
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::context;
+use crate::log_writer::{write_json_str, LogWriter};
+use crate::metadata::Metadata;
+use crate::status::Code;
+
+/// Span context of the request being handled.
+#[derive(Clone, Debug)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    /// ID of the server span of this request.
+    pub span_id: [u8; 8],
+    /// ID of the client span from `traceparent`, if any.
+    pub parent_span_id: Option<[u8; 8]>,
+    /// Trace flags. Only the sampled flag (`0x01`) is defined now.
+    pub flags: u8,
+    /// The `tracestate` header, kept as it is.
+    pub tracestate: Option<String>,
+}
+
+impl SpanContext {
+    pub const FLAG_SAMPLED: u8 = 0x01;
+
+    // Create a child of the incoming span in metadata, or a new root
+    // sampled by the ratio.
+    pub(crate) fn from_metadata(metadata: &Metadata, sample_ratio: f64) -> Self {
+        let span_id = new_span_id();
+        let parent = metadata.get_str("traceparent").and_then(parse_traceparent);
+        match parent {
+            Some((trace_id, parent_span_id, flags)) => Self {
+                trace_id,
+                span_id,
+                parent_span_id: Some(parent_span_id),
+                flags,
+                tracestate: metadata.get_str("tracestate").map(String::from),
+            },
+            None => {
+                let trace_id = new_trace_id();
+                let flags = if is_sampled_by_ratio(&trace_id, sample_ratio) {
+                    Self::FLAG_SAMPLED
+                } else {
+                    0
+                };
+                Self {
+                    trace_id,
+                    span_id,
+                    parent_span_id: None,
+                    flags,
+                    tracestate: None,
+                }
+            }
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & Self::FLAG_SAMPLED != 0
+    }
+
+    /// Value of `traceparent` header for outgoing requests, with this
+    /// server span as the parent.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            Hex(&self.trace_id),
+            Hex(&self.span_id),
+            self.flags
+        )
+    }
+}
+
+/// Return the span context of the request being handled.
+///
+/// Return `None` if tracing is not configured or not called in a handler.
+pub fn current() -> Option<SpanContext> {
+    context::with(|ctx| ctx?.span().cloned())
+}
+
+/// A finished server span.
+#[derive(Debug)]
+pub struct Span<'a> {
+    pub context: &'a SpanContext,
+    /// Method path, e.g. `/helloworld.Greeter/SayHello`.
+    pub method: &'a str,
+    pub peer: SocketAddr,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub code: Code,
+}
+
+/// Where finished spans go.
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: &Span);
+}
+
+impl fmt::Debug for dyn SpanExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SpanExporter")
+    }
+}
+
+/// Write spans in OTLP/JSON format, one `ExportTraceServiceRequest`
+/// object per line, which is the body of OTLP-over-HTTP/JSON requests.
+///
+/// Spans are written by a [`LogWriter`] with a queue of
+/// [`Self::QUEUE_SIZE`] spans.
+pub struct JsonExporter {
+    writer: LogWriter,
+    service_name: String,
+}
+
+impl JsonExporter {
+    pub const QUEUE_SIZE: usize = 64 * 1024;
+
+    /// Write to the file, in append mode. The `service_name` is set as
+    /// the `service.name` resource attribute.
+    pub fn file<P: AsRef<Path>>(path: P, service_name: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: LogWriter::new(file, Self::QUEUE_SIZE)?,
+            service_name: String::from(service_name),
+        })
+    }
+
+    /// Write to stdout.
+    pub fn stdout(service_name: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: LogWriter::new(std::io::stdout(), Self::QUEUE_SIZE)?,
+            service_name: String::from(service_name),
+        })
+    }
+
+    /// Number of spans dropped because the queue is full.
+    pub fn dropped(&self) -> u64 {
+        self.writer.dropped()
+    }
+
+    fn format(&self, span: &Span, out: &mut String) -> fmt::Result {
+        let sc = span.context;
+        let (service, method) = split_method(span.method);
+
+        out.push_str("{\"resourceSpans\":[{\"resource\":{\"attributes\":[");
+        write_attr_str(out, "service.name", &self.service_name)?;
+        out.push_str("]},\"scopeSpans\":[{\"scope\":{\"name\":\"pajamax\"},\"spans\":[{");
+
+        write!(
+            out,
+            "\"traceId\":\"{}\",\"spanId\":\"{}\",",
+            Hex(&sc.trace_id),
+            Hex(&sc.span_id)
+        )?;
+        if let Some(parent) = &sc.parent_span_id {
+            write!(out, "\"parentSpanId\":\"{}\",", Hex(parent))?;
+        }
+        if let Some(tracestate) = &sc.tracestate {
+            out.push_str("\"traceState\":");
+            write_json_str(out, tracestate)?;
+            out.push(',');
+        }
+        out.push_str("\"name\":");
+        write_json_str(out, span.method.trim_start_matches('/'))?;
+        write!(
+            out,
+            ",\"kind\":2,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+            unix_nanos(span.start),
+            unix_nanos(span.end)
+        )?;
+        write_attr_str(out, "rpc.system", "grpc")?;
+        out.push(',');
+        write_attr_str(out, "rpc.service", service)?;
+        out.push(',');
+        write_attr_str(out, "rpc.method", method)?;
+        out.push(',');
+        write!(
+            out,
+            "{{\"key\":\"rpc.grpc.status_code\",\"value\":{{\"intValue\":\"{}\"}}}},",
+            span.code as u8
+        )?;
+        write_attr_str(out, "net.sock.peer.addr", &span.peer.ip().to_string())?;
+        out.push(',');
+        write!(
+            out,
+            "{{\"key\":\"net.sock.peer.port\",\"value\":{{\"intValue\":\"{}\"}}}}",
+            span.peer.port()
+        )?;
+
+        // status: 2 for ERROR, 0 for UNSET
+        let status = if span.code == Code::Ok { 0 } else { 2 };
+        writeln!(out, "],\"status\":{{\"code\":{status}}}}}]}}]}}]}}")
+    }
+}
+
+impl SpanExporter for JsonExporter {
+    fn export(&self, span: &Span) {
+        let mut line = String::with_capacity(1024);
+        self.format(span, &mut line).unwrap();
+        self.writer.write_line(line);
+    }
+}
+
+// "00-{trace-id}-{parent-id}-{flags}". Future versions may append more
+// fields, while version "ff" is invalid.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    let [version] = parse_hex(version)?;
+    if version == 0xff || (version == 0 && parts.next().is_some()) {
+        return None;
+    }
+    let trace_id: [u8; 16] = parse_hex(trace_id)?;
+    let parent_id: [u8; 8] = parse_hex(parent_id)?;
+    let [flags] = parse_hex(flags)?;
+
+    // all-zero IDs are invalid
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id, flags))
+}
+
+// Compare the random lower half of the trace ID with the ratio, as
+// OpenTelemetry's `TraceIdRatioBased` sampler does.
+fn is_sampled_by_ratio(trace_id: &[u8; 16], ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+    let threshold = (ratio * u64::MAX as f64) as u64;
+    let lower = u64::from_be_bytes(trace_id[8..].try_into().unwrap());
+    lower < threshold
+}
+
+// Lowercase hex only.
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    fn digit(b: u8) -> Option<u8> {
+        match b {
+            b'0'..=b'9' => Some(b - b'0'),
+            b'a'..=b'f' => Some(b - b'a' + 10),
+            _ => None,
+        }
+    }
+
+    let s = s.as_bytes();
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, pair) in s.chunks(2).enumerate() {
+        out[i] = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(out)
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+// Random IDs. They need to be unique but not unpredictable, so
+// a xorshift generator seeded by std's random hasher keys is enough.
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(RandomState::new().hash_one(std::thread::current().id()) | 1);
+}
+
+fn next_random() -> u64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        x
+    })
+}
+
+fn new_trace_id() -> [u8; 16] {
+    let mut id = [0; 16];
+    id[..8].copy_from_slice(&next_random().to_be_bytes());
+    id[8..].copy_from_slice(&next_random().to_be_bytes());
+    id
+}
+
+fn new_span_id() -> [u8; 8] {
+    next_random().to_be_bytes()
+}
+
+// "/helloworld.Greeter/SayHello" -> ("helloworld.Greeter", "SayHello")
+fn split_method(path: &str) -> (&str, &str) {
+    path.trim_start_matches('/')
+        .split_once('/')
+        .unwrap_or((path, ""))
+}
+
+fn unix_nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn write_attr_str(out: &mut String, key: &str, value: &str) -> fmt::Result {
+    write!(out, "{{\"key\":\"{key}\",\"value\":{{\"stringValue\":")?;
+    write_json_str(out, value)?;
+    out.push_str("}}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn traceparent() {
+        let value = format!("00-{TRACE_ID}-{PARENT_ID}-01");
+        let (trace_id, parent_id, flags) = parse_traceparent(&value).unwrap();
+        assert_eq!(Hex(&trace_id).to_string(), TRACE_ID);
+        assert_eq!(Hex(&parent_id).to_string(), PARENT_ID);
+        assert_eq!(flags, 1);
+
+        // future versions may append fields
+        let value = format!("01-{TRACE_ID}-{PARENT_ID}-00-what-ever");
+        assert_eq!(parse_traceparent(&value).unwrap().2, 0);
+    }
+
+    #[test]
+    fn traceparent_bad_version() {
+        for version in ["ff", "0", "000", "0g", ""] {
+            let value = format!("{version}-{TRACE_ID}-{PARENT_ID}-01");
+            assert!(parse_traceparent(&value).is_none(), "{value}");
+        }
+        // version 00 has exactly 4 fields
+        let value = format!("00-{TRACE_ID}-{PARENT_ID}-01-00");
+        assert!(parse_traceparent(&value).is_none());
+    }
+
+    #[test]
+    fn traceparent_bad_lengths() {
+        let cases = [
+            format!("00-{}-{PARENT_ID}-01", &TRACE_ID[1..]),
+            format!("00-{TRACE_ID}0-{PARENT_ID}-01"),
+            format!("00-{TRACE_ID}-{}-01", &PARENT_ID[1..]),
+            format!("00-{TRACE_ID}-{PARENT_ID}0-01"),
+            format!("00-{TRACE_ID}-{PARENT_ID}-1"),
+            format!("00-{TRACE_ID}-{PARENT_ID}-001"),
+            format!("00-{TRACE_ID}-{PARENT_ID}"),
+            String::new(),
+        ];
+        for value in cases {
+            assert!(parse_traceparent(&value).is_none(), "{value}");
+        }
+    }
+
+    #[test]
+    fn traceparent_bad_hex() {
+        let upper = TRACE_ID.to_uppercase();
+        let cases = [
+            format!("00-{upper}-{PARENT_ID}-01"),
+            format!("00-{TRACE_ID}-{PARENT_ID}-0x"),
+        ];
+        for value in cases {
+            assert!(parse_traceparent(&value).is_none(), "{value}");
+        }
+    }
+
+    #[test]
+    fn traceparent_zero_ids() {
+        let zero_trace = format!("00-{}-{PARENT_ID}-01", "0".repeat(32));
+        assert!(parse_traceparent(&zero_trace).is_none());
+        let zero_parent = format!("00-{TRACE_ID}-{}-01", "0".repeat(16));
+        assert!(parse_traceparent(&zero_parent).is_none());
+    }
+
+    #[test]
+    fn sample_ratio() {
+        let sampled = |ratio| {
+            (0..10000)
+                .filter(|_| is_sampled_by_ratio(&new_trace_id(), ratio))
+                .count()
+        };
+        assert_eq!(sampled(1.0), 10000);
+        assert_eq!(sampled(0.0), 0);
+        assert!((4000..6000).contains(&sampled(0.5)));
+
+        // by the trace ID
+        let mut trace_id = [0xff; 16];
+        assert!(!is_sampled_by_ratio(&trace_id, 0.5));
+        trace_id[8..].fill(0);
+        assert!(is_sampled_by_ratio(&trace_id, 0.5));
+    }
+}
@@ -0,0 +1,279 @@
+// Worker-pool execution model: a fixed pool of worker threads, each of
+// which multiplexes many connections by epoll.
+//
+// The acceptor assigns each new connection to the worker with the least
+// connections. The worker reads the connection when it's readable, and
+// handles the requests by `ConnState::process()`, the same as the
+// connection thread in thread-per-connection model, including the
+// batched response flushing.
+//
+// Sockets are kept in blocking mode, so responses are written in blocking
+// way with `Config::write_timeout`, while requests are read with
+// `MSG_DONTWAIT`.
+
+use std::collections::HashMap;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::connection::ConnState;
+use crate::epoll::{self, Epoll};
+use crate::error::Error;
+use crate::macros::*;
+
+// How often to check idle connections.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Max events for each `epoll_wait()`.
+const MAX_EVENTS: usize = 256;
+
+// Called when the connection is closed, with the reason.
+pub(crate) type OnClose = Box<dyn FnOnce(Result<(), Error>) + Send>;
+
+struct PoolConn {
+    fd: RawFd,
+    _c: Box<dyn Send>, // keep the socket open
+    state: ConnState,
+    last_active: Instant,
+    on_close: OnClose,
+}
+
+struct Worker {
+    epoll: Arc<Epoll>,
+    tx: mpsc::Sender<PoolConn>,
+    conns: Arc<AtomicUsize>,
+}
+
+pub(crate) struct WorkerPool {
+    workers: Vec<Worker>,
+}
+
+impl WorkerPool {
+    pub(crate) fn new(threads: usize, idle_timeout: Duration) -> std::io::Result<Self> {
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let epoll = Arc::new(Epoll::new()?);
+            let (tx, rx) = mpsc::channel();
+            let conns = Arc::new(AtomicUsize::new(0));
+
+            let epoll2 = epoll.clone();
+            let conns2 = conns.clone();
+            thread::Builder::new()
+                .name(String::from("pajamax-p"))
+                .spawn(move || {
+                    if let Err(err) = worker_routine(&epoll2, rx, &conns2, idle_timeout) {
+                        error!("worker fail: {:?}", err);
+                    }
+                })?;
+
+            workers.push(Worker { epoll, tx, conns });
+        }
+        Ok(Self { workers })
+    }
+
+    // Assign a new connection to the worker with the least connections.
+    pub(crate) fn add<C: Send + 'static>(
+        &self,
+        c: C,
+        fd: RawFd,
+        state: ConnState,
+        on_close: OnClose,
+    ) -> std::io::Result<()> {
+        let worker = self
+            .workers
+            .iter()
+            .min_by_key(|w| w.conns.load(Ordering::Relaxed))
+            .unwrap();
+
+        let conn = PoolConn {
+            fd,
+            _c: Box::new(c),
+            state,
+            last_active: Instant::now(),
+            on_close,
+        };
+
+        // Register before sending, so on failure the connection is not
+        // owned by the worker, and `on_close` is not called. The events
+        // before the worker receives the connection are skipped, and
+        // reported again as epoll is level-triggered.
+        worker.epoll.add(fd, fd as u64, epoll::READABLE)?;
+        worker.conns.fetch_add(1, Ordering::Relaxed);
+        if worker.tx.send(conn).is_err() {
+            worker.conns.fetch_sub(1, Ordering::Relaxed);
+            let _ = worker.epoll.delete(fd);
+            return Err(std::io::Error::other("worker exited"));
+        }
+        Ok(())
+    }
+}
+
+fn worker_routine(
+    epoll: &Epoll,
+    rx: mpsc::Receiver<PoolConn>,
+    nconns: &AtomicUsize,
+    idle_timeout: Duration,
+) -> std::io::Result<()> {
+    let mut conns: HashMap<RawFd, PoolConn> = HashMap::new();
+    let mut events = Vec::with_capacity(MAX_EVENTS);
+    let mut last_sweep = Instant::now();
+
+    let close = |conns: &mut HashMap<RawFd, PoolConn>, fd, result| {
+        let conn: PoolConn = conns.remove(&fd).unwrap();
+        let _ = epoll.delete(fd);
+        epoll::shutdown(fd);
+        nconns.fetch_sub(1, Ordering::Relaxed);
+        (conn.on_close)(result);
+    };
+
+    loop {
+        epoll.wait(&mut events, SWEEP_INTERVAL)?;
+
+        // new connections
+        while let Ok(conn) = rx.try_recv() {
+            conns.insert(conn.fd, conn);
+        }
+
+        for event in events.iter() {
+            let fd = event.u64 as RawFd;
+            let Some(conn) = conns.get_mut(&fd) else {
+                continue;
+            };
+
+            match read_and_process(conn) {
+                Ok(true) => (),
+                Ok(false) => close(&mut conns, fd, Ok(())),
+                Err(err) => close(&mut conns, fd, Err(err)),
+            }
+        }
+
+        // close idle connections
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            last_sweep = Instant::now();
+            let idle: Vec<RawFd> = conns
+                .values()
+                .filter(|conn| conn.last_active.elapsed() >= idle_timeout)
+                .map(|conn| conn.fd)
+                .collect();
+            for fd in idle {
+                trace!("close idle connection");
+                close(&mut conns, fd, Ok(()));
+            }
+        }
+    }
+}
+
+// Return false if the connection is closed by peer.
+fn read_and_process(conn: &mut PoolConn) -> Result<bool, Error> {
+    match epoll::recv_nonblocking(conn.fd, conn.state.input_buf()) {
+        Ok(0) => Ok(false),
+        Ok(len) => {
+            trace!("receive data {len}");
+            conn.last_active = Instant::now();
+            conn.state.process(len)?;
+            Ok(true)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => Ok(true),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::*;
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    type Closed = mpsc::Receiver<Result<(), Error>>;
+
+    // Add a connection of the echo service to the pool, and return the
+    // peer end, and the receiver of the `on_close` result.
+    fn add_conn(pool: &WorkerPool) -> (UnixStream, Closed) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let output = Box::new(server.try_clone().unwrap());
+        let (state, _) = echo_conn_state(output, Config::new());
+        let (tx, rx) = mpsc::channel();
+        let fd = server.as_raw_fd();
+        let on_close = Box::new(move |result| {
+            let _ = tx.send(result);
+        });
+        pool.add(server, fd, state, on_close).unwrap();
+        (client, rx)
+    }
+
+    fn echo(client: &mut UnixStream, stream_id: u32, s: &str) -> Option<String> {
+        client.write_all(&echo_request(stream_id, s)).unwrap();
+        read_echo_reply(client)
+    }
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn request_and_peer_close() {
+        let pool = WorkerPool::new(2, WAIT).unwrap();
+        let (mut c1, closed1) = add_conn(&pool);
+        let (mut c2, closed2) = add_conn(&pool);
+
+        // least connections
+        let conns: Vec<usize> = pool
+            .workers
+            .iter()
+            .map(|w| w.conns.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(conns, [1, 1]);
+
+        c1.write_all(&client_preface()).unwrap();
+        c2.write_all(&client_preface()).unwrap();
+        assert_eq!(echo(&mut c1, 1, "hello").as_deref(), Some("hello"));
+        assert_eq!(echo(&mut c2, 1, "world").as_deref(), Some("world"));
+        assert_eq!(echo(&mut c1, 3, "again").as_deref(), Some("again"));
+
+        // close by peer, while the unread frames are still buffered
+        c1.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(closed1.recv_timeout(WAIT).unwrap().is_ok());
+        drop(c1);
+
+        // the fd may be reused by the new connection
+        let (mut c3, _closed3) = add_conn(&pool);
+        c3.write_all(&client_preface()).unwrap();
+        assert_eq!(echo(&mut c3, 1, "new").as_deref(), Some("new"));
+        assert_eq!(echo(&mut c2, 3, "old").as_deref(), Some("old"));
+        assert!(closed2.try_recv().is_err());
+    }
+
+    #[test]
+    fn close_on_error() {
+        let pool = WorkerPool::new(1, WAIT).unwrap();
+        let (mut client, closed) = add_conn(&pool);
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nmore than the preface")
+            .unwrap();
+        assert!(closed.recv_timeout(WAIT).unwrap().is_err());
+
+        // shut down, so the peer sees EOF
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).unwrap(), 0);
+        assert_eq!(pool.workers[0].conns.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn close_idle() {
+        let pool = WorkerPool::new(1, Duration::from_millis(100)).unwrap();
+        let (mut client, closed) = add_conn(&pool);
+        client.write_all(&client_preface()).unwrap();
+        assert_eq!(echo(&mut client, 1, "hello").as_deref(), Some("hello"));
+
+        // closed by the sweep
+        let start = Instant::now();
+        assert!(closed.recv_timeout(WAIT).unwrap().is_ok());
+        assert!(start.elapsed() <= SWEEP_INTERVAL * 2);
+        assert_eq!(read_echo_reply(&mut client), None);
+    }
+}